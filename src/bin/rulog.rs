@@ -0,0 +1,242 @@
+//!
+//! `rulog`: read, filter and follow the log files produced by `rust_utils::logger`.
+//!
+//! ```bash
+//! # All `WARN` and `ERROR` records from the `Net` module in the last hour window
+//! rulog --level WARN --module Net --since 2026-10-19T08:00 --until 2026-10-19T09:00 app.log
+//!
+//! # Follow the file across rotation (like `tail -F`) and only show one peer
+//! rulog -F --field peer=10.0.0.1 app.log
+//! ```
+//!
+use rust_utils::logger::reader::{
+    LogFilter, LogFollower, LogRecordParser, ReadLogRecord, read_lines,
+};
+use rust_utils::logger::{LogLevel, parse_timestamp};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: rulog [OPTIONS] [FILE]...
+
+Read the log files produced by `rust_utils::logger` (`Text`, `Json` or the console
+output), filter the records and print them. Read from stdin if no FILE is given.
+
+Options:
+  -l, --level <LEVEL>        Only print records at this level or higher (DEBUG/INFO/WARN/ERROR)
+  -m, --module <NAME>        Only print records from this module
+  -f, --function <NAME>      Only print records from this function
+      --since <TIME>         Only print records at or after this UTC time, e.g. 2026-10-19T08:00
+      --until <TIME>         Only print records at or before this UTC time
+      --field <NAME=VALUE>   Only print records with this field value, can be repeated
+  -F, --follow               Keep reading new records, reopen the file when it's rotated
+      --color                Always print with ANSI colors
+      --no-color             Never print with ANSI colors
+  -h, --help                 Print this help
+";
+
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+struct Options {
+    filter: LogFilter,
+    follow: bool,
+    color: bool,
+    files: Vec<PathBuf>,
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        filter: LogFilter::default(),
+        follow: false,
+        color: io::stdout().is_terminal()
+            && !std::env::var("LOGGER_DISABLE_COLOR")
+                .is_ok_and(|v| v.trim().to_uppercase() == "TRUE"),
+        files: Vec::new(),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value_of = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("'{name}' requires a value"))
+        };
+
+        match arg.as_str() {
+            "-l" | "--level" => {
+                let level = value_of(&arg)?;
+                options.filter.level = Some(
+                    LogLevel::from_name(&level).ok_or_else(|| format!("invalid level: {level}"))?,
+                );
+            }
+            "-m" | "--module" => options.filter.module_name = Some(value_of(&arg)?),
+            "-f" | "--function" => options.filter.function_name = Some(value_of(&arg)?),
+            "--since" | "--until" => {
+                let time = value_of(&arg)?;
+                let parsed =
+                    parse_timestamp(&time).ok_or_else(|| format!("invalid time: {time}"))?;
+                if arg == "--since" {
+                    options.filter.since = Some(parsed);
+                } else {
+                    options.filter.until = Some(parsed);
+                }
+            }
+            "--field" => {
+                let field = value_of(&arg)?;
+                let (name, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid field filter (NAME=VALUE): {field}"))?;
+                options
+                    .filter
+                    .fields
+                    .push((name.to_string(), value.to_string()));
+            }
+            "-F" | "--follow" => options.follow = true,
+            "--color" => options.color = true,
+            "--no-color" => options.color = false,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown option: {arg}"));
+            }
+            _ => options.files.push(PathBuf::from(arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn print_record(record: &ReadLogRecord, options: &Options, out: &mut impl Write) -> io::Result<()> {
+    if options.filter.matches(record) {
+        writeln!(out, "{}", record.render(options.color))?;
+    }
+    Ok(())
+}
+
+fn read_all<R: BufRead>(mut reader: R, options: &Options, out: &mut impl Write) -> io::Result<()> {
+    let mut partial = Vec::new();
+    let mut parser = LogRecordParser::new();
+    read_lines(&mut reader, &mut partial, &mut parser, &mut |record| {
+        print_record(&record, options, out)
+    })?;
+
+    if !partial.is_empty()
+        && let Some(record) = parser.push_line(&String::from_utf8_lossy(&partial))
+    {
+        print_record(&record, options, out)?;
+    }
+    if let Some(record) = parser.finish() {
+        print_record(&record, options, out)?;
+    }
+    Ok(())
+}
+
+fn follow(options: &Options, out: &mut impl Write) -> io::Result<()> {
+    let mut followers = options
+        .files
+        .iter()
+        .map(|path| LogFollower::new(path.clone()))
+        .collect::<Vec<_>>();
+
+    loop {
+        for follower in followers.iter_mut() {
+            for record in follower.poll()? {
+                print_record(&record, options, out)?;
+            }
+        }
+        out.flush()?;
+        std::thread::sleep(FOLLOW_POLL_INTERVAL);
+    }
+}
+
+fn run(options: &Options) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+
+    if options.follow && !options.files.is_empty() {
+        return follow(options, &mut out);
+    }
+
+    if options.files.is_empty() {
+        read_all(io::stdin().lock(), options, &mut out)?;
+    }
+    for path in options.files.iter() {
+        if path.as_os_str() == "-" {
+            read_all(io::stdin().lock(), options, &mut out)?;
+            continue;
+        }
+        match File::open(path) {
+            Ok(file) => read_all(BufReader::new(file), options, &mut out)?,
+            Err(e) => eprintln!("rulog: {}: {e}", path.display()),
+        }
+    }
+    out.flush()
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(e) if e.is_empty() => {
+            print!("{USAGE}");
+            return;
+        }
+        Err(e) => {
+            eprintln!("rulog: {e}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&options) {
+        //
+        // The reader (e.g. `head`) closed the pipe, it's not an error
+        //
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("rulog: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_utils::logger::{self, FileSink, LogFormat};
+
+    #[test]
+    fn field_filter_should_match_file_sink_records() {
+        let log_file =
+            std::env::temp_dir().join(format!("rust_utils_rulog_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&log_file);
+
+        logger::add_sink(FileSink::new(&log_file, LogFormat::Json).unwrap());
+        for peer in ["10.0.0.1", "10.0.0.2"] {
+            logger::log_with_fields(
+                LogLevel::ERROR,
+                "RulogTests",
+                "field_filter",
+                &format!("connected to {peer}"),
+                &[("peer", peer)],
+            );
+        }
+        logger::flush();
+
+        let args = ["--no-color", "--field", "peer=10.0.0.1"]
+            .into_iter()
+            .map(String::from)
+            .chain([log_file.display().to_string()])
+            .collect();
+        let options = parse_args(args).unwrap();
+        let mut out = Vec::new();
+        read_all(
+            BufReader::new(File::open(&log_file).unwrap()),
+            &options,
+            &mut out,
+        )
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("connected to 10.0.0.1"), "{out}");
+        assert!(!out.contains("connected to 10.0.0.2"), "{out}");
+        let _ = std::fs::remove_file(&log_file);
+    }
+}
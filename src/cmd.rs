@@ -1,17 +1,125 @@
+#[cfg(feature = "tokio")]
+mod async_exec;
+mod audit;
+mod cancel;
+mod capture;
+mod command_runner;
+mod error;
+mod exec;
+mod expect;
+mod fixture;
+mod graph;
+mod macros;
+mod output;
+mod pipeline;
+#[cfg(unix)]
+mod pty;
+mod resource;
+mod retry;
+mod runner;
+pub mod shell;
+mod spec;
+mod stream;
+mod supervisor;
+mod which;
+
+#[cfg(feature = "tokio")]
+pub use async_exec::{AsyncCommand, OutputLine};
+pub use audit::{AuditLog, AuditRunner, DryRunRunner, is_dry_run, set_audit_log, set_dry_run};
+pub use cancel::CancellationToken;
+pub use capture::{CaptureLimit, Truncation};
+pub use command_runner::{CannedResult, CommandMatcher, CommandRunner, FakeRunner, SystemRunner};
+pub use error::CommandError;
+pub use exec::OutputStream;
+pub use expect::{Expect, ExpectError, ExpectMatch, ExpectSession};
+pub use fixture::{RecordingRunner, ReplayRunner};
+pub use graph::{TaskGraph, TaskGraphError, TaskGraphReport, TaskReport, TaskStatus};
+pub use output::CommandOutput;
+pub use pipeline::{Pipeline, PipelineOutput, PipelineResult, StageStatus};
+#[cfg(unix)]
+pub use pty::PtySize;
+pub use resource::{ResourceLimits, ResourceUsage};
+pub use retry::{Backoff, RetryAttempt, RetryPolicy, RetryResult};
+pub use runner::{BatchProgress, BatchRunner, FailureMode};
+pub use spec::{CommandSpec, Redirect, StdinSource};
+pub use supervisor::{
+    ExitReason, ProcessState, RestartPolicy, SupervisedProcess, Supervisor, SupervisorStatus,
+};
+pub use which::{which, which_in};
+
 ///
 /// Execute command result
 ///
 #[derive(Debug)]
 pub enum ExecuteCommandResult {
-    Success {
-        cmd_desc: String,
-        exit_code: Option<i32>,
-        output: String,
-    },
-    // Fail(std::io::Error),
-    Fail {
-        error_message: String,
-    },
+    ///
+    /// The command ran and exited, no matter what the exit code is
+    ///
+    Success(CommandOutput),
+
+    ///
+    /// The command was terminated because of `CommandSpec::timeout`, with the output
+    /// collected until then
+    ///
+    TimedOut(CommandOutput),
+
+    ///
+    /// The command was terminated because of `CommandSpec::cancel_token`, with the
+    /// output collected until then
+    ///
+    Cancelled(CommandOutput),
+
+    ///
+    /// The command couldn't run, e.g. the program doesn't exist
+    ///
+    Fail(CommandError),
+}
+
+impl ExecuteCommandResult {
+    ///
+    /// Whether the command ran and exited with 0
+    ///
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Success(output) if output.exit_code == Some(0))
+    }
+
+    ///
+    /// Only a command which ran and exited with 0 is ok, everything else is an error:
+    /// a non-zero exit becomes `CommandError::NonZeroExit` and a signal death becomes
+    /// `CommandError::Signaled`, both carry the captured stderr.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use rust_utils::cmd::{CommandError, CommandSpec};
+    ///
+    /// let output = CommandSpec::new("echo").arg("ok").execute().check().unwrap();
+    /// assert_eq!(output.stdout_lossy(), "ok\n");
+    ///
+    /// let error = CommandSpec::new("ls")
+    ///     .arg("/no/such/dir")
+    ///     .execute()
+    ///     .check()
+    ///     .unwrap_err();
+    /// assert!(matches!(error, CommandError::NonZeroExit { .. }));
+    /// assert!(error.stderr().is_some_and(|stderr| stderr.contains("/no/such/dir")));
+    /// ```
+    ///
+    pub fn check(self) -> Result<CommandOutput, CommandError> {
+        match self {
+            Self::Success(output) => match CommandError::from_exit(&output) {
+                Some(error) => Err(error),
+                None => Ok(output),
+            },
+            Self::TimedOut(output) => Err(CommandError::TimedOut {
+                cmd_desc: output.cmd_desc,
+            }),
+            Self::Cancelled(output) => Err(CommandError::Cancelled {
+                cmd_desc: output.cmd_desc,
+            }),
+            Self::Fail(error) => Err(error),
+        }
+    }
 }
 
 ///
@@ -20,36 +128,34 @@ pub enum ExecuteCommandResult {
 /// Example:
 ///
 /// ```rust
-/// use rust_utils::utils::{cmd, logger};
+/// use rust_utils::cmd;
+/// use rust_utils::logger::{log, LogLevel};
+/// use rust_utils::{debug_log, error_log};
 ///
 /// match cmd::execute_command(vec!["ls", "-lht", "./"]) {
-///     cmd::ExecuteCommandResult::Success {
-///         cmd_desc,
-///         exit_code,
-///         output,
-///     } => {
-///         logger::debug_log(
+///     cmd::ExecuteCommandResult::Success(output) => {
+///         debug_log!(
 ///             "Main",
 ///             "main",
-///             std::format!(
+///             &format!(
 ///                 concat!(
 ///                     "Succeeded in executing the command with the result: {{",
 ///                     "\n\tcmd_desc: {}",
 ///                     "\n\texit_code: {:?}",
 ///                     "\n}}, output:\n\n{}",
 ///                 ),
-///                 cmd_desc,
-///                 exit_code,
-///                 output
+///                 output.cmd_desc,
+///                 output.exit_code,
+///                 output.stdout_lossy()
 ///             )
-///             .as_str(),
 ///         );
 ///     }
-///     cmd::ExecuteCommandResult::Fail { error_message } => {
-///         logger::error_log(
+///     cmd::ExecuteCommandResult::TimedOut(_) | cmd::ExecuteCommandResult::Cancelled(_) => {}
+///     cmd::ExecuteCommandResult::Fail(error) => {
+///         error_log!(
 ///             "Main",
 ///             "main",
-///             std::format!("Faild to execute command with error: {error_message}").as_str(),
+///             &format!("Faild to execute command with error: {error}")
 ///         );
 ///     }
 /// }
@@ -71,41 +177,53 @@ pub enum ExecuteCommandResult {
 /// -rw-r--r--  1 wison  staff   1.4K  4 Nov 17:03 build.rs
 /// ```
 ///
+/// Use `CommandSpec` if you need to set env vars, working directory or stdin.
+///
 pub fn execute_command(cmd_list: Vec<&str>) -> ExecuteCommandResult {
-    if cmd_list.len() == 0 {
-        return ExecuteCommandResult::Fail {
-            error_message: String::from("'cmd_list' is empty"),
-        };
-    }
-
     //
     // Build command by the first element and attach the rest elements as arguments
     //
-    let mut cmd = std::process::Command::new(cmd_list[0]);
-    cmd.args(&cmd_list[1..]);
-    // for temp_arg in &cmd_list {
-    //     cmd.arg(temp_arg);
-    // }
-
-    //
-    // Get back a cmd string
-    //
-    let cmd_desc = cmd_list.join(" ");
+    match CommandSpec::from_list(&cmd_list) {
+        Some(spec) => spec.execute(),
+        None => ExecuteCommandResult::Fail(CommandError::InvalidCommand {
+            reason: String::from("'cmd_list' is empty"),
+        }),
+    }
+}
 
-    //
-    // Executes the command as a child process, waiting for it to finish
-    // and collecting all of its output.
-    //
-    let result = cmd.output();
-    match result {
-        Ok(output) => ExecuteCommandResult::Success {
-            cmd_desc: cmd_desc,
-            exit_code: output.status.code(),
-            output: String::from_utf8(output.stdout).unwrap_or(String::new()),
-        },
+///
+/// Execute a command line string and wait for the result, the line is split by the
+/// POSIX shell quoting rules (see `shell::split`) and no shell is involved.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{self, ExecuteCommandResult};
+///
+/// if let ExecuteCommandResult::Success(output) = cmd::execute_command_line("echo 'a  b' c") {
+///     assert_eq!(output.stdout_lossy(), "a  b c\n");
+///     assert_eq!(output.cmd_desc, "echo 'a  b' c");
+/// }
+/// ```
+///
+pub fn execute_command_line(line: &str) -> ExecuteCommandResult {
+    match CommandSpec::parse(line) {
+        Ok(spec) => spec.execute(),
+        Err(reason) => ExecuteCommandResult::Fail(CommandError::InvalidCommand {
+            reason: format!("'{line}': {reason}"),
+        }),
+    }
+}
 
-        Err(e) => ExecuteCommandResult::Fail {
-            error_message: e.to_string(),
-        },
+///
+/// Async version of `execute_command` on the tokio runtime (`tokio` feature)
+///
+#[cfg(feature = "tokio")]
+pub async fn execute_command_async(cmd_list: Vec<&str>) -> ExecuteCommandResult {
+    match CommandSpec::from_list(&cmd_list) {
+        Some(spec) => spec.execute_async().await,
+        None => ExecuteCommandResult::Fail(CommandError::InvalidCommand {
+            reason: String::from("'cmd_list' is empty"),
+        }),
     }
 }
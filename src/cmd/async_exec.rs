@@ -0,0 +1,521 @@
+//!
+//! Run a `CommandSpec` on the tokio runtime (`tokio` feature): the output is read by
+//! tasks instead of threads, the timeout uses `tokio::time` and the child process is
+//! killed when the `AsyncCommand` is dropped.
+//!
+use super::exec::{OutputCollector, Termination, into_result, map_spawn_error, prepare_command};
+use super::stream::{LineSplitter, log_output_line};
+use super::{CommandError, CommandSpec, ExecuteCommandResult, OutputStream, StdinSource};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior, interval, sleep_until};
+use tokio_stream::Stream;
+
+const READ_BUFFER_SIZE: usize = 8192;
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+//
+// How long to keep reading the output after a terminated child process exits
+//
+const TERMINATED_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+///
+/// A complete output line without the trailing newline
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputLine {
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+///
+/// A running child process on the tokio runtime.
+///
+/// It's a `Stream` of the stdout and stderr lines, and `wait()` returns the result
+/// with the full output no matter how many lines were consumed. Dropping it kills
+/// the child process.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandSpec, OutputStream};
+///
+/// # tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+/// let mut command = CommandSpec::new("sh")
+///     .args(&["-c", "echo compiling; echo 'warning: unused' >&2"])
+///     .spawn_async()
+///     .unwrap();
+///
+/// while let Some(line) = command.next_line().await {
+///     if line.stream == OutputStream::Stderr {
+///         assert_eq!(line.line, "warning: unused");
+///     }
+/// }
+///
+/// let output = command.wait().await.check().unwrap();
+/// assert_eq!(output.stdout_lossy(), "compiling\n");
+/// # });
+/// ```
+///
+pub struct AsyncCommand {
+    spec: CommandSpec,
+    child: tokio::process::Child,
+    pid: Option<u32>,
+    events: mpsc::UnboundedReceiver<(OutputStream, Vec<u8>)>,
+    events_closed: bool,
+    tasks: Vec<JoinHandle<()>>,
+    collector: OutputCollector,
+    lines: LineSplitter,
+    log_lines: Option<LineSplitter>,
+    pending_lines: VecDeque<OutputLine>,
+    started_at: Instant,
+}
+
+fn spawn_reader<R: AsyncRead + Unpin + Send + 'static>(
+    mut reader: R,
+    stream: OutputStream,
+    sender: mpsc::UnboundedSender<(OutputStream, Vec<u8>)>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(size) => {
+                    if sender.send((stream, buffer[..size].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+impl AsyncCommand {
+    ///
+    /// Must be called within a tokio runtime
+    ///
+    pub(crate) fn spawn(spec: &CommandSpec) -> Result<Self, CommandError> {
+        let mut cmd = tokio::process::Command::from(prepare_command(spec)?);
+        cmd.kill_on_drop(true);
+        let mut child = cmd.spawn().map_err(|e| map_spawn_error(spec, e))?;
+
+        let mut tasks = Vec::with_capacity(3);
+        if let (StdinSource::Bytes(bytes), Some(mut stdin)) = (&spec.stdin, child.stdin.take()) {
+            let bytes = bytes.clone();
+            tasks.push(tokio::spawn(async move {
+                // The child process might exit without reading all stdin
+                let _ = stdin.write_all(&bytes).await;
+            }));
+        }
+
+        let (sender, events) = mpsc::unbounded_channel();
+        if let Some(stdout) = child.stdout.take() {
+            tasks.push(spawn_reader(stdout, OutputStream::Stdout, sender.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tasks.push(spawn_reader(stderr, OutputStream::Stderr, sender));
+        }
+
+        Ok(Self {
+            spec: spec.clone(),
+            pid: child.id(),
+            child,
+            events,
+            events_closed: false,
+            tasks,
+            collector: OutputCollector::new(spec),
+            lines: LineSplitter::default(),
+            log_lines: spec.log_output.then(LineSplitter::default),
+            pending_lines: VecDeque::new(),
+            started_at: Instant::now(),
+        })
+    }
+
+    ///
+    /// The OS process id, `None` if it has been reaped already
+    ///
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    fn handle_event(&mut self, stream: OutputStream, data: &[u8], split_lines: bool) {
+        self.collector.push(stream, data);
+        if let Some(splitter) = self.log_lines.as_mut() {
+            let module_name = self.spec.program_name();
+            splitter.push(stream, data, &mut |stream, line| {
+                log_output_line(module_name, stream, line)
+            });
+        }
+        if split_lines {
+            let pending_lines = &mut self.pending_lines;
+            self.lines.push(stream, data, &mut |stream, line| {
+                pending_lines.push_back(OutputLine {
+                    stream,
+                    line: line.to_string(),
+                })
+            });
+        }
+    }
+
+    fn handle_events_closed(&mut self) {
+        self.events_closed = true;
+        let pending_lines = &mut self.pending_lines;
+        self.lines.finish(&mut |stream, line| {
+            pending_lines.push_back(OutputLine {
+                stream,
+                line: line.to_string(),
+            })
+        });
+    }
+
+    ///
+    /// The next stdout or stderr line, `None` after both streams are closed
+    ///
+    pub async fn next_line(&mut self) -> Option<OutputLine> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    //
+    // Send `SIGTERM` and return when to send `SIGKILL`, or send `SIGKILL` directly if
+    // there is no grace period
+    //
+    fn terminate(&mut self) -> Option<Instant> {
+        if self.spec.kill_grace_period.is_zero() {
+            self.kill();
+            return None;
+        }
+
+        #[cfg(unix)]
+        if let Some(pid) = self.child.id() {
+            super::exec::send_terminate_signal_to(pid, self.spec.kill_process_group, false);
+            return Some(Instant::now() + self.spec.kill_grace_period);
+        }
+
+        self.kill();
+        None
+    }
+
+    fn kill(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.child.id() {
+            super::exec::send_terminate_signal_to(pid, self.spec.kill_process_group, true);
+            return;
+        }
+        let _ = self.child.start_kill();
+    }
+
+    async fn wait_for_exit(&mut self) -> io::Result<(ExitStatus, Option<Termination>)> {
+        let deadline = self.spec.timeout.map(|timeout| self.started_at + timeout);
+        let cancel_token = self.spec.cancel_token.clone();
+        let mut status: Option<ExitStatus> = None;
+        let mut exited_at: Option<Instant> = None;
+        let mut termination: Option<Termination> = None;
+        let mut terminated_at: Option<Instant> = None;
+        let mut kill_at: Option<Instant> = None;
+        #[cfg(unix)]
+        let mut group_killed = false;
+
+        //
+        // Only wakes the loop up, the cancellation is checked every round, so a child
+        // process which writes non-stop can't starve it
+        //
+        let mut cancel_poll = interval(CANCEL_POLL_INTERVAL);
+        cancel_poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            //
+            // Also checked after the child process exits, its own children might keep
+            // the pipes open for much longer
+            //
+            if termination.is_none() {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    termination = Some(Termination::TimedOut);
+                } else if cancel_token
+                    .as_ref()
+                    .is_some_and(|token| token.is_cancelled())
+                {
+                    termination = Some(Termination::Cancelled);
+                }
+
+                //
+                // An exited child process is reaped already, its pid must not be
+                // signaled anymore, only its process group below
+                //
+                if termination.is_some() {
+                    terminated_at = Some(Instant::now());
+                    if status.is_none() {
+                        kill_at = self.terminate();
+                    }
+                }
+            } else if status.is_none() && kill_at.is_some_and(|t| Instant::now() >= t) {
+                self.kill();
+                kill_at = None;
+            }
+
+            let mut drain_deadline = None;
+            if let Some(status) = status {
+                if self.events_closed {
+                    return Ok((status, termination));
+                }
+
+                if termination.is_some() {
+                    //
+                    // Clean up the rest of the process group
+                    //
+                    #[cfg(unix)]
+                    if self.spec.kill_process_group && !group_killed {
+                        // The process group id is the reaped leader's pid
+                        if let Some(pid) = self.pid {
+                            super::exec::send_terminate_signal_to(pid, true, true);
+                        }
+                        group_killed = true;
+                    }
+
+                    //
+                    // Don't wait for the reader tasks, they might never finish
+                    //
+                    let draining_since = exited_at.max(terminated_at).unwrap_or_else(Instant::now);
+                    if draining_since.elapsed() >= TERMINATED_DRAIN_TIMEOUT {
+                        return Ok((status, termination));
+                    }
+                    drain_deadline = Some(draining_since + TERMINATED_DRAIN_TIMEOUT);
+                }
+            }
+
+            let wake_at = match termination {
+                None => deadline,
+                Some(_) if status.is_none() => kill_at,
+                Some(_) => drain_deadline,
+            };
+
+            tokio::select! {
+                event = self.events.recv(), if !self.events_closed => match event {
+                    Some((stream, data)) => self.handle_event(stream, &data, false),
+                    None => self.events_closed = true,
+                },
+                result = self.child.wait(), if status.is_none() => {
+                    status = Some(result?);
+                    exited_at = Some(Instant::now());
+                }
+                _ = sleep_until(wake_at.unwrap_or_else(Instant::now)), if wake_at.is_some() => {}
+                _ = cancel_poll.tick(), if cancel_token.is_some() && termination.is_none() => {}
+            }
+        }
+    }
+
+    ///
+    /// Wait for the child process to exit (or terminate it on timeout/cancellation)
+    /// and return the result with the full output. Its `resource_usage` is always
+    /// `None`, the child process is reaped by the tokio runtime instead of `wait4`.
+    ///
+    pub async fn wait(mut self) -> ExecuteCommandResult {
+        let result = self.wait_for_exit().await;
+        if let Some(mut splitter) = self.log_lines.take() {
+            let module_name = self.spec.program_name();
+            splitter.finish(&mut |stream, line| log_output_line(module_name, stream, line));
+        }
+        let collector = std::mem::take(&mut self.collector);
+        into_result(&self.spec, collector, result)
+    }
+}
+
+impl Stream for AsyncCommand {
+    type Item = OutputLine;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<OutputLine>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(line) = this.pending_lines.pop_front() {
+                return Poll::Ready(Some(line));
+            }
+            if this.events_closed {
+                return Poll::Ready(None);
+            }
+            match this.events.poll_recv(cx) {
+                Poll::Ready(Some((stream, data))) => this.handle_event(stream, &data, true),
+                Poll::Ready(None) => this.handle_events_closed(),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for AsyncCommand {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::{CancellationToken, CaptureLimit};
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn execute_async_should_capture_output() {
+        let output = CommandSpec::new("sh")
+            .args(&["-c", "cat; echo err >&2"])
+            .stdin_bytes(b"hello\n".to_vec())
+            .execute_async()
+            .await
+            .check()
+            .unwrap();
+        assert_eq!(output.stdout_lossy(), "hello\n");
+        assert_eq!(output.stderr_lossy(), "err\n");
+    }
+
+    #[tokio::test]
+    async fn next_line_should_stream_lines() {
+        let mut command = CommandSpec::new("sh")
+            .args(&["-c", "printf 'a\\nb'; echo c >&2"])
+            .spawn_async()
+            .unwrap();
+
+        let mut lines = Vec::new();
+        while let Some(line) = command.next_line().await {
+            lines.push(line);
+        }
+        lines.sort_by_key(|line| line.line.clone());
+        assert_eq!(
+            lines,
+            vec![
+                OutputLine {
+                    stream: OutputStream::Stdout,
+                    line: String::from("a"),
+                },
+                OutputLine {
+                    stream: OutputStream::Stdout,
+                    line: String::from("b"),
+                },
+                OutputLine {
+                    stream: OutputStream::Stderr,
+                    line: String::from("c"),
+                },
+            ]
+        );
+        assert!(command.wait().await.is_success());
+    }
+
+    #[tokio::test]
+    async fn execute_async_should_time_out() {
+        let start = Instant::now();
+        let result = CommandSpec::new("sleep")
+            .arg("10")
+            .timeout(Duration::from_millis(100))
+            .execute_async()
+            .await;
+        assert!(matches!(result, ExecuteCommandResult::TimedOut(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn execute_async_should_be_cancelled() {
+        let token = CancellationToken::new();
+        let command = CommandSpec::new("sleep")
+            .arg("10")
+            .cancel_token(&token)
+            .spawn_async()
+            .unwrap();
+
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+        assert!(matches!(
+            command.wait().await,
+            ExecuteCommandResult::Cancelled(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn execute_async_should_be_cancelled_while_output_keeps_coming() {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+
+        let start = Instant::now();
+        let result = CommandSpec::new("yes")
+            .stdout_limit(CaptureLimit::tail(1024))
+            .timeout(Duration::from_secs(5))
+            .cancel_token(&token)
+            .execute_async()
+            .await;
+        assert!(matches!(result, ExecuteCommandResult::Cancelled(_)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn timeout_should_apply_while_grandchild_keeps_pipe_open() {
+        for kill_process_group in [false, true] {
+            let start = Instant::now();
+            let result = CommandSpec::new("sh")
+                .args(&["-c", "sleep 3 & echo hi"])
+                .timeout(Duration::from_millis(300))
+                .kill_process_group(kill_process_group)
+                .execute_async()
+                .await;
+
+            let ExecuteCommandResult::TimedOut(output) = result else {
+                panic!("should time out: {result:?}");
+            };
+            assert_eq!(output.stdout_lossy(), "hi\n");
+            assert_eq!(output.exit_code, Some(0));
+            assert!(
+                start.elapsed() < Duration::from_secs(2),
+                "{:?}",
+                start.elapsed()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_should_kill_the_child() {
+        let command = CommandSpec::new("sleep").arg("10").spawn_async().unwrap();
+        let pid = command.id().unwrap() as libc::pid_t;
+        drop(command);
+
+        //
+        // The killed child might not be reaped yet, wait until it's gone or a zombie
+        //
+        let start = std::time::Instant::now();
+        let state = loop {
+            let state = std::fs::read_to_string(format!("/proc/{pid}/stat"))
+                .map(|stat| stat.split(") ").nth(1).unwrap_or_default().to_string())
+                .unwrap_or_default();
+            if state.is_empty()
+                || state.starts_with('Z')
+                || start.elapsed() > Duration::from_secs(2)
+            {
+                break state;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        assert!(state.is_empty() || state.starts_with('Z'), "{state}");
+    }
+
+    #[tokio::test]
+    async fn spawn_async_should_fail_for_missing_program() {
+        let result = CommandSpec::new("no-such-program-xyz")
+            .execute_async()
+            .await;
+        assert!(matches!(
+            result,
+            ExecuteCommandResult::Fail(CommandError::NotFound { .. })
+        ));
+    }
+}
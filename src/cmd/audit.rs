@@ -0,0 +1,546 @@
+//!
+//! Dry-run and audit logging.
+//!
+//! Both can be switched on globally (`set_dry_run`, `set_audit_log`), which covers
+//! `execute_command*`, every `CommandSpec::execute*` (also through `BatchRunner`,
+//! `TaskGraph` and retries) and `Pipeline::execute`, or per runner (`DryRunRunner`,
+//! `AuditRunner`). Expect sessions and supervised processes aren't covered. Don't
+//! combine the global audit log with `AuditRunner<SystemRunner>`, every command is
+//! recorded twice then.
+//!
+use super::{CommandOutput, CommandRunner, CommandSpec, ExecuteCommandResult};
+use crate::json::JsonValue;
+use crate::logger::{LogLevel, format_timestamp, log};
+use crate::{info_log, warn_log};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+const LOG_MODULE_NAME: &str = "DryRun";
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+static AUDIT_LOG: RwLock<Option<AuditLog>> = RwLock::new(None);
+
+///
+/// Don't execute any command from now on, log it and return a synthetic success
+/// (exit code 0 without output) instead
+///
+pub fn set_dry_run(enable: bool) {
+    DRY_RUN.store(enable, Ordering::SeqCst);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+///
+/// Append every command from now on to the audit log file, `None` stops it
+///
+pub fn set_audit_log<P: AsRef<Path>>(path: Option<P>) -> io::Result<()> {
+    let log = path.map(AuditLog::open).transpose()?;
+    *AUDIT_LOG.write().unwrap_or_else(PoisonError::into_inner) = log;
+    Ok(())
+}
+
+//
+// e.g. `+LANG -HOME`, with `(cleared)` first if the env is cleared. Only the keys, the
+// values might be secrets.
+//
+fn describe_env(spec: &CommandSpec) -> String {
+    let cleared = spec.env_clear.then(|| String::from("(cleared)"));
+    let changes = spec.envs.iter().map(|(key, value)| match value {
+        Some(_) => format!("+{key}"),
+        None => format!("-{key}"),
+    });
+    cleared
+        .into_iter()
+        .chain(changes)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+//
+// Log what would run
+//
+pub(crate) fn log_dry_run(cmd_desc: &str, spec: Option<&CommandSpec>) {
+    let mut message = format!("Would run: {cmd_desc}");
+    if let Some(cwd) = spec.and_then(|spec| spec.cwd.as_ref()) {
+        message.push_str(&format!(", cwd: {}", cwd.display()));
+    }
+    let env = spec.map(describe_env).unwrap_or_default();
+    if !env.is_empty() {
+        message.push_str(&format!(", env: {env}"));
+    }
+    info_log!(LOG_MODULE_NAME, "execute", &message);
+}
+
+//
+// Log what would run and return the synthetic success
+//
+pub(crate) fn dry_run(spec: &CommandSpec) -> ExecuteCommandResult {
+    log_dry_run(&spec.cmd_desc(), Some(spec));
+    ExecuteCommandResult::Success(CommandOutput {
+        cmd_desc: spec.cmd_desc(),
+        exit_code: Some(0),
+        combined: spec.combined_output.then(Vec::new),
+        ..Default::default()
+    })
+}
+
+//
+// Run `execute` unless the global dry-run is on, and write the global audit log
+//
+pub(crate) fn run_global(
+    spec: &CommandSpec,
+    execute: impl FnOnce() -> ExecuteCommandResult,
+) -> ExecuteCommandResult {
+    let (started_at, start) = (SystemTime::now(), Instant::now());
+    let dry_run = is_dry_run();
+    let result = if dry_run {
+        self::dry_run(spec)
+    } else {
+        execute()
+    };
+    record_global(spec, &result, started_at, start.elapsed(), dry_run);
+    result
+}
+
+pub(crate) fn record_global(
+    spec: &CommandSpec,
+    result: &ExecuteCommandResult,
+    started_at: SystemTime,
+    duration: Duration,
+    dry_run: bool,
+) {
+    if let Some(log) = AUDIT_LOG
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+    {
+        log.record(&AuditEntry::new(
+            spec, result, started_at, duration, dry_run,
+        ));
+    }
+}
+
+//
+// Write the global audit log for the pipeline, it's recorded as one command
+//
+pub(crate) fn record_global_pipeline(
+    cmd_desc: &str,
+    status: &'static str,
+    exit_code: Option<i32>,
+    error: Option<String>,
+    started_at: SystemTime,
+    duration: Duration,
+    dry_run: bool,
+) {
+    if let Some(log) = AUDIT_LOG
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+    {
+        log.record(&AuditEntry {
+            timestamp: started_at,
+            cmd_desc: cmd_desc.to_string(),
+            cwd: None,
+            env: String::new(),
+            duration,
+            status,
+            exit_code,
+            signal: None,
+            error,
+            dry_run,
+        });
+    }
+}
+
+struct AuditEntry {
+    timestamp: SystemTime,
+    cmd_desc: String,
+    cwd: Option<String>,
+    env: String,
+    duration: Duration,
+    status: &'static str,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    error: Option<String>,
+    dry_run: bool,
+}
+
+impl AuditEntry {
+    fn new(
+        spec: &CommandSpec,
+        result: &ExecuteCommandResult,
+        timestamp: SystemTime,
+        duration: Duration,
+        dry_run: bool,
+    ) -> Self {
+        let mut entry = Self {
+            timestamp,
+            cmd_desc: spec.cmd_desc(),
+            cwd: spec.cwd.as_ref().map(|cwd| cwd.display().to_string()),
+            env: describe_env(spec),
+            duration,
+            status: "exited",
+            exit_code: None,
+            signal: None,
+            error: None,
+            dry_run,
+        };
+        let output = match result {
+            ExecuteCommandResult::Success(output) => output,
+            ExecuteCommandResult::TimedOut(output) => {
+                entry.status = "timed_out";
+                output
+            }
+            ExecuteCommandResult::Cancelled(output) => {
+                entry.status = "cancelled";
+                output
+            }
+            ExecuteCommandResult::Fail(error) => {
+                entry.status = "error";
+                entry.error = Some(error.to_string());
+                return entry;
+            }
+        };
+        entry.exit_code = output.exit_code;
+        entry.signal = output.signal;
+        entry
+    }
+}
+
+fn current_user() -> String {
+    if let Some(user) = ["USER", "USERNAME", "LOGNAME"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok().filter(|user| !user.is_empty()))
+    {
+        return user;
+    }
+
+    #[cfg(unix)]
+    return format!("uid:{}", unsafe { libc::getuid() });
+    #[cfg(not(unix))]
+    String::from("unknown")
+}
+
+///
+/// An append-only JSON Lines audit log, one command per line:
+///
+/// ```json
+/// {"timestamp":"2026-10-19T08:30:00.123Z","user":"ops","pid":4242,"cmd_desc":"systemctl restart nginx","cwd":null,"env":"","dry_run":false,"duration_ms":215,"status":"exited","exit_code":0,"signal":null,"error":null}
+/// ```
+///
+/// `timestamp` is when the command started, `user` and `pid` are the current
+/// process's. `env` only lists the changed keys (e.g. `+API_TOKEN -HOME`), never the
+/// values, so secrets passed by `CommandSpec::env` don't end up in the audit trail.
+/// `status` is `exited`, `timed_out`, `cancelled` or `error` (failed to run, see
+/// `error`).
+///
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+    user: String,
+}
+
+impl AuditLog {
+    ///
+    /// Open the file for appending, it's created if it doesn't exist
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            user: current_user(),
+        })
+    }
+
+    fn record(&self, entry: &AuditEntry) {
+        let string_or_null =
+            |value: &Option<String>| value.clone().map_or(JsonValue::Null, JsonValue::String);
+        let number_or_null =
+            |value: Option<i32>| value.map_or(JsonValue::Null, |v| JsonValue::Number(v as f64));
+        let line = JsonValue::Object(vec![
+            (
+                String::from("timestamp"),
+                JsonValue::String(format_timestamp(entry.timestamp)),
+            ),
+            (String::from("user"), JsonValue::String(self.user.clone())),
+            (
+                String::from("pid"),
+                JsonValue::Number(std::process::id() as f64),
+            ),
+            (
+                String::from("cmd_desc"),
+                JsonValue::String(entry.cmd_desc.clone()),
+            ),
+            (String::from("cwd"), string_or_null(&entry.cwd)),
+            (String::from("env"), JsonValue::String(entry.env.clone())),
+            (String::from("dry_run"), JsonValue::Bool(entry.dry_run)),
+            (
+                String::from("duration_ms"),
+                JsonValue::Number(entry.duration.as_millis() as f64),
+            ),
+            (
+                String::from("status"),
+                JsonValue::String(entry.status.to_string()),
+            ),
+            (String::from("exit_code"), number_or_null(entry.exit_code)),
+            (String::from("signal"), number_or_null(entry.signal)),
+            (String::from("error"), string_or_null(&entry.error)),
+        ])
+        .to_json();
+
+        //
+        // One `write_all` per line, so the concurrent writers don't interleave
+        //
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = file.write_all(format!("{line}\n").as_bytes()) {
+            warn_log!(
+                "AuditLog",
+                "record",
+                &format!("Failed to record `{}`: {e}", entry.cmd_desc)
+            );
+        }
+    }
+}
+
+///
+/// Never execute anything: log every command with its cwd and env keys, and
+/// return a synthetic success (exit code 0 without output)
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DryRunRunner;
+
+impl CommandRunner for DryRunRunner {
+    fn run(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        dry_run(spec)
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+///
+/// Run the commands by the inner runner and record every one of them into the audit
+/// log.
+///
+/// `SystemRunner` also writes the global audit log (`set_audit_log`), so with both
+/// set, `AuditRunner<SystemRunner>` records every command twice: once into its own
+/// log and once into the global one.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{AuditLog, AuditRunner, CommandRunner, SystemRunner};
+///
+/// let path = std::env::temp_dir().join("rust_utils_doc_audit.jsonl");
+/// let runner = AuditRunner::new(SystemRunner, AuditLog::open(&path).unwrap());
+/// assert!(runner.execute_command(vec!["echo", "hello"]).is_success());
+///
+/// let log = std::fs::read_to_string(&path).unwrap();
+/// assert!(log.lines().last().unwrap().contains(r#""cmd_desc":"echo hello""#));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+///
+#[derive(Debug)]
+pub struct AuditRunner<R: CommandRunner> {
+    inner: R,
+    log: AuditLog,
+}
+
+impl<R: CommandRunner> AuditRunner<R> {
+    pub fn new(inner: R, log: AuditLog) -> Self {
+        Self { inner, log }
+    }
+}
+
+impl<R: CommandRunner> CommandRunner for AuditRunner<R> {
+    fn run(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        let (started_at, start) = (SystemTime::now(), Instant::now());
+        let result = self.inner.run(spec);
+        self.log.record(&AuditEntry::new(
+            spec,
+            &result,
+            started_at,
+            start.elapsed(),
+            self.inner.is_dry_run() || is_dry_run(),
+        ));
+        result
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.inner.is_dry_run()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::{
+        CannedResult, CommandMatcher, FakeRunner, Pipeline, PipelineResult, PtySize, SystemRunner,
+    };
+    use crate::json;
+
+    fn field(line: &JsonValue, key: &str) -> JsonValue {
+        let JsonValue::Object(fields) = line else {
+            panic!("{line:?}");
+        };
+        fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn describe_env_should_work() {
+        let spec = CommandSpec::new("env")
+            .env_clear()
+            .env("LANG", "C")
+            .env("GREETING", "hello world")
+            .env_remove("HOME");
+        assert_eq!(describe_env(&spec), "(cleared) +LANG +GREETING -HOME");
+        assert_eq!(describe_env(&CommandSpec::new("env")), "");
+    }
+
+    #[test]
+    fn dry_run_runner_should_not_execute() {
+        let marker =
+            std::env::temp_dir().join(format!("rust_utils_dry_run_test_{}", std::process::id()));
+        let result = DryRunRunner.execute_command(vec!["touch", marker.to_str().unwrap()]);
+        let output = result.check().unwrap();
+        assert_eq!(output.exit_code, Some(0));
+        assert!(output.stdout.is_empty());
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn audit_runner_should_record_every_command() {
+        let path = std::env::temp_dir().join(format!(
+            "rust_utils_audit_test_{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let runner = AuditRunner::new(SystemRunner, AuditLog::open(&path).unwrap());
+        let _ = runner.execute_command(vec!["sh", "-c", "exit 3"]);
+        let _ = runner.execute_command(vec!["rust_utils_no_such_program"]);
+
+        let fake = FakeRunner::new().on(CommandMatcher::argv(&["deploy"]), CannedResult::exit(0));
+        let dry_runner = AuditRunner::new(DryRunRunner, AuditLog::open(&path).unwrap());
+        let _ = AuditRunner::new(fake, AuditLog::open(&path).unwrap())
+            .run(&CommandSpec::new("deploy").cwd("/tmp"));
+        let _ = dry_runner.execute_command(vec!["rm", "-rf", "/tmp/x"]);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = content
+            .lines()
+            .map(|line| json::parse(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4, "{content}");
+
+        assert_eq!(
+            field(&lines[0], "cmd_desc").as_str(),
+            Some("sh -c 'exit 3'")
+        );
+        assert_eq!(field(&lines[0], "status").as_str(), Some("exited"));
+        assert_eq!(field(&lines[0], "exit_code"), JsonValue::Number(3.0));
+        assert_eq!(
+            field(&lines[0], "pid"),
+            JsonValue::Number(std::process::id() as f64)
+        );
+        assert!(!field(&lines[0], "user").as_str().unwrap().is_empty());
+
+        assert_eq!(field(&lines[1], "status").as_str(), Some("error"));
+        assert_eq!(field(&lines[1], "exit_code"), JsonValue::Null);
+        assert!(field(&lines[1], "error").as_str().is_some());
+
+        assert_eq!(field(&lines[2], "cwd").as_str(), Some("/tmp"));
+        assert_eq!(field(&lines[2], "dry_run"), JsonValue::Bool(false));
+
+        assert_eq!(field(&lines[3], "cmd_desc").as_str(), Some("rm -rf /tmp/x"));
+        assert_eq!(field(&lines[3], "dry_run"), JsonValue::Bool(true));
+    }
+
+    //
+    // The global switches affect every test running in parallel, so the test runs
+    // alone in a child process: the test binary re-runs itself with only this test
+    // and the env var set.
+    //
+    #[test]
+    fn global_dry_run_and_audit_log_should_cover_everything() {
+        const ISOLATED_ENV: &str = "RUST_UTILS_GLOBAL_AUDIT_TEST";
+        let test_name = format!(
+            "{}::global_dry_run_and_audit_log_should_cover_everything",
+            module_path!().split_once("::").unwrap().1
+        );
+        if std::env::var_os(ISOLATED_ENV).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([test_name.as_str(), "--exact", "--test-threads=1"])
+                .env(ISOLATED_ENV, "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{stdout}");
+            assert!(stdout.contains("1 passed"), "{stdout}");
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(format!(
+            "rust_utils_global_audit_test_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("audit.jsonl");
+        let touch = |name: &str| CommandSpec::new("touch").arg(dir.join(name).to_str().unwrap());
+
+        set_audit_log(Some(&log_path)).unwrap();
+        set_dry_run(true);
+
+        let result = touch("execute").execute();
+        assert_eq!(result.check().unwrap().exit_code, Some(0));
+        let result = touch("pty").execute_pty(PtySize::default());
+        assert_eq!(result.check().unwrap().exit_code, Some(0));
+        let pipeline = Pipeline::new(touch("pipeline")).pipe(CommandSpec::new("cat"));
+        assert!(matches!(pipeline.execute(), PipelineResult::Success(_)));
+
+        set_dry_run(false);
+        set_audit_log(None::<&Path>).unwrap();
+
+        for name in ["execute", "pty", "pipeline"] {
+            assert!(!dir.join(name).exists(), "{name} shouldn't run");
+        }
+
+        let content = std::fs::read_to_string(&log_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let lines = content
+            .lines()
+            .map(|line| json::parse(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{content}");
+
+        let cmd_descs = lines
+            .iter()
+            .map(|line| field(line, "cmd_desc").as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cmd_descs,
+            vec![
+                touch("execute").cmd_desc(),
+                touch("pty").cmd_desc(),
+                pipeline.cmd_desc(),
+            ]
+        );
+        for line in &lines {
+            assert_eq!(field(line, "dry_run"), JsonValue::Bool(true));
+            assert_eq!(field(line, "status").as_str(), Some("exited"));
+            assert_eq!(field(line, "exit_code"), JsonValue::Number(0.0));
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+///
+/// Cancel running commands from another thread. All clones share the same state,
+/// cancelling any of them cancels every command that uses it.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CancellationToken, CommandSpec, ExecuteCommandResult};
+/// use std::time::Duration;
+///
+/// let token = CancellationToken::new();
+///
+/// let canceller = token.clone();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(Duration::from_millis(100));
+///     canceller.cancel();
+/// });
+///
+/// let result = CommandSpec::new("sleep").arg("10").cancel_token(&token).execute();
+/// assert!(matches!(result, ExecuteCommandResult::Cancelled(_)));
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    parent: Option<Box<CancellationToken>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    ///
+    /// Create a token which is cancelled when this token is cancelled, cancelling the
+    /// child doesn't cancel this token.
+    ///
+    pub fn child_token(&self) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.is_cancelled())
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn child_token_should_work() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let other_child = parent.child_token();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!other_child.is_cancelled());
+
+        parent.cancel();
+        assert!(other_child.is_cancelled());
+        assert_ne!(child, other_child);
+        assert_eq!(parent, parent.clone());
+    }
+}
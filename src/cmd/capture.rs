@@ -0,0 +1,364 @@
+//!
+//! Bound how much of a stream is kept in memory, a runaway child process (e.g.
+//! `yes`) can't exhaust the memory then.
+//!
+use crate::logger::{LogLevel, log};
+use crate::warn_log;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+const LOG_MODULE_NAME: &str = "CaptureBuffer";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeepMode {
+    Head,
+    Tail,
+    HeadAndTail,
+}
+
+///
+/// How much of a stream is captured, the rest is dropped (or spilled into a temp
+/// file). See `CommandSpec::stdout_limit`.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CaptureLimit, CommandSpec};
+///
+/// let output = CommandSpec::new("seq")
+///     .args(&["1", "100000"])
+///     .stdout_limit(CaptureLimit::head_and_tail(16))
+///     .execute()
+///     .check()
+///     .unwrap();
+///
+/// let truncation = output.stdout_truncation.as_ref().unwrap();
+/// assert_eq!(truncation.total_bytes, 588895);
+/// assert_eq!(
+///     output.stdout_lossy(),
+///     "1\n2\n3\n4\n\n... 588879 bytes omitted ...\n\n100000\n"
+/// );
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureLimit {
+    max_bytes: usize,
+    mode: KeepMode,
+    spill: bool,
+}
+
+impl CaptureLimit {
+    ///
+    /// Keep the first `max_bytes`
+    ///
+    pub fn head(max_bytes: usize) -> Self {
+        Self::with_mode(max_bytes, KeepMode::Head)
+    }
+
+    ///
+    /// Keep the last `max_bytes`
+    ///
+    pub fn tail(max_bytes: usize) -> Self {
+        Self::with_mode(max_bytes, KeepMode::Tail)
+    }
+
+    ///
+    /// Keep the first and the last `max_bytes / 2`, joined by a
+    /// `\n... N bytes omitted ...\n` marker if anything is dropped
+    ///
+    pub fn head_and_tail(max_bytes: usize) -> Self {
+        Self::with_mode(max_bytes, KeepMode::HeadAndTail)
+    }
+
+    fn with_mode(max_bytes: usize, mode: KeepMode) -> Self {
+        Self {
+            max_bytes,
+            mode,
+            spill: false,
+        }
+    }
+
+    ///
+    /// Once the limit is exceeded, write the whole stream (from the first byte) into a
+    /// temp file, see `Truncation::spill_file`. The file isn't removed automatically.
+    ///
+    pub fn spill_to_temp_file(mut self, enable: bool) -> Self {
+        self.spill = enable;
+        self
+    }
+}
+
+///
+/// The captured stream was cut by its `CaptureLimit`
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Truncation {
+    ///
+    /// How many bytes the child process wrote
+    ///
+    pub total_bytes: u64,
+    pub omitted_bytes: u64,
+
+    ///
+    /// The whole stream, if spilling is enabled and the temp file could be written
+    ///
+    pub spill_file: Option<PathBuf>,
+}
+
+//
+// One captured stream, all of it without a limit
+//
+#[derive(Debug, Default)]
+pub(crate) struct CaptureBuffer {
+    name: &'static str,
+    limit: Option<CaptureLimit>,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    total_bytes: u64,
+    spill: Option<(PathBuf, File)>,
+    spill_failed: bool,
+}
+
+impl CaptureBuffer {
+    pub(crate) fn new(name: &'static str, limit: Option<CaptureLimit>) -> Self {
+        Self {
+            name,
+            limit,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        let Some(limit) = self.limit else {
+            self.head.extend_from_slice(data);
+            self.total_bytes += data.len() as u64;
+            return;
+        };
+
+        let exceeded = self.total_bytes + data.len() as u64 > limit.max_bytes as u64;
+        if limit.spill && exceeded && self.spill.is_none() && !self.spill_failed {
+            self.start_spill();
+        }
+        if let Some((path, file)) = self.spill.as_mut()
+            && let Err(e) = file.write_all(data)
+        {
+            warn_log!(
+                LOG_MODULE_NAME,
+                "push",
+                &format!("Failed to spill into '{}': {e}", path.display())
+            );
+            let _ = std::fs::remove_file(path);
+            self.spill = None;
+            self.spill_failed = true;
+        }
+
+        let (head_max, tail_max) = match limit.mode {
+            KeepMode::Head => (limit.max_bytes, 0),
+            KeepMode::Tail => (0, limit.max_bytes),
+            KeepMode::HeadAndTail => (limit.max_bytes / 2, limit.max_bytes - limit.max_bytes / 2),
+        };
+        let taken = head_max.saturating_sub(self.head.len()).min(data.len());
+        self.head.extend_from_slice(&data[..taken]);
+
+        let rest = &data[taken..];
+        if rest.len() >= tail_max {
+            self.tail.clear();
+            self.tail.extend(&rest[rest.len() - tail_max..]);
+        } else {
+            self.tail.extend(rest);
+            let excess = self.tail.len().saturating_sub(tail_max);
+            self.tail.drain(..excess);
+        }
+        self.total_bytes += data.len() as u64;
+    }
+
+    //
+    // Nothing is dropped yet when the limit is exceeded for the first time, so the
+    // file gets the whole stream
+    //
+    fn start_spill(&mut self) {
+        let spilled = create_spill_file(self.name).and_then(|(path, file)| {
+            let (head, tail) = self.tail.as_slices();
+            let written = [self.head.as_slice(), head, tail]
+                .into_iter()
+                .try_for_each(|data| (&file).write_all(data));
+            match written {
+                Ok(_) => Ok((path, file)),
+                Err(e) => {
+                    let _ = std::fs::remove_file(&path);
+                    Err(io::Error::new(
+                        e.kind(),
+                        format!("Failed to spill into '{}': {e}", path.display()),
+                    ))
+                }
+            }
+        });
+        match spilled {
+            Ok(spill) => self.spill = Some(spill),
+            Err(e) => {
+                warn_log!(LOG_MODULE_NAME, "push", &e.to_string());
+                self.spill_failed = true;
+            }
+        }
+    }
+
+    //
+    // All of the data without a limit, otherwise only the head
+    //
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.head
+    }
+
+    pub(crate) fn finish(self) -> (Vec<u8>, Option<Truncation>) {
+        let Self {
+            limit,
+            mut head,
+            tail,
+            total_bytes,
+            spill,
+            ..
+        } = self;
+
+        let omitted_bytes = total_bytes - (head.len() + tail.len()) as u64;
+        if omitted_bytes == 0 {
+            head.extend(tail);
+            return (head, None);
+        }
+
+        if limit.is_some_and(|limit| limit.mode == KeepMode::HeadAndTail) {
+            head.extend_from_slice(format!("\n... {omitted_bytes} bytes omitted ...\n").as_bytes());
+        }
+        head.extend(tail);
+
+        let spill_file = spill.and_then(|(path, mut file)| match file.flush() {
+            Ok(()) => Some(path),
+            Err(_) => None,
+        });
+        let truncation = Truncation {
+            total_bytes,
+            omitted_bytes,
+            spill_file,
+        };
+        (head, Some(truncation))
+    }
+}
+
+//
+// Create a new temp file only readable by the current user. The name is predictable,
+// so never open an existing file (e.g. a symlink planted by another user), try the
+// next name instead.
+//
+fn create_spill_file(name: &str) -> io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    const MAX_ATTEMPTS: usize = 16;
+
+    let mut last_error = None;
+    for _ in 0..MAX_ATTEMPTS {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let path = std::env::temp_dir().join(format!(
+            "rust_utils_{}_{}_{nanos:08x}_{name}.out",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst),
+        ));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => last_error = Some(e),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("Failed to create '{}': {e}", path.display()),
+                ));
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::other("no spill file name available")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(limit: CaptureLimit, chunks: &[&str]) -> (String, Option<Truncation>) {
+        let mut buffer = CaptureBuffer::new("test", Some(limit));
+        for chunk in chunks {
+            buffer.push(chunk.as_bytes());
+        }
+        let (data, truncation) = buffer.finish();
+        (String::from_utf8(data).unwrap(), truncation)
+    }
+
+    #[test]
+    fn limits_should_keep_the_right_part() {
+        let chunks = ["0123", "456", "789ab"];
+        assert_eq!(capture(CaptureLimit::head(20), &chunks).0, "0123456789ab");
+        assert_eq!(capture(CaptureLimit::head(20), &chunks).1, None);
+
+        let (data, truncation) = capture(CaptureLimit::head(5), &chunks);
+        assert_eq!(data, "01234");
+        assert_eq!(
+            truncation,
+            Some(Truncation {
+                total_bytes: 12,
+                omitted_bytes: 7,
+                spill_file: None,
+            })
+        );
+
+        assert_eq!(capture(CaptureLimit::tail(5), &chunks).0, "789ab");
+        assert_eq!(capture(CaptureLimit::tail(10), &chunks).0, "23456789ab");
+        assert_eq!(
+            capture(CaptureLimit::head_and_tail(6), &chunks).0,
+            "012\n... 6 bytes omitted ...\n9ab"
+        );
+        assert_eq!(
+            capture(CaptureLimit::head_and_tail(12), &chunks).0,
+            "0123456789ab"
+        );
+    }
+
+    #[test]
+    fn spill_should_keep_the_whole_stream() {
+        let limit = CaptureLimit::tail(4).spill_to_temp_file(true);
+        let (data, truncation) = capture(limit, &["abc", "defgh", "ij"]);
+        assert_eq!(data, "ghij");
+
+        let spill_file = truncation.unwrap().spill_file.unwrap();
+        assert_eq!(std::fs::read_to_string(&spill_file).unwrap(), "abcdefghij");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&spill_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&spill_file).unwrap();
+
+        let (_, truncation) = capture(limit, &["abc"]);
+        assert_eq!(truncation, None);
+    }
+
+    #[test]
+    fn spill_file_should_never_be_reused() {
+        let (first, _) = create_spill_file("test").unwrap();
+        let (second, _) = create_spill_file("test").unwrap();
+        assert_ne!(first, second);
+
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+    }
+}
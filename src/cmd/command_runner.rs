@@ -0,0 +1,374 @@
+//!
+//! Run commands through a `CommandRunner`, so the code calling them can be tested
+//! with `FakeRunner` (canned results) or `ReplayRunner` (recorded results) instead of
+//! running real programs.
+//!
+use super::{CommandError, CommandOutput, CommandSpec, ExecuteCommandResult};
+use regex::Regex;
+use std::sync::Mutex;
+
+///
+/// Something that executes a `CommandSpec`.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CannedResult, CommandMatcher, CommandRunner, FakeRunner, SystemRunner};
+///
+/// fn current_branch(runner: &dyn CommandRunner) -> Option<String> {
+///     let output = runner
+///         .execute_command(vec!["git", "rev-parse", "--abbrev-ref", "HEAD"])
+///         .check()
+///         .ok()?;
+///     Some(output.stdout_lossy().trim().to_string())
+/// }
+///
+/// let fake = FakeRunner::new().on(
+///     CommandMatcher::argv(&["git", "rev-parse", "..."]),
+///     CannedResult::exit(0).stdout("main\n"),
+/// );
+/// assert_eq!(current_branch(&fake), Some(String::from("main")));
+/// assert_eq!(fake.calls().len(), 1);
+///
+/// // The real one in production
+/// let _ = current_branch(&SystemRunner);
+/// ```
+///
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, spec: &CommandSpec) -> ExecuteCommandResult;
+
+    ///
+    /// Whether the runner only pretends to run the commands
+    ///
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+
+    ///
+    /// Same as `cmd::execute_command` but through this runner
+    ///
+    fn execute_command(&self, cmd_list: Vec<&str>) -> ExecuteCommandResult {
+        match CommandSpec::from_list(&cmd_list) {
+            Some(spec) => self.run(&spec),
+            None => ExecuteCommandResult::Fail(CommandError::InvalidCommand {
+                reason: String::from("'cmd_list' is empty"),
+            }),
+        }
+    }
+
+    ///
+    /// Same as `cmd::execute_command_line` but through this runner
+    ///
+    fn execute_command_line(&self, line: &str) -> ExecuteCommandResult {
+        match CommandSpec::parse(line) {
+            Ok(spec) => self.run(&spec),
+            Err(reason) => ExecuteCommandResult::Fail(CommandError::InvalidCommand {
+                reason: format!("'{line}': {reason}"),
+            }),
+        }
+    }
+}
+
+///
+/// Really run the command as a child process
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        spec.execute()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ArgvPattern {
+    ///
+    /// `*` matches any single argument and a trailing `...` matches the rest
+    ///
+    Args(Vec<String>),
+    Regex(Regex),
+}
+
+///
+/// Which commands a canned result is for: the program and arguments, plus the env
+/// vars explicitly set on the spec.
+///
+#[derive(Debug, Clone)]
+pub struct CommandMatcher {
+    argv: ArgvPattern,
+    envs: Vec<(String, String)>,
+}
+
+impl CommandMatcher {
+    ///
+    /// Match the program and arguments, `*` matches any single argument and a
+    /// trailing `...` matches all remaining arguments (including none)
+    ///
+    pub fn argv(pattern: &[&str]) -> Self {
+        Self {
+            argv: ArgvPattern::Args(pattern.iter().map(|arg| arg.to_string()).collect()),
+            envs: Vec::new(),
+        }
+    }
+
+    ///
+    /// Match the regex against the shell-quoted command line, see
+    /// `CommandSpec::cmd_desc`
+    ///
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            argv: ArgvPattern::Regex(Regex::new(pattern)?),
+            envs: Vec::new(),
+        })
+    }
+
+    ///
+    /// Also require the env var set by `CommandSpec::env`
+    ///
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn matches(&self, spec: &CommandSpec) -> bool {
+        let argv_matched = match &self.argv {
+            ArgvPattern::Args(pattern) => {
+                let argv = std::iter::once(&spec.program)
+                    .chain(&spec.args)
+                    .collect::<Vec<_>>();
+                match pattern.split_last() {
+                    Some((last, init)) if last == "..." => {
+                        argv.len() >= init.len() && args_match(init, &argv[..init.len()])
+                    }
+                    _ => argv.len() == pattern.len() && args_match(pattern, &argv),
+                }
+            }
+            ArgvPattern::Regex(regex) => regex.is_match(&spec.cmd_desc()),
+        };
+
+        argv_matched
+            && self.envs.iter().all(|(key, value)| {
+                spec.envs
+                    .iter()
+                    .rev()
+                    .find(|(k, _)| k == key)
+                    .is_some_and(|(_, v)| v.as_deref() == Some(value))
+            })
+    }
+}
+
+fn args_match(pattern: &[String], argv: &[&String]) -> bool {
+    pattern
+        .iter()
+        .zip(argv)
+        .all(|(pattern, arg)| pattern == "*" || pattern == *arg)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CannedKind {
+    Exit(i32),
+    Signal(i32),
+    TimedOut,
+    NotFound,
+}
+
+///
+/// The result `FakeRunner` returns for the matched command
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct CannedResult {
+    kind: CannedKind,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl CannedResult {
+    ///
+    /// The command exits with `exit_code`
+    ///
+    pub fn exit(exit_code: i32) -> Self {
+        Self::with_kind(CannedKind::Exit(exit_code))
+    }
+
+    ///
+    /// The command is terminated by `signal`
+    ///
+    pub fn signal(signal: i32) -> Self {
+        Self::with_kind(CannedKind::Signal(signal))
+    }
+
+    pub fn timed_out() -> Self {
+        Self::with_kind(CannedKind::TimedOut)
+    }
+
+    ///
+    /// The program doesn't exist
+    ///
+    pub fn not_found() -> Self {
+        Self::with_kind(CannedKind::NotFound)
+    }
+
+    fn with_kind(kind: CannedKind) -> Self {
+        Self {
+            kind,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    pub fn stdout(mut self, stdout: &str) -> Self {
+        self.stdout = stdout.as_bytes().to_vec();
+        self
+    }
+
+    pub fn stderr(mut self, stderr: &str) -> Self {
+        self.stderr = stderr.as_bytes().to_vec();
+        self
+    }
+
+    pub fn to_result(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        let mut output = CommandOutput {
+            cmd_desc: spec.cmd_desc(),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            ..Default::default()
+        };
+        if spec.combined_output {
+            output.combined = Some([self.stdout.as_slice(), &self.stderr].concat());
+        }
+
+        match self.kind {
+            CannedKind::Exit(exit_code) => {
+                output.exit_code = Some(exit_code);
+                ExecuteCommandResult::Success(output)
+            }
+            CannedKind::Signal(signal) => {
+                output.signal = Some(signal);
+                ExecuteCommandResult::Success(output)
+            }
+            CannedKind::TimedOut => ExecuteCommandResult::TimedOut(output),
+            CannedKind::NotFound => ExecuteCommandResult::Fail(CommandError::NotFound {
+                program: spec.program.clone(),
+                searched: Vec::new(),
+            }),
+        }
+    }
+}
+
+///
+/// Return canned results by the first matching `CommandMatcher` without running
+/// anything, and remember every call. A command without any match fails with
+/// `CommandError::InvalidCommand`.
+///
+#[derive(Debug, Default)]
+pub struct FakeRunner {
+    rules: Vec<(CommandMatcher, CannedResult)>,
+    calls: Mutex<Vec<CommandSpec>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on(mut self, matcher: CommandMatcher, result: CannedResult) -> Self {
+        self.rules.push((matcher, result));
+        self
+    }
+
+    ///
+    /// All specs run so far, in order
+    ///
+    pub fn calls(&self) -> Vec<CommandSpec> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        self.calls.lock().unwrap().push(spec.clone());
+        match self.rules.iter().find(|(matcher, _)| matcher.matches(spec)) {
+            Some((_, result)) => result.to_result(spec),
+            None => ExecuteCommandResult::Fail(CommandError::InvalidCommand {
+                reason: format!("FakeRunner has no result for `{}`", spec.cmd_desc()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matcher_should_work() {
+        let spec = CommandSpec::new("git")
+            .args(&["commit", "-m", "fix: typo"])
+            .env("GIT_AUTHOR_NAME", "rust");
+
+        assert!(CommandMatcher::argv(&["git", "commit", "-m", "*"]).matches(&spec));
+        assert!(CommandMatcher::argv(&["git", "..."]).matches(&spec));
+        assert!(CommandMatcher::argv(&["git", "commit", "-m", "fix: typo", "..."]).matches(&spec));
+        assert!(!CommandMatcher::argv(&["git", "commit"]).matches(&spec));
+        assert!(!CommandMatcher::argv(&["git", "push", "..."]).matches(&spec));
+
+        assert!(
+            CommandMatcher::regex("^git commit -m 'fix: .*'$")
+                .unwrap()
+                .matches(&spec)
+        );
+        assert!(
+            CommandMatcher::argv(&["git", "..."])
+                .env("GIT_AUTHOR_NAME", "rust")
+                .matches(&spec)
+        );
+        assert!(
+            !CommandMatcher::argv(&["git", "..."])
+                .env("GIT_AUTHOR_NAME", "other")
+                .matches(&spec)
+        );
+    }
+
+    #[test]
+    fn fake_runner_should_return_canned_results() {
+        let fake = FakeRunner::new()
+            .on(
+                CommandMatcher::argv(&["make", "test"]),
+                CannedResult::exit(2).stderr("1 test failed\n"),
+            )
+            .on(
+                CommandMatcher::argv(&["make", "..."]),
+                CannedResult::exit(0),
+            )
+            .on(
+                CommandMatcher::argv(&["cargo", "..."]),
+                CannedResult::not_found(),
+            );
+
+        let error = fake
+            .execute_command(vec!["make", "test"])
+            .check()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`make test` exited with code 2, stderr:\n1 test failed"
+        );
+        assert!(fake.execute_command_line("make build").is_success());
+        assert!(matches!(
+            fake.execute_command(vec!["cargo", "build"]),
+            ExecuteCommandResult::Fail(CommandError::NotFound { .. })
+        ));
+        assert!(matches!(
+            fake.execute_command(vec!["ls"]),
+            ExecuteCommandResult::Fail(CommandError::InvalidCommand { .. })
+        ));
+
+        let calls = fake
+            .calls()
+            .iter()
+            .map(|spec| spec.cmd_desc())
+            .collect::<Vec<_>>();
+        assert_eq!(calls, vec!["make test", "make build", "cargo build", "ls"]);
+    }
+}
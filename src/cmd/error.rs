@@ -0,0 +1,192 @@
+use super::CommandOutput;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+///
+/// Why a command couldn't run or didn't succeed
+///
+#[derive(Debug)]
+pub enum CommandError {
+    ///
+    /// The command line is empty or can't be parsed
+    ///
+    InvalidCommand { reason: String },
+
+    ///
+    /// The program doesn't exist, `searched` is every location tried (empty if
+    /// unknown)
+    ///
+    NotFound {
+        program: String,
+        searched: Vec<PathBuf>,
+    },
+
+    ///
+    /// The program exists but isn't executable by the current user
+    ///
+    PermissionDenied { program: String },
+
+    ///
+    /// The child process couldn't be spawned for any other reason, e.g. the working
+    /// directory doesn't exist
+    ///
+    Spawn { program: String, source: io::Error },
+
+    ///
+    /// Failed to prepare the stdio (stdin file, redirect file) or to wait for the
+    /// child process
+    ///
+    Io { context: String, source: io::Error },
+
+    ///
+    /// The command exited with a non-zero exit code, only returned by `check()`
+    ///
+    NonZeroExit {
+        cmd_desc: String,
+        exit_code: i32,
+        stderr: String,
+    },
+
+    ///
+    /// The command was terminated by a signal (unix only), only returned by `check()`
+    ///
+    Signaled {
+        cmd_desc: String,
+        signal: i32,
+        core_dumped: bool,
+        stderr: String,
+    },
+
+    ///
+    /// The command was terminated because of `CommandSpec::timeout`
+    ///
+    TimedOut { cmd_desc: String },
+
+    ///
+    /// The command was terminated because of `CommandSpec::cancel_token`
+    ///
+    Cancelled { cmd_desc: String },
+}
+
+impl CommandError {
+    //
+    // Map the spawn error by its `io::ErrorKind`
+    //
+    pub(crate) fn from_spawn_error(program: &str, error: io::Error) -> Self {
+        let program = program.to_string();
+        match error.kind() {
+            io::ErrorKind::NotFound => Self::NotFound {
+                program,
+                searched: Vec::new(),
+            },
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied { program },
+            _ => Self::Spawn {
+                program,
+                source: error,
+            },
+        }
+    }
+
+    pub(crate) fn io(context: String, source: io::Error) -> Self {
+        Self::Io { context, source }
+    }
+
+    ///
+    /// Turn the finished command's output into `NonZeroExit` or `Signaled`, `None` if
+    /// it exited with 0
+    ///
+    pub(crate) fn from_exit(output: &CommandOutput) -> Option<Self> {
+        let stderr = output.stderr_lossy().trim_end().to_string();
+        match (output.exit_code, output.signal) {
+            (Some(0), _) => None,
+            (Some(exit_code), _) => Some(Self::NonZeroExit {
+                cmd_desc: output.cmd_desc.clone(),
+                exit_code,
+                stderr,
+            }),
+            (None, signal) => Some(Self::Signaled {
+                cmd_desc: output.cmd_desc.clone(),
+                signal: signal.unwrap_or_default(),
+                core_dumped: output.core_dumped,
+                stderr,
+            }),
+        }
+    }
+
+    ///
+    /// The captured stderr of `NonZeroExit` and `Signaled`
+    ///
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            Self::NonZeroExit { stderr, .. } | Self::Signaled { stderr, .. } => Some(stderr),
+            _ => None,
+        }
+    }
+}
+
+//
+// Append the stderr to the error message, it's usually the most useful part
+//
+fn write_stderr(f: &mut fmt::Formatter<'_>, stderr: &str) -> fmt::Result {
+    if stderr.is_empty() {
+        Ok(())
+    } else {
+        write!(f, ", stderr:\n{stderr}")
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCommand { reason } => write!(f, "Invalid command: {reason}"),
+            Self::NotFound { program, searched } => {
+                write!(f, "Command not found: {program}")?;
+                if !searched.is_empty() {
+                    let searched = searched
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>();
+                    write!(f, " (searched {})", searched.join(", "))?;
+                }
+                Ok(())
+            }
+            Self::PermissionDenied { program } => {
+                write!(f, "Permission denied to execute: {program}")
+            }
+            Self::Spawn { program, source } => write!(f, "Failed to spawn '{program}': {source}"),
+            Self::Io { context, source } => write!(f, "{context}: {source}"),
+            Self::NonZeroExit {
+                cmd_desc,
+                exit_code,
+                stderr,
+            } => {
+                write!(f, "`{cmd_desc}` exited with code {exit_code}")?;
+                write_stderr(f, stderr)
+            }
+            Self::Signaled {
+                cmd_desc,
+                signal,
+                core_dumped,
+                stderr,
+            } => {
+                write!(f, "`{cmd_desc}` was terminated by signal {signal}")?;
+                if *core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                write_stderr(f, stderr)
+            }
+            Self::TimedOut { cmd_desc } => write!(f, "`{cmd_desc}` timed out"),
+            Self::Cancelled { cmd_desc } => write!(f, "`{cmd_desc}` was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Spawn { source, .. } | Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
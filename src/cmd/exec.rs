@@ -0,0 +1,593 @@
+//!
+//! Run a `CommandSpec`: spawn the child process, read stdout and stderr concurrently
+//! and wait for it to exit (or terminate it on timeout/cancellation).
+//!
+//! stdout and stderr are read by their own threads, the chunks are sent back to the
+//! calling thread through a channel, so the calling thread sees them in the order
+//! they were read. The calling thread polls the child process status in between,
+//! that's how the timeout and the cancellation are checked.
+//!
+use super::audit;
+use super::capture::CaptureBuffer;
+use super::stream::{LineSplitter, log_output_line};
+use super::{
+    CommandError, CommandOutput, CommandSpec, ExecuteCommandResult, Redirect, ResourceUsage,
+    StdinSource,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+
+pub(crate) const READ_BUFFER_SIZE: usize = 8192;
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+//
+// How long to keep reading the output after a terminated child process exits. The
+// pipes might be kept open by its own children, don't wait for them forever.
+//
+pub(crate) const TERMINATED_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+///
+/// Which output stream of the child process the data comes from
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+pub(crate) enum OutputEvent {
+    Data(OutputStream, Vec<u8>),
+    Eof,
+}
+
+///
+/// Why the child process was terminated
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Termination {
+    TimedOut,
+    Cancelled,
+}
+
+fn spawn_reader<R: Read + Send + 'static>(
+    mut reader: R,
+    stream: OutputStream,
+    sender: Sender<OutputEvent>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => {
+                    if sender
+                        .send(OutputEvent::Data(stream, buffer[..size].to_vec()))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        let _ = sender.send(OutputEvent::Eof);
+    })
+}
+
+//
+// Send `SIGTERM` (or `SIGKILL` if `force` is `true`) to the child process, or to
+// its whole process group.
+//
+#[cfg(unix)]
+pub(crate) fn send_terminate_signal(child: &mut Child, process_group: bool, force: bool) {
+    send_terminate_signal_to(child.id(), process_group, force);
+}
+
+#[cfg(unix)]
+pub(crate) fn send_terminate_signal_to(pid: u32, process_group: bool, force: bool) {
+    let pid = pid as libc::pid_t;
+    let target = if process_group { -pid } else { pid };
+    let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+    unsafe {
+        libc::kill(target, signal);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn send_terminate_signal(child: &mut Child, _process_group: bool, _force: bool) {
+    let _ = child.kill();
+}
+
+pub(crate) fn stdin_stdio(stdin: &StdinSource) -> Result<Stdio, CommandError> {
+    match stdin {
+        StdinSource::Null => Ok(Stdio::null()),
+        StdinSource::Bytes(_) => Ok(Stdio::piped()),
+        StdinSource::File(path) => File::open(path).map(Stdio::from).map_err(|e| {
+            CommandError::io(format!("Failed to open stdin file '{}'", path.display()), e)
+        }),
+    }
+}
+
+pub(crate) fn redirect_stdio(redirect: &Redirect) -> Result<Stdio, CommandError> {
+    let (path, append) = match redirect {
+        Redirect::Null => return Ok(Stdio::null()),
+        Redirect::File(path) => (path, false),
+        Redirect::Append(path) => (path, true),
+    };
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map(Stdio::from)
+        .map_err(|e| {
+            CommandError::io(
+                format!("Failed to open redirect file '{}'", path.display()),
+                e,
+            )
+        })
+}
+
+//
+// Spawn the child process, a missing working directory also fails with `NotFound`,
+// don't report it as a missing program.
+//
+pub(crate) fn spawn_child(cmd: &mut Command, spec: &CommandSpec) -> Result<Child, CommandError> {
+    cmd.spawn().map_err(|e| map_spawn_error(spec, e))
+}
+
+pub(crate) fn map_spawn_error(spec: &CommandSpec, e: io::Error) -> CommandError {
+    match &spec.cwd {
+        Some(cwd) if !cwd.is_dir() => CommandError::Spawn {
+            program: spec.program.clone(),
+            source: io::Error::new(
+                e.kind(),
+                format!("working directory '{}' doesn't exist", cwd.display()),
+            ),
+        },
+        //
+        // Report where the program was looked for
+        //
+        _ if e.kind() == io::ErrorKind::NotFound => match spec.resolve_program() {
+            Err(error @ CommandError::NotFound { .. }) => error,
+            _ => CommandError::from_spawn_error(&spec.program, e),
+        },
+        _ => CommandError::from_spawn_error(&spec.program, e),
+    }
+}
+
+//
+// Build the command with stdio set up: stdin by the spec, stdout and stderr piped
+// unless they're redirected
+//
+pub(crate) fn prepare_command(spec: &CommandSpec) -> Result<Command, CommandError> {
+    let mut cmd = spec.to_command();
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    #[cfg(unix)]
+    if spec.kill_process_group {
+        cmd.process_group(0);
+    }
+
+    cmd.stdin(stdin_stdio(&spec.stdin)?);
+    if let Some(redirect) = &spec.stdout_redirect {
+        cmd.stdout(redirect_stdio(redirect)?);
+    }
+    if let Some(redirect) = &spec.stderr_redirect {
+        cmd.stderr(redirect_stdio(redirect)?);
+    }
+    Ok(cmd)
+}
+
+//
+// Write stdin in another thread, otherwise it might deadlock when the child process
+// fills up the stdout pipe before reading all stdin.
+//
+pub(crate) fn spawn_stdin_writer(child: &mut Child, stdin: &StdinSource) -> Option<JoinHandle<()>> {
+    let (StdinSource::Bytes(bytes), Some(mut stdin)) = (stdin, child.stdin.take()) else {
+        return None;
+    };
+    let bytes = bytes.clone();
+    Some(std::thread::spawn(move || {
+        // The child process might exit without reading all stdin
+        let _ = stdin.write_all(&bytes);
+    }))
+}
+
+//
+// The running child process with its stdin writer and output readers
+//
+pub(crate) struct RunningCommand {
+    pub(crate) child: Child,
+    events: Receiver<OutputEvent>,
+    threads: Vec<JoinHandle<()>>,
+    open_streams: usize,
+    process_group: bool,
+
+    //
+    // Only set once the child process is reaped by `try_wait`
+    //
+    pub(crate) resource_usage: Option<ResourceUsage>,
+}
+
+impl RunningCommand {
+    pub(crate) fn spawn(spec: &CommandSpec) -> Result<Self, CommandError> {
+        let mut cmd = prepare_command(spec)?;
+        let mut child = spawn_child(&mut cmd, spec)?;
+        let stdin_writer = spawn_stdin_writer(&mut child, &spec.stdin);
+        Ok(Self::from_child(
+            child,
+            stdin_writer,
+            spec.kill_process_group,
+        ))
+    }
+
+    //
+    // Start reading the piped stdout and stderr of the spawned child process
+    //
+    pub(crate) fn from_child(
+        mut child: Child,
+        stdin_writer: Option<JoinHandle<()>>,
+        process_group: bool,
+    ) -> Self {
+        let mut threads = Vec::with_capacity(3);
+        threads.extend(stdin_writer);
+
+        let (sender, events) = mpsc::channel();
+        let mut open_streams = 0;
+        if let Some(stdout) = child.stdout.take() {
+            threads.push(spawn_reader(stdout, OutputStream::Stdout, sender.clone()));
+            open_streams += 1;
+        }
+        if let Some(stderr) = child.stderr.take() {
+            threads.push(spawn_reader(stderr, OutputStream::Stderr, sender));
+            open_streams += 1;
+        }
+
+        Self {
+            child,
+            events,
+            threads,
+            open_streams,
+            process_group,
+            resource_usage: None,
+        }
+    }
+
+    //
+    // The child process writes all its output to `output`, e.g. a pseudo-terminal,
+    // it's reported as stdout
+    //
+    #[cfg(unix)]
+    pub(crate) fn with_output<R: Read + Send + 'static>(
+        child: Child,
+        output: R,
+        stdin_writer: Option<JoinHandle<()>>,
+        process_group: bool,
+    ) -> Self {
+        let (sender, events) = mpsc::channel();
+        let mut threads = Vec::with_capacity(2);
+        threads.extend(stdin_writer);
+        threads.push(spawn_reader(output, OutputStream::Stdout, sender));
+
+        Self {
+            child,
+            events,
+            threads,
+            open_streams: 1,
+            process_group,
+            resource_usage: None,
+        }
+    }
+
+    //
+    // Receive the next output event within the timeout, `None` when all output
+    // streams are closed or nothing comes in within the timeout.
+    //
+    pub(crate) fn next_event(&mut self, timeout: Duration) -> Option<OutputEvent> {
+        if self.open_streams == 0 {
+            std::thread::sleep(timeout);
+            return None;
+        }
+
+        match self.events.recv_timeout(timeout) {
+            Ok(OutputEvent::Eof) => {
+                self.open_streams -= 1;
+                Some(OutputEvent::Eof)
+            }
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.open_streams = 0;
+                None
+            }
+        }
+    }
+
+    //
+    // Reap the child process by `wait4` if it has exited, to get its resource usage
+    // as well. Don't call `self.child.try_wait()` or `self.child.wait()` after it
+    // returns the status.
+    //
+    #[cfg(unix)]
+    pub(crate) fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let pid = self.child.id() as libc::pid_t;
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            match unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut usage) } {
+                0 => return Ok(None),
+                -1 => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(error);
+                    }
+                }
+                _ => {
+                    self.resource_usage = Some(ResourceUsage::from_rusage(&usage));
+                    return Ok(Some(ExitStatus::from_raw(status)));
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    pub(crate) fn is_output_closed(&self) -> bool {
+        self.open_streams == 0
+    }
+
+    ///
+    /// Wait for the child process to exit while handing all output to `on_output`.
+    /// The child process is terminated when the timeout is reached or the spec's
+    /// cancellation token is cancelled: `SIGTERM` first, then `SIGKILL` after the
+    /// grace period.
+    ///
+    pub(crate) fn wait(
+        &mut self,
+        spec: &CommandSpec,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> io::Result<(ExitStatus, Option<Termination>)> {
+        let deadline = spec.timeout.map(|timeout| Instant::now() + timeout);
+        let mut status: Option<ExitStatus> = None;
+        let mut exited_at: Option<Instant> = None;
+        let mut termination: Option<Termination> = None;
+        let mut terminated_at: Option<Instant> = None;
+        let mut kill_at: Option<Instant> = None;
+        let mut killed = false;
+
+        //
+        // Handle at most one chunk per round, the timeout and the cancellation are
+        // checked every round, so a child process which writes non-stop can't starve
+        // them
+        //
+        loop {
+            if let Some(OutputEvent::Data(stream, data)) = self.next_event(POLL_INTERVAL) {
+                on_output(stream, &data);
+            }
+
+            if status.is_none() {
+                status = self.try_wait()?;
+                if status.is_some() {
+                    exited_at = Some(Instant::now());
+                }
+            }
+
+            //
+            // Also checked after the child process exits, its own children might keep
+            // the pipes open for much longer
+            //
+            if termination.is_none() {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    termination = Some(Termination::TimedOut);
+                } else if spec
+                    .cancel_token
+                    .as_ref()
+                    .is_some_and(|token| token.is_cancelled())
+                {
+                    termination = Some(Termination::Cancelled);
+                }
+
+                if termination.is_some() {
+                    terminated_at = Some(Instant::now());
+                }
+
+                //
+                // An exited child process is reaped already, its pid must not be
+                // signaled anymore, only its process group below
+                //
+                if termination.is_some() && status.is_none() {
+                    if spec.kill_grace_period.is_zero() {
+                        send_terminate_signal(&mut self.child, self.process_group, true);
+                        killed = true;
+                    } else {
+                        send_terminate_signal(&mut self.child, self.process_group, false);
+                        kill_at = Some(Instant::now() + spec.kill_grace_period);
+                    }
+                }
+            } else if status.is_none() && !killed && kill_at.is_some_and(|t| Instant::now() >= t) {
+                send_terminate_signal(&mut self.child, self.process_group, true);
+                killed = true;
+            }
+
+            if let Some(status) = status {
+                if self.open_streams == 0 {
+                    self.join_threads();
+                    return Ok((status, termination));
+                }
+
+                if termination.is_some() {
+                    //
+                    // Clean up the rest of the process group
+                    //
+                    if self.process_group && !killed {
+                        send_terminate_signal(&mut self.child, true, true);
+                        killed = true;
+                    }
+                    let draining_since = exited_at.max(terminated_at).unwrap_or_else(Instant::now);
+                    if draining_since.elapsed() >= TERMINATED_DRAIN_TIMEOUT {
+                        // Don't join the reader threads, they might never finish
+                        return Ok((status, termination));
+                    }
+                }
+            }
+        }
+    }
+
+    fn join_threads(&mut self) {
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+//
+// Accumulate the output events
+//
+#[derive(Default)]
+pub(crate) struct OutputCollector {
+    pub(crate) stdout: CaptureBuffer,
+    pub(crate) stderr: CaptureBuffer,
+    pub(crate) combined: Option<CaptureBuffer>,
+    pub(crate) resource_usage: Option<ResourceUsage>,
+}
+
+impl OutputCollector {
+    //
+    // The combined output follows the stdout limit, but it's never spilled
+    //
+    pub(crate) fn new(spec: &CommandSpec) -> Self {
+        let combined_limit = spec
+            .stdout_limit
+            .map(|limit| limit.spill_to_temp_file(false));
+        Self {
+            stdout: CaptureBuffer::new("stdout", spec.stdout_limit),
+            stderr: CaptureBuffer::new("stderr", spec.stderr_limit),
+            combined: spec
+                .combined_output
+                .then(|| CaptureBuffer::new("combined", combined_limit)),
+            resource_usage: None,
+        }
+    }
+
+    pub(crate) fn push(&mut self, stream: OutputStream, data: &[u8]) {
+        match stream {
+            OutputStream::Stdout => self.stdout.push(data),
+            OutputStream::Stderr => self.stderr.push(data),
+        }
+        if let Some(combined) = self.combined.as_mut() {
+            combined.push(data);
+        }
+    }
+
+    pub(crate) fn into_output(self, cmd_desc: String, status: ExitStatus) -> CommandOutput {
+        #[cfg(unix)]
+        let (signal, core_dumped) = {
+            use std::os::unix::process::ExitStatusExt;
+            (status.signal(), status.core_dumped())
+        };
+        #[cfg(not(unix))]
+        let (signal, core_dumped) = (None, false);
+
+        let (stdout, stdout_truncation) = self.stdout.finish();
+        let (stderr, stderr_truncation) = self.stderr.finish();
+        let (combined, combined_truncation) = match self.combined.map(CaptureBuffer::finish) {
+            Some((combined, truncation)) => (Some(combined), truncation),
+            None => (None, None),
+        };
+        CommandOutput {
+            cmd_desc,
+            exit_code: status.code(),
+            signal,
+            core_dumped,
+            stdout,
+            stderr,
+            combined,
+            stdout_truncation,
+            stderr_truncation,
+            combined_truncation,
+            resource_usage: self.resource_usage,
+        }
+    }
+}
+
+pub(crate) fn execute(spec: &CommandSpec) -> ExecuteCommandResult {
+    execute_with(spec, &mut |_, _| {})
+}
+
+///
+/// Execute the command and hand every output chunk to `on_chunk` as soon as it's
+/// read, the full output is still collected into the result.
+///
+pub(crate) fn execute_with(
+    spec: &CommandSpec,
+    on_chunk: &mut dyn FnMut(OutputStream, &[u8]),
+) -> ExecuteCommandResult {
+    audit::run_global(spec, || match RunningCommand::spawn(spec) {
+        Ok(running) => collect_output(spec, running, on_chunk),
+        Err(error) => ExecuteCommandResult::Fail(error),
+    })
+}
+
+//
+// Wait for the spawned command and collect its output into the result
+//
+pub(crate) fn collect_output(
+    spec: &CommandSpec,
+    mut running: RunningCommand,
+    on_chunk: &mut dyn FnMut(OutputStream, &[u8]),
+) -> ExecuteCommandResult {
+    let mut collector = OutputCollector::new(spec);
+    let mut log_splitter = spec.log_output.then(LineSplitter::default);
+    let module_name = spec.program_name();
+    let mut log_line = |stream, line: &str| log_output_line(module_name, stream, line);
+
+    let result = running.wait(spec, &mut |stream, data| {
+        collector.push(stream, data);
+        if let Some(splitter) = log_splitter.as_mut() {
+            splitter.push(stream, data, &mut log_line);
+        }
+        on_chunk(stream, data);
+    });
+    if let Some(splitter) = log_splitter.as_mut() {
+        splitter.finish(&mut log_line);
+    }
+
+    collector.resource_usage = running.resource_usage;
+    into_result(spec, collector, result)
+}
+
+pub(crate) fn into_result(
+    spec: &CommandSpec,
+    collector: OutputCollector,
+    result: io::Result<(ExitStatus, Option<Termination>)>,
+) -> ExecuteCommandResult {
+    match result {
+        Ok((status, termination)) => {
+            let output = collector.into_output(spec.cmd_desc(), status);
+            match termination {
+                None => ExecuteCommandResult::Success(output),
+                Some(Termination::TimedOut) => ExecuteCommandResult::TimedOut(output),
+                Some(Termination::Cancelled) => ExecuteCommandResult::Cancelled(output),
+            }
+        }
+        Err(e) => ExecuteCommandResult::Fail(CommandError::io(
+            format!("Failed to wait for '{}'", spec.cmd_desc()),
+            e,
+        )),
+    }
+}
@@ -0,0 +1,634 @@
+//!
+//! Expect-style scripted interaction with a child process: wait for a text or regex
+//! in its output, then send the next input.
+//!
+//! stdout and stderr (or the pseudo-terminal) are merged into one output buffer, a
+//! successful `expect` consumes the buffer up to the end of the match.
+//!
+use super::capture::CaptureBuffer;
+use super::exec::{
+    OutputCollector, OutputEvent, RunningCommand, into_result, prepare_command,
+    send_terminate_signal, spawn_child,
+};
+use super::stream::{LineSplitter, log_output_line};
+use super::{CommandError, CommandSpec, ExecuteCommandResult};
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Regex(Regex),
+    Eof,
+}
+
+///
+/// What to wait for in the output
+///
+#[derive(Debug, Clone)]
+pub struct Expect {
+    pattern: Pattern,
+    desc: String,
+}
+
+impl Expect {
+    ///
+    /// The exact text
+    ///
+    pub fn text(text: &str) -> Self {
+        Self {
+            pattern: Pattern::Regex(
+                Regex::new(&regex::escape(text)).expect("escaped text is a valid regex"),
+            ),
+            desc: format!("{text:?}"),
+        }
+    }
+
+    ///
+    /// A regex, the captures are available in the `ExpectMatch`
+    ///
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            pattern: Pattern::Regex(Regex::new(pattern)?),
+            desc: format!("/{pattern}/"),
+        })
+    }
+
+    ///
+    /// The end of the output, i.e. the child process closed stdout and stderr (or
+    /// the pseudo-terminal), usually because it exited
+    ///
+    pub fn eof() -> Self {
+        Self {
+            pattern: Pattern::Eof,
+            desc: String::from("EOF"),
+        }
+    }
+}
+
+///
+/// A successful `expect`
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectMatch {
+    ///
+    /// The output skipped before the match
+    ///
+    pub before: String,
+
+    ///
+    /// The matched text, it's empty for `Expect::eof`
+    ///
+    pub matched: String,
+    captures: Vec<Option<String>>,
+    named: HashMap<String, String>,
+}
+
+impl ExpectMatch {
+    ///
+    /// The capture group by index, 0 is the whole match
+    ///
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.captures.get(index)?.as_deref()
+    }
+
+    ///
+    /// The named capture group
+    ///
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.named.get(name).map(String::as_str)
+    }
+}
+
+///
+/// Why an `expect` or `send` failed
+///
+#[derive(Debug)]
+pub enum ExpectError {
+    ///
+    /// Nothing matched within the timeout, `pending` is the unmatched output
+    ///
+    TimedOut {
+        expected: String,
+        timeout: Duration,
+        pending: String,
+    },
+
+    ///
+    /// The output ended before anything matched
+    ///
+    Eof { expected: String, pending: String },
+
+    ///
+    /// Failed to write to the child process
+    ///
+    Io(io::Error),
+
+    ///
+    /// The pattern given to `expect_regex` isn't a valid regex
+    ///
+    InvalidRegex(regex::Error),
+}
+
+fn write_pending(f: &mut fmt::Formatter<'_>, pending: &str) -> fmt::Result {
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        write!(f, ", pending output:\n{pending}")
+    }
+}
+
+impl fmt::Display for ExpectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TimedOut {
+                expected,
+                timeout,
+                pending,
+            } => {
+                write!(f, "Timed out after {timeout:?} while expecting {expected}")?;
+                write_pending(f, pending)
+            }
+            Self::Eof { expected, pending } => {
+                write!(f, "The output ended while expecting {expected}")?;
+                write_pending(f, pending)
+            }
+            Self::Io(error) => write!(f, "Failed to send to the child process: {error}"),
+            Self::InvalidRegex(error) => write!(f, "Invalid regex: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ExpectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::InvalidRegex(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+///
+/// An interactive child process, created by `CommandSpec::spawn_session` (piped
+/// stdio) or `CommandSpec::spawn_session_pty` (pseudo-terminal, unix only).
+///
+/// Every `expect` has its own timeout (30 seconds by default), the spec's `timeout`
+/// and `cancel_token` only apply to `wait`. Dropping the session kills the child
+/// process if it's still running.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandSpec, Expect};
+/// use std::time::Duration;
+///
+/// let mut session = CommandSpec::new("sh")
+///     .args(&["-c", "printf 'user: '; read user; printf 'password: '; read pw; echo \"uid=$user-${#pw}\""])
+///     .spawn_session()
+///     .unwrap();
+/// session.set_timeout(Duration::from_secs(5));
+///
+/// session.expect("user:").unwrap();
+/// session.send_line("admin").unwrap();
+/// session.expect("password:").unwrap();
+/// session.send_line("secret").unwrap();
+///
+/// let found = session.expect_regex(r"uid=(?<user>\w+)-(\d+)").unwrap();
+/// assert_eq!(found.name("user"), Some("admin"));
+/// assert_eq!(found.get(2), Some("6"));
+///
+/// assert!(session.wait().is_success());
+/// ```
+///
+pub struct ExpectSession {
+    spec: CommandSpec,
+    running: Option<RunningCommand>,
+    input: Option<Box<dyn Write + Send>>,
+    line_ending: &'static str,
+    pty: bool,
+    timeout: Duration,
+    collector: OutputCollector,
+    log_lines: Option<LineSplitter>,
+
+    //
+    // The unmatched output, it starts at `collector.combined[consumed..]`
+    //
+    consumed: usize,
+}
+
+impl ExpectSession {
+    pub(crate) fn spawn(spec: &CommandSpec) -> Result<Self, CommandError> {
+        let mut cmd = prepare_command(spec)?;
+        cmd.stdin(Stdio::piped());
+        let mut child = spawn_child(&mut cmd, spec)?;
+        let input = child
+            .stdin
+            .take()
+            .map(|stdin| Box::new(stdin) as Box<dyn Write + Send>);
+        let running = RunningCommand::from_child(child, None, spec.kill_process_group);
+        Ok(Self::new(spec, running, input, false))
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn spawn_pty(
+        spec: &CommandSpec,
+        size: super::PtySize,
+    ) -> Result<Self, CommandError> {
+        let (child, master) = super::pty::spawn_pty(spec, size)?;
+        let input = master.try_clone().map_err(|e| {
+            CommandError::io(String::from("Failed to clone the pseudo-terminal"), e)
+        })?;
+        let running = RunningCommand::with_output(child, master, None, spec.kill_process_group);
+        Ok(Self::new(spec, running, Some(Box::new(input)), true))
+    }
+
+    fn new(
+        spec: &CommandSpec,
+        running: RunningCommand,
+        input: Option<Box<dyn Write + Send>>,
+        pty: bool,
+    ) -> Self {
+        Self {
+            spec: spec.clone(),
+            running: Some(running),
+            input,
+            //
+            // The Enter key sends `\r` on a terminal
+            //
+            line_ending: if pty { "\r" } else { "\n" },
+            pty,
+            timeout: DEFAULT_EXPECT_TIMEOUT,
+            //
+            // The transcript is always kept in full
+            //
+            collector: OutputCollector {
+                combined: Some(CaptureBuffer::new("combined", None)),
+                ..OutputCollector::new(spec)
+            },
+            log_lines: spec.log_output.then(LineSplitter::default),
+            consumed: 0,
+        }
+    }
+
+    ///
+    /// The timeout of `expect`, `expect_regex` and `expect_eof`
+    ///
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    ///
+    /// Wait for the exact text
+    ///
+    pub fn expect(&mut self, text: &str) -> Result<ExpectMatch, ExpectError> {
+        self.expect_within(&Expect::text(text), self.timeout)
+    }
+
+    ///
+    /// Wait for the regex, an invalid regex returns `ExpectError::InvalidRegex`
+    ///
+    pub fn expect_regex(&mut self, pattern: &str) -> Result<ExpectMatch, ExpectError> {
+        let expect = Expect::regex(pattern).map_err(ExpectError::InvalidRegex)?;
+        self.expect_within(&expect, self.timeout)
+    }
+
+    ///
+    /// Wait for the end of the output, `before` of the match is the rest of it
+    ///
+    pub fn expect_eof(&mut self) -> Result<ExpectMatch, ExpectError> {
+        self.expect_within(&Expect::eof(), self.timeout)
+    }
+
+    ///
+    /// Wait for `expect` with its own timeout
+    ///
+    pub fn expect_within(
+        &mut self,
+        expect: &Expect,
+        timeout: Duration,
+    ) -> Result<ExpectMatch, ExpectError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(found) = self.try_match(expect) {
+                return Ok(found);
+            }
+
+            let Some(running) = self.running.as_mut() else {
+                return Err(self.eof_error(expect));
+            };
+            if running.is_output_closed() {
+                return Err(self.eof_error(expect));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ExpectError::TimedOut {
+                    expected: expect.desc.clone(),
+                    timeout,
+                    pending: self.pending_lossy(),
+                });
+            }
+
+            if let Some(OutputEvent::Data(stream, data)) =
+                running.next_event(POLL_INTERVAL.min(deadline - now))
+            {
+                self.collector.push(stream, &data);
+                if let Some(splitter) = self.log_lines.as_mut() {
+                    let module_name = self.spec.program_name();
+                    splitter.push(stream, &data, &mut |stream, line| {
+                        log_output_line(module_name, stream, line)
+                    });
+                }
+            }
+        }
+    }
+
+    fn pending(&self) -> &[u8] {
+        &self.transcript()[self.consumed..]
+    }
+
+    fn pending_lossy(&self) -> String {
+        String::from_utf8_lossy(self.pending()).to_string()
+    }
+
+    fn eof_error(&self, expect: &Expect) -> ExpectError {
+        ExpectError::Eof {
+            expected: expect.desc.clone(),
+            pending: self.pending_lossy(),
+        }
+    }
+
+    fn try_match(&mut self, expect: &Expect) -> Option<ExpectMatch> {
+        let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+        let pending = self.pending();
+        let found = match &expect.pattern {
+            Pattern::Regex(regex) => {
+                let captures = regex.captures(pending)?;
+                let whole = captures.get(0)?;
+                let named = regex
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| {
+                        Some((name.to_string(), lossy(captures.name(name)?.as_bytes())))
+                    })
+                    .collect();
+                let found = ExpectMatch {
+                    before: lossy(&pending[..whole.start()]),
+                    matched: lossy(whole.as_bytes()),
+                    captures: captures
+                        .iter()
+                        .map(|group| group.map(|group| lossy(group.as_bytes())))
+                        .collect(),
+                    named,
+                };
+                self.consumed += whole.end();
+                found
+            }
+            Pattern::Eof => {
+                if !self
+                    .running
+                    .as_ref()
+                    .is_none_or(|running| running.is_output_closed())
+                {
+                    return None;
+                }
+                let found = ExpectMatch {
+                    before: lossy(pending),
+                    matched: String::new(),
+                    captures: Vec::new(),
+                    named: HashMap::new(),
+                };
+                self.consumed = self.transcript().len();
+                found
+            }
+        };
+        Some(found)
+    }
+
+    ///
+    /// Send the text as is
+    ///
+    pub fn send(&mut self, text: &str) -> Result<(), ExpectError> {
+        self.send_bytes(text.as_bytes())
+    }
+
+    ///
+    /// Send the line followed by `\n`, or `\r` (the Enter key) on a pseudo-terminal
+    ///
+    pub fn send_line(&mut self, line: &str) -> Result<(), ExpectError> {
+        self.send_bytes(format!("{line}{}", self.line_ending).as_bytes())
+    }
+
+    ///
+    /// Send a control character, e.g. `send_control('c')` sends `Ctrl-C`. It only
+    /// works as a signal on a pseudo-terminal.
+    ///
+    pub fn send_control(&mut self, key: char) -> Result<(), ExpectError> {
+        let key = key.to_ascii_uppercase();
+        if !('@'..='_').contains(&key) {
+            return Err(ExpectError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{key}' isn't a control key"),
+            )));
+        }
+        self.send_bytes(&[key as u8 - b'@'])
+    }
+
+    ///
+    /// Close stdin, or send `Ctrl-D` on a pseudo-terminal
+    ///
+    pub fn send_eof(&mut self) -> Result<(), ExpectError> {
+        if self.pty {
+            self.send_control('d')
+        } else {
+            self.input = None;
+            Ok(())
+        }
+    }
+
+    fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), ExpectError> {
+        let input = self.input.as_mut().ok_or_else(|| {
+            ExpectError::Io(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stdin has been closed",
+            ))
+        })?;
+        input
+            .write_all(bytes)
+            .and_then(|_| input.flush())
+            .map_err(ExpectError::Io)
+    }
+
+    ///
+    /// All output read so far, no matter whether it's matched or not
+    ///
+    pub fn transcript(&self) -> &[u8] {
+        self.collector
+            .combined
+            .as_ref()
+            .map(CaptureBuffer::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn transcript_lossy(&self) -> String {
+        String::from_utf8_lossy(self.transcript()).to_string()
+    }
+
+    ///
+    /// Close stdin (not on a pseudo-terminal), wait for the child process to exit and
+    /// return the result with all output of the session
+    ///
+    pub fn wait(mut self) -> ExecuteCommandResult {
+        if !self.pty {
+            self.input = None;
+        }
+        let Some(mut running) = self.running.take() else {
+            unreachable!("the running command is only taken by `wait`")
+        };
+
+        let mut collector = std::mem::take(&mut self.collector);
+        let mut log_lines = self.log_lines.take();
+        let module_name = self.spec.program_name();
+        let mut log_line = |stream, line: &str| log_output_line(module_name, stream, line);
+
+        let result = running.wait(&self.spec, &mut |stream, data| {
+            collector.push(stream, data);
+            if let Some(splitter) = log_lines.as_mut() {
+                splitter.push(stream, data, &mut log_line);
+            }
+        });
+        if let Some(splitter) = log_lines.as_mut() {
+            splitter.finish(&mut log_line);
+        }
+
+        if !self.spec.combined_output {
+            collector.combined = None;
+        }
+        collector.resource_usage = running.resource_usage;
+        into_result(&self.spec, collector, result)
+    }
+}
+
+impl Drop for ExpectSession {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.as_mut()
+            && matches!(running.try_wait(), Ok(None))
+        {
+            send_terminate_signal(&mut running.child, self.spec.kill_process_group, true);
+            let _ = running.child.wait();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::PtySize;
+
+    fn session(script: &str) -> ExpectSession {
+        let mut session = CommandSpec::new("sh")
+            .args(&["-c", script])
+            .spawn_session()
+            .unwrap();
+        session.set_timeout(Duration::from_secs(5));
+        session
+    }
+
+    #[test]
+    fn expect_should_consume_the_output() {
+        let mut session = session("echo 'a: 1'; sleep 0.1; echo 'b: 2' >&2; sleep 0.1; echo done");
+        let found = session.expect_regex(r"(\w): (\d)").unwrap();
+        assert_eq!((found.get(1), found.get(2)), (Some("a"), Some("1")));
+
+        let found = session.expect_regex(r"(\w): (\d)").unwrap();
+        assert_eq!((found.get(1), found.get(2)), (Some("b"), Some("2")));
+        assert_eq!(found.before, "\n");
+
+        assert!(matches!(
+            session.expect_regex(r"(unclosed"),
+            Err(ExpectError::InvalidRegex(_))
+        ));
+
+        let found = session.expect_eof().unwrap();
+        assert_eq!(found.before, "\ndone\n");
+        assert_eq!(session.transcript_lossy(), "a: 1\nb: 2\ndone\n");
+
+        let output = session.wait().check().unwrap();
+        assert_eq!(output.stdout_lossy(), "a: 1\ndone\n");
+        assert_eq!(output.stderr_lossy(), "b: 2\n");
+    }
+
+    #[test]
+    fn expect_should_time_out() {
+        let mut session = session("printf 'waiting'; sleep 10");
+        let start = Instant::now();
+        let error = session
+            .expect_within(&Expect::text("ready"), Duration::from_millis(200))
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(
+            matches!(&error, ExpectError::TimedOut { pending, .. } if pending == "waiting"),
+            "{error}"
+        );
+
+        //
+        // Dropping the session kills the child process
+        //
+        let start = Instant::now();
+        drop(session);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn expect_should_fail_on_eof() {
+        let mut session = session("echo bye");
+        let error = session.expect("password:").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The output ended while expecting \"password:\", pending output:\nbye\n"
+        );
+    }
+
+    #[test]
+    fn send_eof_should_close_stdin() {
+        let mut session = session("cat; echo closed");
+        session.send("a").unwrap();
+        session.send_line("b").unwrap();
+        session.expect("ab\n").unwrap();
+        session.send_eof().unwrap();
+        session.expect("closed").unwrap();
+        assert!(matches!(session.send("c"), Err(ExpectError::Io(_))));
+        assert!(session.wait().is_success());
+    }
+
+    #[test]
+    fn pty_session_should_work() {
+        let mut session = CommandSpec::new("sh")
+            .args(&[
+                "-c",
+                "printf 'continue? '; read answer; echo \"answer=$answer\"; sleep 10",
+            ])
+            .spawn_session_pty(PtySize::default())
+            .unwrap();
+        session.set_timeout(Duration::from_secs(5));
+
+        session.expect("continue? ").unwrap();
+        session.send_line("yes").unwrap();
+        let found = session.expect_regex(r"answer=(\w+)\r\n").unwrap();
+        assert_eq!(found.get(1), Some("yes"));
+
+        //
+        // Ctrl-C interrupts the foreground process group
+        //
+        session.send_control('c').unwrap();
+        let output = session.wait().check().unwrap_err();
+        assert!(matches!(output, CommandError::Signaled { signal, .. } if signal == libc::SIGINT));
+    }
+}
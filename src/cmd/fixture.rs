@@ -0,0 +1,399 @@
+//!
+//! Record real command invocations into a fixture file and serve them back in tests.
+//!
+//! The fixture file is JSON Lines, one invocation per line:
+//!
+//! ```json
+//! {"argv":["git","status","--short"],"env":{},"status":"exited","exit_code":0,"signal":null,"stdout":" M src/cmd.rs\n","stderr":""}
+//! ```
+//!
+//! The output which isn't valid UTF-8 is saved as `stdout_hex`/`stderr_hex` instead.
+//!
+use super::{CommandError, CommandOutput, CommandRunner, CommandSpec, ExecuteCommandResult};
+use crate::hex;
+use crate::json::{self, JsonValue};
+use crate::logger::{LogLevel, log};
+use crate::warn_log;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const LOG_MODULE_NAME: &str = "RecordingRunner";
+
+//
+// One recorded invocation
+//
+#[derive(Debug, Clone, PartialEq)]
+struct Fixture {
+    argv: Vec<String>,
+    envs: Vec<(String, Option<String>)>,
+    status: String,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    error: Option<String>,
+}
+
+fn argv_of(spec: &CommandSpec) -> Vec<String> {
+    std::iter::once(spec.program.clone())
+        .chain(spec.args.iter().cloned())
+        .collect()
+}
+
+fn bytes_entry(key: &str, bytes: &[u8]) -> (String, JsonValue) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (key.to_string(), JsonValue::String(text.to_string())),
+        Err(_) => (
+            format!("{key}_hex"),
+            JsonValue::String(hex::byte_arr_to_hex_string(bytes, None)),
+        ),
+    }
+}
+
+fn number_or_null(value: Option<i32>) -> JsonValue {
+    value.map_or(JsonValue::Null, |value| JsonValue::Number(value as f64))
+}
+
+impl Fixture {
+    fn record(spec: &CommandSpec, result: &ExecuteCommandResult) -> Self {
+        let mut fixture = Self {
+            argv: argv_of(spec),
+            envs: spec.envs.clone(),
+            status: String::new(),
+            exit_code: None,
+            signal: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            error: None,
+        };
+
+        let (status, output) = match result {
+            ExecuteCommandResult::Success(output) => ("exited", output),
+            ExecuteCommandResult::TimedOut(output) => ("timed_out", output),
+            ExecuteCommandResult::Cancelled(output) => ("cancelled", output),
+            ExecuteCommandResult::Fail(error) => {
+                fixture.status = String::from(match error {
+                    CommandError::NotFound { .. } => "not_found",
+                    CommandError::PermissionDenied { .. } => "permission_denied",
+                    _ => "error",
+                });
+                fixture.error = Some(error.to_string());
+                return fixture;
+            }
+        };
+        fixture.status = status.to_string();
+        fixture.exit_code = output.exit_code;
+        fixture.signal = output.signal;
+        fixture.stdout = output.stdout.clone();
+        fixture.stderr = output.stderr.clone();
+        fixture
+    }
+
+    fn to_json(&self) -> String {
+        let envs = self
+            .envs
+            .iter()
+            .map(|(key, value)| {
+                let value = value.clone().map_or(JsonValue::Null, JsonValue::String);
+                (key.clone(), value)
+            })
+            .collect();
+        let mut entries = vec![
+            (
+                String::from("argv"),
+                JsonValue::Array(self.argv.iter().cloned().map(JsonValue::String).collect()),
+            ),
+            (String::from("env"), JsonValue::Object(envs)),
+            (
+                String::from("status"),
+                JsonValue::String(self.status.clone()),
+            ),
+        ];
+        match &self.error {
+            Some(error) => entries.push((String::from("error"), JsonValue::String(error.clone()))),
+            None => entries.extend([
+                (String::from("exit_code"), number_or_null(self.exit_code)),
+                (String::from("signal"), number_or_null(self.signal)),
+                bytes_entry("stdout", &self.stdout),
+                bytes_entry("stderr", &self.stderr),
+            ]),
+        }
+        JsonValue::Object(entries).to_json()
+    }
+
+    fn parse(line: &str) -> Result<Self, String> {
+        let JsonValue::Object(entries) = json::parse(line)? else {
+            return Err(String::from("not a JSON object"));
+        };
+        let get = |key: &str| {
+            entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value)
+        };
+        let get_str = |key: &str| get(key).and_then(JsonValue::as_str);
+        let get_i32 = |key: &str| match get(key) {
+            Some(JsonValue::Number(n)) => Some(*n as i32),
+            _ => None,
+        };
+        let get_bytes = |key: &str| -> Result<Vec<u8>, String> {
+            match (get_str(key), get_str(&format!("{key}_hex"))) {
+                (Some(text), _) => Ok(text.as_bytes().to_vec()),
+                (None, Some(hex_string)) => hex::hex_string_to_byte_arr(hex_string),
+                (None, None) => Ok(Vec::new()),
+            }
+        };
+
+        let Some(JsonValue::Array(argv)) = get("argv") else {
+            return Err(String::from("missing 'argv'"));
+        };
+        let argv = argv
+            .iter()
+            .map(|arg| arg.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .filter(|argv| !argv.is_empty())
+            .ok_or("'argv' must be a non-empty string array")?;
+        let envs = match get("env") {
+            Some(JsonValue::Object(envs)) => envs
+                .iter()
+                .map(|(key, value)| (key.clone(), value.as_str().map(str::to_string)))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(Self {
+            argv,
+            envs,
+            status: get_str("status").ok_or("missing 'status'")?.to_string(),
+            exit_code: get_i32("exit_code"),
+            signal: get_i32("signal"),
+            stdout: get_bytes("stdout")?,
+            stderr: get_bytes("stderr")?,
+            error: get_str("error").map(str::to_string),
+        })
+    }
+
+    fn matches(&self, spec: &CommandSpec) -> bool {
+        self.argv == argv_of(spec) && self.envs == spec.envs
+    }
+
+    fn to_result(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        let output = CommandOutput {
+            cmd_desc: spec.cmd_desc(),
+            exit_code: self.exit_code,
+            signal: self.signal,
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            combined: spec
+                .combined_output
+                .then(|| [self.stdout.as_slice(), &self.stderr].concat()),
+            ..Default::default()
+        };
+        let program = spec.program.clone();
+        let error = self.error.clone().unwrap_or_default();
+        match self.status.as_str() {
+            "exited" => ExecuteCommandResult::Success(output),
+            "timed_out" => ExecuteCommandResult::TimedOut(output),
+            "cancelled" => ExecuteCommandResult::Cancelled(output),
+            "not_found" => ExecuteCommandResult::Fail(CommandError::NotFound {
+                program,
+                searched: Vec::new(),
+            }),
+            "permission_denied" => {
+                ExecuteCommandResult::Fail(CommandError::PermissionDenied { program })
+            }
+            _ => ExecuteCommandResult::Fail(CommandError::Spawn {
+                program,
+                source: io::Error::other(error),
+            }),
+        }
+    }
+}
+
+///
+/// Really run the commands and record every invocation into the fixture file, see
+/// `ReplayRunner`
+///
+#[derive(Debug)]
+pub struct RecordingRunner {
+    file: Mutex<File>,
+}
+
+impl RecordingRunner {
+    ///
+    /// Create or truncate the fixture file
+    ///
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(File::create(path)?),
+        })
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn run(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        let result = spec.execute();
+
+        //
+        // Write every invocation right away, so the fixture is complete even if the
+        // test panics afterwards
+        //
+        let line = Fixture::record(spec, &result).to_json();
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{line}").and_then(|_| file.flush()) {
+            warn_log!(
+                LOG_MODULE_NAME,
+                "run",
+                &format!("Failed to record `{}`: {e}", spec.cmd_desc())
+            );
+        }
+        result
+    }
+}
+
+///
+/// Serve the recorded results from the fixture file without running anything.
+///
+/// The invocation is matched by the program, arguments and the env vars set on the
+/// spec. The recordings of the same invocation are served in the recorded order, and
+/// the last one is served again after all of them are used. A command without any
+/// recording fails with `CommandError::InvalidCommand`.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandRunner, RecordingRunner, ReplayRunner};
+///
+/// let path = std::env::temp_dir().join("rust_utils_doc_fixture.jsonl");
+///
+/// let recorder = RecordingRunner::create(&path).unwrap();
+/// let recorded = recorder.execute_command(vec!["echo", "hello"]).check().unwrap();
+///
+/// let replayer = ReplayRunner::open(&path).unwrap();
+/// let replayed = replayer.execute_command(vec!["echo", "hello"]).check().unwrap();
+/// assert_eq!(replayed.stdout, recorded.stdout);
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+///
+#[derive(Debug)]
+pub struct ReplayRunner {
+    fixtures: Vec<Fixture>,
+    used: Mutex<Vec<bool>>,
+}
+
+impl ReplayRunner {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let fixtures = content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                Fixture::parse(line).map_err(|reason| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: {reason}", path.display(), index + 1),
+                    )
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            used: Mutex::new(vec![false; fixtures.len()]),
+            fixtures,
+        })
+    }
+}
+
+impl CommandRunner for ReplayRunner {
+    fn run(&self, spec: &CommandSpec) -> ExecuteCommandResult {
+        let mut used = self.used.lock().unwrap();
+        let matched = || {
+            self.fixtures
+                .iter()
+                .enumerate()
+                .filter(|(_, fixture)| fixture.matches(spec))
+        };
+        let found = matched()
+            .find(|(index, _)| !used[*index])
+            .or_else(|| matched().next_back());
+
+        match found {
+            Some((index, fixture)) => {
+                used[index] = true;
+                fixture.to_result(spec)
+            }
+            None => ExecuteCommandResult::Fail(CommandError::InvalidCommand {
+                reason: format!("ReplayRunner has no recording for `{}`", spec.cmd_desc()),
+            }),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_should_serve_recordings_in_order() {
+        let path = std::env::temp_dir().join(format!(
+            "rust_utils_fixture_test_{}.jsonl",
+            std::process::id()
+        ));
+        let counter =
+            std::env::temp_dir().join(format!("rust_utils_fixture_counter_{}", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let script = format!(
+            "echo x >> {}; wc -l < {} | tr -d ' '; printf '\\377' >&2",
+            counter.display(),
+            counter.display()
+        );
+        let spec = CommandSpec::new("sh").args(&["-c", &script]);
+
+        let recorder = RecordingRunner::create(&path).unwrap();
+        for _ in 0..2 {
+            assert!(recorder.run(&spec).is_success());
+        }
+        assert!(matches!(
+            recorder.execute_command(vec!["no-such-program-xyz", "--version"]),
+            ExecuteCommandResult::Fail(CommandError::NotFound { .. })
+        ));
+        let with_env = spec.clone().env("MODE", "slow");
+        assert!(recorder.run(&with_env).is_success());
+        drop(recorder);
+
+        let replayer = ReplayRunner::open(&path).unwrap();
+        let outputs = (0..3)
+            .map(|_| replayer.run(&spec).check().unwrap())
+            .collect::<Vec<_>>();
+        let stdouts = outputs
+            .iter()
+            .map(|output| output.stdout_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(stdouts, vec!["1\n", "2\n", "2\n"]);
+        assert_eq!(outputs[0].stderr, vec![0xff]);
+
+        assert!(matches!(
+            replayer.execute_command(vec!["no-such-program-xyz", "--version"]),
+            ExecuteCommandResult::Fail(CommandError::NotFound { .. })
+        ));
+        assert_eq!(
+            replayer.run(&with_env).check().unwrap().stdout_lossy(),
+            "3\n"
+        );
+        assert!(matches!(
+            replayer.execute_command(vec!["sh"]),
+            ExecuteCommandResult::Fail(CommandError::InvalidCommand { .. })
+        ));
+
+        std::fs::write(&path, "{\"argv\":[]}\n").unwrap();
+        let error = ReplayRunner::open(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&counter);
+    }
+}
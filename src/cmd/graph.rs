@@ -0,0 +1,618 @@
+//!
+//! Run named tasks in dependency order, a task starts as soon as all its dependencies
+//! succeeded. Independent tasks run concurrently.
+//!
+use super::runner::describe_failure;
+use super::{CommandSpec, ExecuteCommandResult};
+use crate::logger::{LogLevel, log};
+use crate::{info_log, warn_log};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const LOG_MODULE_NAME: &str = "TaskGraph";
+
+///
+/// The graph can't run as declared
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskGraphError {
+    DuplicateTask(String),
+    UnknownDependency {
+        task: String,
+        dependency: String,
+    },
+
+    ///
+    /// The task names on the cycle, the first one is repeated at the end, e.g.
+    /// `["a", "b", "a"]`
+    ///
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for TaskGraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateTask(name) => write!(f, "Duplicate task: {name}"),
+            Self::UnknownDependency { task, dependency } => {
+                write!(f, "Task '{task}' depends on unknown task '{dependency}'")
+            }
+            Self::Cycle(names) => write!(f, "Dependency cycle: {}", names.join(" -> ")),
+        }
+    }
+}
+
+impl std::error::Error for TaskGraphError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskStatus {
+    Succeeded,
+    Failed,
+
+    ///
+    /// Not run because the dependency failed or was skipped itself
+    ///
+    Skipped {
+        dependency: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct TaskReport {
+    pub name: String,
+    pub status: TaskStatus,
+    pub duration: Duration,
+
+    ///
+    /// `None` if the task was skipped
+    ///
+    pub result: Option<ExecuteCommandResult>,
+}
+
+///
+/// The result of all tasks in declaration order
+///
+#[derive(Debug)]
+pub struct TaskGraphReport {
+    pub tasks: Vec<TaskReport>,
+    pub elapsed: Duration,
+}
+
+impl TaskGraphReport {
+    pub fn is_success(&self) -> bool {
+        self.tasks
+            .iter()
+            .all(|task| task.status == TaskStatus::Succeeded)
+    }
+
+    pub fn task(&self, name: &str) -> Option<&TaskReport> {
+        self.tasks.iter().find(|task| task.name == name)
+    }
+
+    ///
+    /// Render a plain text table of all tasks, e.g.
+    ///
+    /// ```bash
+    /// TASK    STATUS     DURATION  DETAIL
+    /// build   succeeded  1.204s
+    /// test    failed     0.310s    exit code 1
+    /// deploy  skipped    -         dependency 'test' didn't succeed
+    /// ```
+    ///
+    pub fn summary_table(&self) -> String {
+        let rows = self
+            .tasks
+            .iter()
+            .map(|task| {
+                let (status, duration, detail) = match (&task.status, &task.result) {
+                    (TaskStatus::Succeeded, _) => {
+                        ("succeeded", format_duration(task.duration), String::new())
+                    }
+                    (TaskStatus::Failed, result) => (
+                        "failed",
+                        format_duration(task.duration),
+                        result.as_ref().map(describe_failure).unwrap_or_default(),
+                    ),
+                    (TaskStatus::Skipped { dependency }, _) => (
+                        "skipped",
+                        String::from("-"),
+                        format!("dependency '{dependency}' didn't succeed"),
+                    ),
+                };
+                [task.name.clone(), status.to_string(), duration, detail]
+            })
+            .collect::<Vec<_>>();
+
+        let header = ["TASK", "STATUS", "DURATION", "DETAIL"].map(String::from);
+        let mut widths = header.clone().map(|column| column.len());
+        for row in rows.iter() {
+            for (width, column) in widths.iter_mut().zip(row) {
+                *width = (*width).max(column.chars().count());
+            }
+        }
+
+        std::iter::once(&header)
+            .chain(rows.iter())
+            .map(|row| {
+                let line = row
+                    .iter()
+                    .zip(widths)
+                    .map(|(column, width)| format!("{column:<width$}"))
+                    .collect::<Vec<_>>()
+                    .join("  ");
+                line.trim_end().to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Task {
+    name: String,
+    spec: CommandSpec,
+    dependencies: Vec<String>,
+}
+
+///
+/// Declare named tasks with their dependencies and run them in dependency order.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandSpec, TaskGraph, TaskStatus};
+///
+/// let report = TaskGraph::new()
+///     .task("fetch", CommandSpec::new("true"), &[])
+///     .task("build", CommandSpec::new("true"), &["fetch"])
+///     .task("lint", CommandSpec::new("false"), &["fetch"])
+///     .task("release", CommandSpec::new("true"), &["build", "lint"])
+///     .run()
+///     .unwrap();
+///
+/// assert_eq!(report.task("build").unwrap().status, TaskStatus::Succeeded);
+/// assert_eq!(report.task("lint").unwrap().status, TaskStatus::Failed);
+/// assert_eq!(
+///     report.task("release").unwrap().status,
+///     TaskStatus::Skipped { dependency: String::from("lint") }
+/// );
+/// println!("{}", report.summary_table());
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskGraph {
+    tasks: Vec<Task>,
+    concurrency: usize,
+    log_progress: bool,
+}
+
+impl Default for TaskGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskGraph {
+    ///
+    /// The default concurrency is the number of available CPUs
+    ///
+    pub fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            concurrency: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            log_progress: false,
+        }
+    }
+
+    pub fn task(mut self, name: &str, spec: CommandSpec, dependencies: &[&str]) -> Self {
+        self.tasks.push(Task {
+            name: name.to_string(),
+            spec,
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+        });
+        self
+    }
+
+    ///
+    /// How many tasks run at the same time, at least 1
+    ///
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    ///
+    /// Log every finished or skipped task and the summary table at the end
+    ///
+    pub fn log_progress(mut self, enable: bool) -> Self {
+        self.log_progress = enable;
+        self
+    }
+
+    //
+    // The dependency indices of every task
+    //
+    fn resolve(&self) -> Result<Vec<Vec<usize>>, TaskGraphError> {
+        let mut indices = HashMap::with_capacity(self.tasks.len());
+        for (index, task) in self.tasks.iter().enumerate() {
+            if indices.insert(task.name.as_str(), index).is_some() {
+                return Err(TaskGraphError::DuplicateTask(task.name.clone()));
+            }
+        }
+
+        self.tasks
+            .iter()
+            .map(|task| {
+                task.dependencies
+                    .iter()
+                    .map(|dependency| {
+                        indices.get(dependency.as_str()).copied().ok_or_else(|| {
+                            TaskGraphError::UnknownDependency {
+                                task: task.name.clone(),
+                                dependency: dependency.clone(),
+                            }
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    //
+    // Depth-first search, a dependency which is still on the stack closes a cycle
+    //
+    fn find_cycle(&self, dependencies: &[Vec<usize>]) -> Option<Vec<String>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            New,
+            OnStack,
+            Done,
+        }
+
+        fn visit(
+            index: usize,
+            dependencies: &[Vec<usize>],
+            marks: &mut [Mark],
+            stack: &mut Vec<usize>,
+        ) -> Option<Vec<usize>> {
+            marks[index] = Mark::OnStack;
+            stack.push(index);
+            for &dependency in dependencies[index].iter() {
+                match marks[dependency] {
+                    Mark::OnStack => {
+                        let start = stack.iter().position(|i| *i == dependency).unwrap();
+                        let mut cycle = stack[start..].to_vec();
+                        cycle.push(dependency);
+                        return Some(cycle);
+                    }
+                    Mark::New => {
+                        if let Some(cycle) = visit(dependency, dependencies, marks, stack) {
+                            return Some(cycle);
+                        }
+                    }
+                    Mark::Done => {}
+                }
+            }
+            stack.pop();
+            marks[index] = Mark::Done;
+            None
+        }
+
+        let mut marks = vec![Mark::New; self.tasks.len()];
+        for index in 0..self.tasks.len() {
+            if marks[index] == Mark::New
+                && let Some(cycle) = visit(index, dependencies, &mut marks, &mut Vec::new())
+            {
+                //
+                // The stack goes from the dependent to the dependency, reverse it to
+                // read in the running order
+                //
+                return Some(
+                    cycle
+                        .into_iter()
+                        .rev()
+                        .map(|i| self.tasks[i].name.clone())
+                        .collect(),
+                );
+            }
+        }
+        None
+    }
+
+    ///
+    /// Check for duplicate names, unknown dependencies and cycles without running
+    /// anything
+    ///
+    pub fn validate(&self) -> Result<(), TaskGraphError> {
+        let dependencies = self.resolve()?;
+        match self.find_cycle(&dependencies) {
+            Some(cycle) => Err(TaskGraphError::Cycle(cycle)),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Validate the graph and run all tasks, the tasks depending on a failed task
+    /// (directly or not) are skipped.
+    ///
+    pub fn run(&self) -> Result<TaskGraphReport, TaskGraphError> {
+        let dependencies = self.resolve()?;
+        if let Some(cycle) = self.find_cycle(&dependencies) {
+            return Err(TaskGraphError::Cycle(cycle));
+        }
+
+        let start = Instant::now();
+        let total = self.tasks.len();
+        let mut dependents = vec![Vec::new(); total];
+        let mut pending_dependencies = vec![0; total];
+        for (index, task_dependencies) in dependencies.iter().enumerate() {
+            for &dependency in task_dependencies {
+                dependents[dependency].push(index);
+                pending_dependencies[index] += 1;
+            }
+        }
+
+        let mut reports: Vec<Option<TaskReport>> =
+            std::iter::repeat_with(|| None).take(total).collect();
+        let mut ready = (0..total)
+            .filter(|index| pending_dependencies[*index] == 0)
+            .collect::<VecDeque<_>>();
+
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            let mut running = 0;
+
+            loop {
+                while running < self.concurrency
+                    && let Some(index) = ready.pop_front()
+                {
+                    let sender = sender.clone();
+                    let spec = &self.tasks[index].spec;
+                    scope.spawn(move || {
+                        let task_start = Instant::now();
+                        let result = spec.execute();
+                        let _ = sender.send((index, result, task_start.elapsed()));
+                    });
+                    running += 1;
+                }
+
+                if running == 0 {
+                    break;
+                }
+                let Ok((index, result, duration)) = receiver.recv() else {
+                    break;
+                };
+                running -= 1;
+
+                let status = if result.is_success() {
+                    TaskStatus::Succeeded
+                } else {
+                    TaskStatus::Failed
+                };
+                let report = TaskReport {
+                    name: self.tasks[index].name.clone(),
+                    status,
+                    duration,
+                    result: Some(result),
+                };
+                if self.log_progress {
+                    log_task(&report);
+                }
+
+                if report.status == TaskStatus::Succeeded {
+                    for &dependent in dependents[index].iter() {
+                        pending_dependencies[dependent] -= 1;
+                        if pending_dependencies[dependent] == 0 && reports[dependent].is_none() {
+                            ready.push_back(dependent);
+                        }
+                    }
+                } else {
+                    self.skip_dependents(index, &dependents, &mut reports);
+                }
+                reports[index] = Some(report);
+            }
+        });
+
+        let report = TaskGraphReport {
+            tasks: reports.into_iter().flatten().collect(),
+            elapsed: start.elapsed(),
+        };
+        if self.log_progress {
+            let message = format!(
+                "Finished in {}:\n{}",
+                format_duration(report.elapsed),
+                report.summary_table()
+            );
+            if report.is_success() {
+                info_log!(LOG_MODULE_NAME, "run", &message);
+            } else {
+                warn_log!(LOG_MODULE_NAME, "run", &message);
+            }
+        }
+        Ok(report)
+    }
+
+    //
+    // Skip everything downstream of the failed task, a task is only skipped once even
+    // if many of its dependencies failed
+    //
+    fn skip_dependents(
+        &self,
+        failed: usize,
+        dependents: &[Vec<usize>],
+        reports: &mut [Option<TaskReport>],
+    ) {
+        let mut queue = VecDeque::from([failed]);
+        while let Some(index) = queue.pop_front() {
+            for &dependent in dependents[index].iter() {
+                if reports[dependent].is_some() {
+                    continue;
+                }
+                let report = TaskReport {
+                    name: self.tasks[dependent].name.clone(),
+                    status: TaskStatus::Skipped {
+                        dependency: self.tasks[index].name.clone(),
+                    },
+                    duration: Duration::ZERO,
+                    result: None,
+                };
+                if self.log_progress {
+                    log_task(&report);
+                }
+                reports[dependent] = Some(report);
+                queue.push_back(dependent);
+            }
+        }
+    }
+}
+
+fn log_task(report: &TaskReport) {
+    match &report.status {
+        TaskStatus::Succeeded => info_log!(
+            LOG_MODULE_NAME,
+            "run",
+            &format!(
+                "'{}' succeeded in {}",
+                report.name,
+                format_duration(report.duration)
+            )
+        ),
+        TaskStatus::Failed => warn_log!(
+            LOG_MODULE_NAME,
+            "run",
+            &format!(
+                "'{}' failed in {} ({})",
+                report.name,
+                format_duration(report.duration),
+                report
+                    .result
+                    .as_ref()
+                    .map(describe_failure)
+                    .unwrap_or_default()
+            )
+        ),
+        TaskStatus::Skipped { dependency } => warn_log!(
+            LOG_MODULE_NAME,
+            "run",
+            &format!(
+                "'{}' skipped, dependency '{dependency}' didn't succeed",
+                report.name
+            )
+        ),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> CommandSpec {
+        CommandSpec::new("sh").args(&["-c", script])
+    }
+
+    #[test]
+    fn validate_should_detect_errors() {
+        let graph = TaskGraph::new()
+            .task("a", sh("true"), &["c"])
+            .task("b", sh("true"), &["a"])
+            .task("c", sh("true"), &["b"])
+            .task("d", sh("true"), &[]);
+        assert_eq!(
+            graph.validate(),
+            Err(TaskGraphError::Cycle(
+                ["a", "b", "c", "a"].map(String::from).to_vec()
+            ))
+        );
+        assert!(graph.run().is_err());
+
+        let graph = TaskGraph::new().task("a", sh("true"), &["a"]);
+        assert_eq!(
+            graph.validate().unwrap_err().to_string(),
+            "Dependency cycle: a -> a"
+        );
+
+        let graph = TaskGraph::new().task("a", sh("true"), &["x"]);
+        assert!(matches!(
+            graph.validate(),
+            Err(TaskGraphError::UnknownDependency { .. })
+        ));
+
+        let graph = TaskGraph::new()
+            .task("a", sh("true"), &[])
+            .task("a", sh("true"), &[]);
+        assert_eq!(
+            graph.validate(),
+            Err(TaskGraphError::DuplicateTask(String::from("a")))
+        );
+    }
+
+    #[test]
+    fn run_should_follow_dependencies() {
+        let dir = std::env::temp_dir().join(format!("rust_utils_graph_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("order.txt");
+        let append = |name: &str, sleep: &str| {
+            sh(&format!("sleep {sleep}; echo {name} >> {}", log.display()))
+        };
+
+        //
+        // `slow` and `fast` run concurrently, `join` waits for both of them
+        //
+        let start = Instant::now();
+        let report = TaskGraph::new()
+            .task("join", append("join", "0"), &["slow", "fast"])
+            .task("slow", append("slow", "0.3"), &["root"])
+            .task("fast", append("fast", "0.1"), &["root"])
+            .task("root", append("root", "0"), &[])
+            .concurrency(4)
+            .log_progress(true)
+            .run()
+            .unwrap();
+
+        assert!(report.is_success());
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "root\nfast\nslow\njoin\n"
+        );
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failure_should_skip_downstream() {
+        let report = TaskGraph::new()
+            .task("a", sh("exit 2"), &[])
+            .task("b", sh("true"), &["a"])
+            .task("c", sh("true"), &["b"])
+            .task("d", sh("true"), &[])
+            .concurrency(1)
+            .log_progress(true)
+            .run()
+            .unwrap();
+
+        assert!(!report.is_success());
+        assert_eq!(report.task("a").unwrap().status, TaskStatus::Failed);
+        assert_eq!(
+            report.task("c").unwrap().status,
+            TaskStatus::Skipped {
+                dependency: String::from("b")
+            }
+        );
+        assert_eq!(report.task("d").unwrap().status, TaskStatus::Succeeded);
+
+        let table = report.summary_table();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "TASK  STATUS     DURATION  DETAIL");
+        assert!(lines[1].starts_with("a     failed     0."));
+        assert!(lines[1].ends_with("exit code 2"));
+        assert_eq!(
+            lines[3],
+            "c     skipped    -         dependency 'b' didn't succeed"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//!
+//! The `cmd!` macro
+//!
+
+///
+/// Build a `CommandSpec` from literals and Rust expressions:
+///
+/// - A literal is split by whitespace, quotes aren't interpreted: `"ls -lht"` is two
+///   arguments.
+/// - `{expr}` is always one argument, no matter what it contains. Anything
+///   `Display` works, e.g. `{count}` or `{path.display()}`.
+/// - `{..expr}` spreads every item of an iterable as its own argument.
+///
+/// The first argument is the program, it panics if there's none.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd;
+/// use std::time::Duration;
+///
+/// let message = "fix: typo in README";
+/// let files = vec!["README.md", "src/lib.rs"];
+///
+/// let spec = cmd!("git commit", "-m", {message}, "--", {..&files});
+/// assert_eq!(
+///     spec.cmd_desc(),
+///     "git commit -m 'fix: typo in README' -- README.md src/lib.rs"
+/// );
+///
+/// let output = cmd!("sh -c", {"echo $GREETING from $(pwd)"})
+///     .env("GREETING", "hello")
+///     .cwd("/tmp")
+///     .timeout(Duration::from_secs(5))
+///     .execute()
+///     .check()
+///     .unwrap();
+/// assert_eq!(output.stdout_lossy(), "hello from /tmp\n");
+/// ```
+///
+#[macro_export]
+macro_rules! cmd {
+    (@arg $args:ident, { .. $values:expr }) => {
+        $args.extend(
+            ::std::iter::IntoIterator::into_iter($values)
+                .map(|value| ::std::string::ToString::to_string(&value)),
+        );
+    };
+    (@arg $args:ident, { $value:expr }) => {
+        $args.push(::std::string::ToString::to_string(&$value));
+    };
+    (@arg $args:ident, $literal:literal) => {
+        $args.extend(
+            ::std::string::ToString::to_string(&$literal)
+                .split_whitespace()
+                .map(::std::string::ToString::to_string),
+        );
+    };
+    ($($arg:tt),+ $(,)?) => {{
+        let mut args: ::std::vec::Vec<::std::string::String> = ::std::vec::Vec::new();
+        $( $crate::cmd!(@arg args, $arg); )+
+        let (program, args) = args.split_first().expect("cmd!: the program is missing");
+        $crate::cmd::CommandSpec::new(program).args(args)
+    }};
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::PathBuf;
+
+    #[test]
+    fn cmd_macro_should_work() {
+        let spec = crate::cmd!("ls -lht", "./");
+        assert_eq!(spec.get_program(), "ls");
+        assert_eq!(spec.get_args(), ["-lht", "./"]);
+
+        let path = PathBuf::from("/tmp/my file.txt");
+        let lines = 5;
+        let extra = ["-v", ""];
+        let spec = crate::cmd!("head", "-n", { lines }, { path.display() }, { ..extra }, 42,);
+        assert_eq!(
+            spec.get_args(),
+            ["-n", "5", "/tmp/my file.txt", "-v", "", "42"]
+        );
+
+        let empty: Vec<String> = Vec::new();
+        assert_eq!(crate::cmd!("true", { ..empty }).get_args(), [] as [&str; 0]);
+
+        let output = crate::cmd!("printf", { "%s|%s" }, { "a b" }, "c")
+            .execute()
+            .check()
+            .unwrap();
+        assert_eq!(output.stdout_lossy(), "a b|c");
+    }
+
+    #[test]
+    #[should_panic(expected = "the program is missing")]
+    fn cmd_macro_should_panic_without_program() {
+        let _ = crate::cmd!("  ");
+    }
+}
@@ -0,0 +1,105 @@
+use super::{ResourceUsage, Truncation};
+use std::borrow::Cow;
+use std::str::Utf8Error;
+
+///
+/// The captured output of a finished command
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CommandOutput {
+    pub cmd_desc: String,
+
+    ///
+    /// `None` if the process was terminated by a signal
+    ///
+    pub exit_code: Option<i32>,
+
+    ///
+    /// The signal which terminated the process (unix only)
+    ///
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+
+    ///
+    /// stdout and stderr interleaved in the order they were read, only captured when
+    /// `CommandSpec::combined_output(true)` is set. The order is kept per read chunk,
+    /// the child process writes into two different pipes after all.
+    ///
+    pub combined: Option<Vec<u8>>,
+
+    ///
+    /// Set if stdout was cut by `CommandSpec::stdout_limit`
+    ///
+    pub stdout_truncation: Option<Truncation>,
+
+    ///
+    /// Set if stderr was cut by `CommandSpec::stderr_limit`
+    ///
+    pub stderr_truncation: Option<Truncation>,
+
+    ///
+    /// Set if the combined output was cut, it follows the stdout limit
+    ///
+    pub combined_truncation: Option<Truncation>,
+
+    ///
+    /// The resource usage of the child process (unix only), it's not available for
+    /// `execute_async`
+    ///
+    pub resource_usage: Option<ResourceUsage>,
+}
+
+impl CommandOutput {
+    ///
+    /// Whether any captured stream was cut by its limit
+    ///
+    pub fn is_truncated(&self) -> bool {
+        self.stdout_truncation.is_some()
+            || self.stderr_truncation.is_some()
+            || self.combined_truncation.is_some()
+    }
+
+    ///
+    /// stdout as text, invalid UTF-8 sequences are replaced with `U+FFFD`
+    ///
+    pub fn stdout_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    ///
+    /// stdout as text, fail if it's not valid UTF-8
+    ///
+    pub fn stdout_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.stdout)
+    }
+
+    ///
+    /// stderr as text, invalid UTF-8 sequences are replaced with `U+FFFD`
+    ///
+    pub fn stderr_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+
+    ///
+    /// stderr as text, fail if it's not valid UTF-8
+    ///
+    pub fn stderr_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.stderr)
+    }
+
+    ///
+    /// The combined output as text, invalid UTF-8 sequences are replaced with `U+FFFD`
+    ///
+    pub fn combined_lossy(&self) -> Option<Cow<'_, str>> {
+        self.combined.as_ref().map(|c| String::from_utf8_lossy(c))
+    }
+
+    ///
+    /// The combined output as text, fail if it's not valid UTF-8
+    ///
+    pub fn combined_str(&self) -> Option<Result<&str, Utf8Error>> {
+        self.combined.as_ref().map(|c| std::str::from_utf8(c))
+    }
+}
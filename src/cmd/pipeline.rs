@@ -0,0 +1,749 @@
+//!
+//! Run commands as a pipeline (`a | b | c`) with real OS pipes between the processes,
+//! no shell is involved.
+//!
+use super::audit;
+use super::capture::CaptureBuffer;
+use super::exec::{
+    POLL_INTERVAL, READ_BUFFER_SIZE, TERMINATED_DRAIN_TIMEOUT, Termination, redirect_stdio,
+    send_terminate_signal, spawn_child, spawn_stdin_writer, stdin_stdio,
+};
+use super::{CancellationToken, CaptureLimit, CommandError, CommandSpec, StdinSource, Truncation};
+use std::io::{self, Read};
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+
+///
+/// The exit status of one stage in the pipeline
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StageStatus {
+    pub cmd_desc: String,
+
+    ///
+    /// `None` if the process was terminated by a signal
+    ///
+    pub exit_code: Option<i32>,
+
+    ///
+    /// The stage's own stderr, empty if it's redirected
+    ///
+    pub stderr: Vec<u8>,
+
+    ///
+    /// Set if stderr was cut by the stage's `CommandSpec::stderr_limit`
+    ///
+    pub stderr_truncation: Option<Truncation>,
+}
+
+impl StageStatus {
+    pub fn is_success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PipelineOutput {
+    ///
+    /// e.g. `cat app.log | grep ERROR | wc -l`
+    ///
+    pub cmd_desc: String,
+
+    ///
+    /// The exit code of the whole pipeline: the last stage's exit code, or with
+    /// `pipefail` enabled, the exit code of the last stage that didn't succeed.
+    /// `None` if that stage was terminated by a signal.
+    ///
+    pub exit_code: Option<i32>,
+
+    ///
+    /// The last stage's stdout, empty if it's redirected
+    ///
+    pub stdout: Vec<u8>,
+
+    ///
+    /// Set if stdout was cut by the last stage's `CommandSpec::stdout_limit`
+    ///
+    pub stdout_truncation: Option<Truncation>,
+    pub stages: Vec<StageStatus>,
+}
+
+impl PipelineOutput {
+    pub fn stdout_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+}
+
+#[derive(Debug)]
+pub enum PipelineResult {
+    ///
+    /// All stages ran and exited, no matter what the exit codes are
+    ///
+    Success(PipelineOutput),
+
+    ///
+    /// The stages were terminated because of a timeout (the pipeline's or a stage's),
+    /// with the output collected until then
+    ///
+    TimedOut(PipelineOutput),
+
+    ///
+    /// The stages were terminated because a cancellation token (the pipeline's or a
+    /// stage's) was cancelled, with the output collected until then
+    ///
+    Cancelled(PipelineOutput),
+    Fail(CommandError),
+}
+
+///
+/// Pipeline builder, every stage is a `CommandSpec`. The first stage's stdin, the
+/// last stage's stdout and every stage's stderr can be redirected by the stage's
+/// own spec, a stage's stdout redirect breaks the pipe (the next stage reads EOF).
+///
+/// A stage's `timeout` and `cancel_token` terminate the whole pipeline, same as the
+/// pipeline's own `timeout` and `cancel_token`. Every running stage is terminated
+/// by its own `kill_grace_period` and `kill_process_group` then.
+///
+/// Only the first stage can have `stdin_bytes` or `stdin_file`, the others read the
+/// previous stage's stdout, the pipeline fails with `CommandError::InvalidCommand`
+/// otherwise. The last stage's `stdout_limit` and every stage's `stderr_limit` bound
+/// what's kept in memory. A stage's `log_output` and `combined_output` aren't
+/// supported.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandSpec, Pipeline, PipelineResult};
+///
+/// let result = Pipeline::new(CommandSpec::new("printf").arg("b\na\nb\n"))
+///     .pipe(CommandSpec::new("sort"))
+///     .pipe(CommandSpec::new("uniq").arg("-c"))
+///     .pipefail(true)
+///     .execute();
+///
+/// if let PipelineResult::Success(output) = result {
+///     assert_eq!(output.exit_code, Some(0));
+///     assert_eq!(output.stages.len(), 3);
+/// }
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    stages: Vec<CommandSpec>,
+    pipefail: bool,
+    timeout: Option<Duration>,
+    cancel_token: Option<CancellationToken>,
+}
+
+impl Pipeline {
+    pub fn new(first: CommandSpec) -> Self {
+        Self {
+            stages: vec![first],
+            pipefail: false,
+            timeout: None,
+            cancel_token: None,
+        }
+    }
+
+    ///
+    /// Append a stage which reads the previous stage's stdout
+    ///
+    pub fn pipe(mut self, next: CommandSpec) -> Self {
+        self.stages.push(next);
+        self
+    }
+
+    ///
+    /// Same as `set -o pipefail`: the pipeline fails if any stage fails, not only the
+    /// last one
+    ///
+    pub fn pipefail(mut self, enable: bool) -> Self {
+        self.pipefail = enable;
+        self
+    }
+
+    ///
+    /// Terminate all stages if the pipeline is still running after the given duration,
+    /// the result is `PipelineResult::TimedOut` with the output collected so far.
+    ///
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    ///
+    /// Terminate all stages when the token is cancelled, the result is
+    /// `PipelineResult::Cancelled` with the output collected so far.
+    ///
+    pub fn cancel_token(mut self, token: &CancellationToken) -> Self {
+        self.cancel_token = Some(token.clone());
+        self
+    }
+
+    pub fn cmd_desc(&self) -> String {
+        self.stages
+            .iter()
+            .map(|stage| stage.cmd_desc())
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    ///
+    /// Spawn all stages, wait for all of them to exit and collect the output.
+    ///
+    pub fn execute(&self) -> PipelineResult {
+        let (started_at, start) = (SystemTime::now(), Instant::now());
+        let dry_run = audit::is_dry_run();
+        let result = match self.validate() {
+            Err(error) => PipelineResult::Fail(error),
+            Ok(_) if dry_run => self.dry_run(),
+            Ok(_) => self.run(),
+        };
+
+        let (status, exit_code, error) = match &result {
+            PipelineResult::Success(output) => ("exited", output.exit_code, None),
+            PipelineResult::TimedOut(output) => ("timed_out", output.exit_code, None),
+            PipelineResult::Cancelled(output) => ("cancelled", output.exit_code, None),
+            PipelineResult::Fail(error) => ("error", None, Some(error.to_string())),
+        };
+        audit::record_global_pipeline(
+            &self.cmd_desc(),
+            status,
+            exit_code,
+            error,
+            started_at,
+            start.elapsed(),
+            dry_run,
+        );
+        result
+    }
+
+    //
+    // The stages after the first one read the previous stage's stdout
+    //
+    fn validate(&self) -> Result<(), CommandError> {
+        match self
+            .stages
+            .iter()
+            .skip(1)
+            .find(|stage| stage.stdin != StdinSource::Null)
+        {
+            Some(stage) => Err(CommandError::InvalidCommand {
+                reason: format!(
+                    "only the first stage can have stdin, '{}' reads the previous stage's stdout",
+                    stage.cmd_desc()
+                ),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn dry_run(&self) -> PipelineResult {
+        audit::log_dry_run(&self.cmd_desc(), None);
+        PipelineResult::Success(PipelineOutput {
+            cmd_desc: self.cmd_desc(),
+            exit_code: Some(0),
+            stages: self
+                .stages
+                .iter()
+                .map(|stage| StageStatus {
+                    cmd_desc: stage.cmd_desc(),
+                    exit_code: Some(0),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn run(&self) -> PipelineResult {
+        let mut children: Vec<Child> = Vec::with_capacity(self.stages.len());
+        match self.spawn_all(&mut children) {
+            Ok(result) => result,
+            Err(error) => {
+                //
+                // Don't leave the already spawned stages behind
+                //
+                for child in children.iter_mut() {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                PipelineResult::Fail(error)
+            }
+        }
+    }
+
+    fn spawn_all(&self, children: &mut Vec<Child>) -> Result<PipelineResult, CommandError> {
+        let mut threads = Vec::new();
+        let mut stderr_readers = Vec::with_capacity(self.stages.len());
+        let mut previous_stdout = None;
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            let is_last = index == self.stages.len() - 1;
+            let mut cmd = stage.to_command();
+
+            #[cfg(unix)]
+            if stage.kill_process_group {
+                cmd.process_group(0);
+            }
+
+            if index == 0 {
+                cmd.stdin(stdin_stdio(&stage.stdin)?);
+            } else {
+                cmd.stdin(previous_stdout.take().unwrap_or_else(Stdio::null));
+            }
+            match &stage.stdout_redirect {
+                Some(redirect) => cmd.stdout(redirect_stdio(redirect)?),
+                None => cmd.stdout(Stdio::piped()),
+            };
+            match &stage.stderr_redirect {
+                Some(redirect) => cmd.stderr(redirect_stdio(redirect)?),
+                None => cmd.stderr(Stdio::piped()),
+            };
+
+            let mut child = spawn_child(&mut cmd, stage)?;
+            if index == 0 {
+                threads.extend(spawn_stdin_writer(&mut child, &stage.stdin));
+            }
+            if !is_last {
+                previous_stdout = child.stdout.take().map(Stdio::from);
+            }
+            stderr_readers.push(
+                child
+                    .stderr
+                    .take()
+                    .map(|stderr| StreamReader::spawn(stderr, "stderr", stage.stderr_limit)),
+            );
+            children.push(child);
+
+            //
+            // `cmd` is dropped here, so the parent doesn't keep a copy of the pipe and
+            // the next stage gets EOF when this stage exits
+            //
+        }
+
+        let stdout_limit = self.stages.last().and_then(|stage| stage.stdout_limit);
+        let stdout = children
+            .last_mut()
+            .and_then(|child| child.stdout.take())
+            .map(|stdout| StreamReader::spawn(stdout, "stdout", stdout_limit));
+
+        let readers_finished = || {
+            stdout
+                .iter()
+                .chain(stderr_readers.iter().flatten())
+                .all(StreamReader::is_finished)
+        };
+        let (statuses, termination) = self.wait_all(children, &readers_finished).map_err(|e| {
+            CommandError::io(format!("Failed to wait for '{}'", self.cmd_desc()), e)
+        })?;
+
+        //
+        // After a termination, the threads which haven't finished yet are left behind,
+        // the pipes might be kept open by the stages' own children
+        //
+        let (stdout, stdout_truncation) = stdout.map(StreamReader::finish).unwrap_or_default();
+        let stages = statuses
+            .into_iter()
+            .zip(&self.stages)
+            .zip(stderr_readers)
+            .map(|((status, stage), stderr)| {
+                let (stderr, stderr_truncation) =
+                    stderr.map(StreamReader::finish).unwrap_or_default();
+                StageStatus {
+                    cmd_desc: stage.cmd_desc(),
+                    exit_code: status.code(),
+                    stderr,
+                    stderr_truncation,
+                }
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            if thread.is_finished() {
+                let _ = thread.join();
+            }
+        }
+
+        let deciding_stage = if self.pipefail {
+            stages.iter().rev().find(|stage| !stage.is_success())
+        } else {
+            None
+        };
+        let exit_code = deciding_stage
+            .or(stages.last())
+            .and_then(|stage| stage.exit_code);
+
+        let output = PipelineOutput {
+            cmd_desc: self.cmd_desc(),
+            exit_code,
+            stdout,
+            stdout_truncation,
+            stages,
+        };
+        Ok(match termination {
+            None => PipelineResult::Success(output),
+            Some(Termination::TimedOut) => PipelineResult::TimedOut(output),
+            Some(Termination::Cancelled) => PipelineResult::Cancelled(output),
+        })
+    }
+
+    //
+    // Wait for all stages to exit and all readers to finish. The timeouts and the
+    // cancellation tokens are checked every round, also after the stages exit (their
+    // own children might keep the pipes open).
+    //
+    fn wait_all(
+        &self,
+        children: &mut [Child],
+        readers_finished: &dyn Fn() -> bool,
+    ) -> io::Result<(Vec<ExitStatus>, Option<Termination>)> {
+        let deadline = self
+            .timeout
+            .iter()
+            .chain(
+                self.stages
+                    .iter()
+                    .filter_map(|stage| stage.timeout.as_ref()),
+            )
+            .min()
+            .map(|timeout| Instant::now() + *timeout);
+        let is_cancelled = || {
+            self.cancel_token
+                .iter()
+                .chain(
+                    self.stages
+                        .iter()
+                        .filter_map(|stage| stage.cancel_token.as_ref()),
+                )
+                .any(CancellationToken::is_cancelled)
+        };
+
+        let mut statuses: Vec<Option<ExitStatus>> = vec![None; children.len()];
+        let mut kill_at: Vec<Option<Instant>> = vec![None; children.len()];
+        let mut exited_at: Option<Instant> = None;
+        let mut termination: Option<Termination> = None;
+        let mut terminated_at: Option<Instant> = None;
+
+        loop {
+            for (child, status) in children.iter_mut().zip(statuses.iter_mut()) {
+                if status.is_none() {
+                    *status = child.try_wait()?;
+                }
+            }
+            let all_exited = statuses.iter().all(Option::is_some);
+            if all_exited && exited_at.is_none() {
+                exited_at = Some(Instant::now());
+            }
+
+            if termination.is_none() {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    termination = Some(Termination::TimedOut);
+                } else if is_cancelled() {
+                    termination = Some(Termination::Cancelled);
+                }
+
+                if termination.is_some() {
+                    terminated_at = Some(Instant::now());
+                    let running = children.iter_mut().zip(&self.stages).zip(&mut kill_at);
+                    for (((child, stage), kill_at), status) in running.zip(&statuses) {
+                        //
+                        // An exited stage is reaped already, don't signal its pid
+                        //
+                        if status.is_some() {
+                            continue;
+                        }
+                        let force = stage.kill_grace_period.is_zero();
+                        send_terminate_signal(child, stage.kill_process_group, force);
+                        if !force {
+                            *kill_at = Some(Instant::now() + stage.kill_grace_period);
+                        }
+                    }
+                }
+            } else {
+                let running = children.iter_mut().zip(&self.stages).zip(&mut kill_at);
+                for (((child, stage), kill_at), status) in running.zip(&statuses) {
+                    if status.is_none() && kill_at.is_some_and(|t| Instant::now() >= t) {
+                        send_terminate_signal(child, stage.kill_process_group, true);
+                        *kill_at = None;
+                    }
+                }
+            }
+
+            if all_exited {
+                if readers_finished() {
+                    break;
+                }
+                let draining_since = exited_at.max(terminated_at).unwrap_or_else(Instant::now);
+                if termination.is_some() && draining_since.elapsed() >= TERMINATED_DRAIN_TIMEOUT {
+                    break;
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        //
+        // Clean up the rest of the process groups
+        //
+        if termination.is_some() {
+            for (child, stage) in children.iter_mut().zip(&self.stages) {
+                if stage.kill_process_group {
+                    send_terminate_signal(child, true, true);
+                }
+            }
+        }
+
+        Ok((statuses.into_iter().flatten().collect(), termination))
+    }
+}
+
+//
+// Read the whole stream in another thread, so a full pipe never blocks the others.
+// The buffer is shared, what's read so far is still there if the thread is left
+// behind.
+//
+struct StreamReader {
+    buffer: Arc<Mutex<CaptureBuffer>>,
+    thread: JoinHandle<()>,
+}
+
+impl StreamReader {
+    fn spawn<R: Read + Send + 'static>(
+        mut reader: R,
+        name: &'static str,
+        limit: Option<CaptureLimit>,
+    ) -> Self {
+        let buffer = Arc::new(Mutex::new(CaptureBuffer::new(name, limit)));
+        let thread = {
+            let buffer = Arc::clone(&buffer);
+            std::thread::spawn(move || {
+                let mut chunk = [0u8; READ_BUFFER_SIZE];
+                loop {
+                    match reader.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(size) => buffer
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .push(&chunk[..size]),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => break,
+                    }
+                }
+            })
+        };
+        Self { buffer, thread }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    fn finish(self) -> (Vec<u8>, Option<Truncation>) {
+        if self.thread.is_finished() {
+            let _ = self.thread.join();
+        }
+        let buffer =
+            std::mem::take(&mut *self.buffer.lock().unwrap_or_else(PoisonError::into_inner));
+        buffer.finish()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::Redirect;
+
+    fn output_of(pipeline: &Pipeline) -> PipelineOutput {
+        match pipeline.execute() {
+            PipelineResult::Success(output) => output,
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[test]
+    fn pipeline_should_work() {
+        let pipeline =
+            Pipeline::new(CommandSpec::new("sh").args(&["-c", "echo b; echo a; echo b"]))
+                .pipe(CommandSpec::new("sort"))
+                .pipe(CommandSpec::new("uniq").arg("-c"));
+        let output = output_of(&pipeline);
+
+        assert_eq!(
+            pipeline.cmd_desc(),
+            "sh -c 'echo b; echo a; echo b' | sort | uniq -c"
+        );
+        let lines = output
+            .stdout_lossy()
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["1 a", "2 b"]);
+        assert_eq!(output.exit_code, Some(0));
+        assert!(output.stages.iter().all(|stage| stage.is_success()));
+    }
+
+    #[test]
+    fn pipefail_should_work() {
+        let pipeline = Pipeline::new(CommandSpec::new("sh").args(&["-c", "echo oops >&2; exit 3"]))
+            .pipe(CommandSpec::new("cat"));
+
+        let output = output_of(&pipeline);
+        assert_eq!(output.exit_code, Some(0));
+        assert_eq!(output.stages[0].exit_code, Some(3));
+        assert_eq!(output.stages[0].stderr, b"oops\n");
+
+        let output = output_of(&pipeline.pipefail(true));
+        assert_eq!(output.exit_code, Some(3));
+    }
+
+    #[test]
+    fn early_exit_stage_should_not_block() {
+        //
+        // `yes` never ends by itself, it's killed by `SIGPIPE` after `head` exits
+        //
+        let output = output_of(
+            &Pipeline::new(CommandSpec::new("yes"))
+                .pipe(CommandSpec::new("head").args(&["-n", "2"]))
+                .pipefail(true),
+        );
+        assert_eq!(output.stdout_lossy(), "y\ny\n");
+        assert_eq!(output.stages[0].exit_code, None);
+        assert_eq!(output.exit_code, None);
+    }
+
+    #[test]
+    fn redirect_should_work() {
+        let dir = std::env::temp_dir().join(format!("rust_utils_pipeline_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("out.txt");
+
+        let write = |text: &str, redirect: Redirect| {
+            output_of(
+                &Pipeline::new(CommandSpec::new("echo").arg(text)).pipe(
+                    CommandSpec::new("tr")
+                        .args(&["a-z", "A-Z"])
+                        .stdout_to(redirect),
+                ),
+            )
+        };
+        let output = write("first", Redirect::File(file.clone()));
+        assert!(output.stdout.is_empty());
+        write("second", Redirect::Append(file.clone()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "FIRST\nSECOND\n");
+        write("third", Redirect::File(file.clone()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "THIRD\n");
+
+        let output = output_of(&Pipeline::new(
+            CommandSpec::new("sort")
+                .stdin_file(&file)
+                .stderr_to(Redirect::Null),
+        ));
+        assert_eq!(output.stdout_lossy(), "THIRD\n");
+        assert_eq!(
+            output.cmd_desc,
+            format!("sort < {} 2> /dev/null", file.display())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn timeout_should_terminate_all_stages() {
+        let start = Instant::now();
+        let result = Pipeline::new(CommandSpec::new("sh").args(&["-c", "echo hi; sleep 10"]))
+            .pipe(CommandSpec::new("cat"))
+            .timeout(Duration::from_millis(300))
+            .execute();
+        let PipelineResult::TimedOut(output) = result else {
+            panic!("should time out: {result:?}");
+        };
+        assert_eq!(output.stdout_lossy(), "hi\n");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        //
+        // A stage's own timeout stops the whole pipeline, its children are killed with
+        // the process group even though they keep the pipe open
+        //
+        let start = Instant::now();
+        let result = Pipeline::new(
+            CommandSpec::new("sh")
+                .args(&["-c", "sleep 10 & echo hi"])
+                .timeout(Duration::from_millis(300))
+                .kill_process_group(true),
+        )
+        .pipe(CommandSpec::new("cat"))
+        .execute();
+        let PipelineResult::TimedOut(output) = result else {
+            panic!("should time out: {result:?}");
+        };
+        assert_eq!(output.stdout_lossy(), "hi\n");
+        assert_eq!(output.stages[0].exit_code, Some(0));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn cancel_token_should_terminate_all_stages() {
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(200));
+                token.cancel();
+            })
+        };
+
+        let start = Instant::now();
+        let result = Pipeline::new(CommandSpec::new("yes"))
+            .pipe(CommandSpec::new("sleep").arg("10"))
+            .cancel_token(&token)
+            .execute();
+        canceller.join().unwrap();
+        assert!(matches!(result, PipelineResult::Cancelled(_)), "{result:?}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stdin_of_later_stages_should_be_rejected() {
+        let pipeline = Pipeline::new(CommandSpec::new("echo").arg("hi"))
+            .pipe(CommandSpec::new("cat").stdin_bytes(b"ignored".to_vec()));
+        let PipelineResult::Fail(error) = pipeline.execute() else {
+            panic!("should fail");
+        };
+        assert!(
+            matches!(error, CommandError::InvalidCommand { .. }),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn capture_limits_should_work() {
+        let output = output_of(
+            &Pipeline::new(
+                CommandSpec::new("sh")
+                    .args(&["-c", "seq 1 100000; seq 1 1000 >&2"])
+                    .stderr_limit(CaptureLimit::tail(4)),
+            )
+            .pipe(CommandSpec::new("cat").stdout_limit(CaptureLimit::head_and_tail(16))),
+        );
+
+        assert_eq!(
+            output.stdout_lossy(),
+            "1\n2\n3\n4\n\n... 588879 bytes omitted ...\n\n100000\n"
+        );
+        assert_eq!(output.stdout_truncation.unwrap().total_bytes, 588895);
+        assert_eq!(output.stages[0].stderr, b"000\n");
+        assert_eq!(
+            output.stages[0]
+                .stderr_truncation
+                .as_ref()
+                .unwrap()
+                .omitted_bytes,
+            3893 - 4
+        );
+        assert_eq!(output.stages[1].stderr_truncation, None);
+    }
+}
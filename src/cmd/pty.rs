@@ -0,0 +1,251 @@
+//!
+//! Run a `CommandSpec` on a pseudo-terminal (unix only), for the programs which
+//! behave differently (colors, progress bars, prompts) or refuse to run when they
+//! aren't attached to a terminal.
+//!
+//! The child process becomes a session leader with the pseudo-terminal as its
+//! controlling terminal, stdin, stdout and stderr are all the terminal. Everything
+//! it writes is captured as one transcript.
+//!
+use super::audit;
+use super::exec::{RunningCommand, collect_output, map_spawn_error};
+use super::{CommandError, CommandSpec, ExecuteCommandResult, OutputStream, StdinSource};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Stdio};
+use std::thread::JoinHandle;
+
+//
+// Used when the child process wouldn't inherit `TERM` from the current process
+//
+const DEFAULT_TERM: &str = "xterm-256color";
+
+///
+/// The pseudo-terminal window size, the default is 24 rows and 80 columns
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtySize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Default for PtySize {
+    fn default() -> Self {
+        Self { rows: 24, cols: 80 }
+    }
+}
+
+impl PtySize {
+    pub fn new(rows: u16, cols: u16) -> Self {
+        Self { rows, cols }
+    }
+
+    fn to_winsize(self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+//
+// Open a pseudo-terminal pair with the given window size, return `(master, slave)`
+//
+fn open_pty(size: PtySize) -> io::Result<(File, File)> {
+    let (mut master, mut slave): (RawFd, RawFd) = (-1, -1);
+    let mut winsize = size.to_winsize();
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &raw mut winsize,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+    //
+    // Don't leak them into other child processes, the master would never see EOF
+    // while someone else holds the slave
+    //
+    set_cloexec(master.as_raw_fd())?;
+    set_cloexec(slave.as_raw_fd())?;
+    Ok((master, slave))
+}
+
+//
+// Spawn the child process on a new pseudo-terminal, return it with the master side
+//
+pub(crate) fn spawn_pty(spec: &CommandSpec, size: PtySize) -> Result<(Child, File), CommandError> {
+    let (master, slave) = open_pty(size)
+        .map_err(|e| CommandError::io(String::from("Failed to open a pseudo-terminal"), e))?;
+    let slave_stdio = || {
+        slave
+            .try_clone()
+            .map(Stdio::from)
+            .map_err(|e| CommandError::io(String::from("Failed to clone the pseudo-terminal"), e))
+    };
+
+    let mut cmd = spec.to_command();
+    cmd.stdin(slave_stdio()?)
+        .stdout(slave_stdio()?)
+        .stderr(slave_stdio()?);
+    let has_term = spec.envs.iter().any(|(key, _)| key == "TERM");
+    if !has_term && (spec.env_clear || std::env::var_os("TERM").is_none()) {
+        cmd.env("TERM", DEFAULT_TERM);
+    }
+
+    //
+    // `setsid` also makes the child process a process group leader, so it works with
+    // `kill_process_group` without `Command::process_group`
+    //
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let child = cmd.spawn().map_err(|e| map_spawn_error(spec, e))?;
+
+    //
+    // Close all slave copies in this process, otherwise reading the master never
+    // ends after the child process exits
+    //
+    drop(cmd);
+    drop(slave);
+    Ok((child, master))
+}
+
+//
+// Stdin is typed into the terminal (it's echoed back into the transcript), there is
+// no EOF after it
+//
+fn stdin_input(stdin: &StdinSource) -> Result<Option<Vec<u8>>, CommandError> {
+    match stdin {
+        StdinSource::Null => Ok(None),
+        StdinSource::Bytes(bytes) => Ok(Some(bytes.clone())),
+        StdinSource::File(path) => std::fs::read(path).map(Some).map_err(|e| {
+            CommandError::io(format!("Failed to read stdin file '{}'", path.display()), e)
+        }),
+    }
+}
+
+fn spawn_stdin_typer(mut master: File, input: Vec<u8>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        // The child process might exit without reading all stdin
+        let _ = master.write_all(&input);
+    })
+}
+
+pub(crate) fn execute_pty(
+    spec: &CommandSpec,
+    size: PtySize,
+    on_chunk: &mut dyn FnMut(OutputStream, &[u8]),
+) -> ExecuteCommandResult {
+    audit::run_global(spec, || execute_pty_with(spec, size, on_chunk))
+}
+
+fn execute_pty_with(
+    spec: &CommandSpec,
+    size: PtySize,
+    on_chunk: &mut dyn FnMut(OutputStream, &[u8]),
+) -> ExecuteCommandResult {
+    let spawned = stdin_input(&spec.stdin).and_then(|input| {
+        let (child, master) = spawn_pty(spec, size)?;
+        let stdin_typer = match input {
+            Some(input) => Some(spawn_stdin_typer(
+                master.try_clone().map_err(|e| {
+                    CommandError::io(String::from("Failed to clone the pseudo-terminal"), e)
+                })?,
+                input,
+            )),
+            None => None,
+        };
+        Ok((child, master, stdin_typer))
+    });
+
+    match spawned {
+        Ok((child, master, stdin_typer)) => {
+            let running =
+                RunningCommand::with_output(child, master, stdin_typer, spec.kill_process_group);
+            collect_output(spec, running, on_chunk)
+        }
+        Err(error) => ExecuteCommandResult::Fail(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn transcript(spec: CommandSpec, size: PtySize) -> String {
+        spec.execute_pty(size)
+            .check()
+            .unwrap()
+            .stdout_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn execute_pty_should_attach_a_terminal() {
+        let spec = CommandSpec::new("sh").args(&[
+            "-c",
+            "test -t 0 && test -t 1 && test -t 2 && echo tty; stty size; echo $TERM",
+        ]);
+        let output = transcript(spec.env("TERM", "vt100"), PtySize::new(40, 120));
+        assert_eq!(output, "tty\r\n40 120\r\nvt100\r\n");
+
+        let output = CommandSpec::new("sh")
+            .args(&["-c", "test -t 1 || echo no tty"])
+            .execute()
+            .check()
+            .unwrap();
+        assert_eq!(output.stdout_lossy(), "no tty\n");
+    }
+
+    #[test]
+    fn execute_pty_should_type_stdin() {
+        let spec = CommandSpec::new("sh")
+            .args(&["-c", "printf 'name? '; read name; echo \"hello $name\""])
+            .stdin_bytes(b"rust\n".to_vec());
+        let output = transcript(spec, PtySize::default());
+        //
+        // The input might be echoed before the prompt since it's typed ahead
+        //
+        assert!(output.contains("rust\r\n"), "{output:?}");
+        assert!(output.contains("name? "), "{output:?}");
+        assert!(output.ends_with("hello rust\r\n"), "{output:?}");
+    }
+
+    #[test]
+    fn execute_pty_should_time_out() {
+        let result = CommandSpec::new("sleep")
+            .arg("10")
+            .timeout(Duration::from_millis(100))
+            .execute_pty(PtySize::default());
+        assert!(matches!(result, ExecuteCommandResult::TimedOut(_)));
+    }
+}
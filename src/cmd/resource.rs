@@ -0,0 +1,260 @@
+//!
+//! Resource limits of the child process and its resource usage (unix only). The
+//! resource usage isn't reported for `execute_async`, the tokio runtime reaps the
+//! child process itself.
+//!
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::io;
+
+///
+/// Per-command resource limits, applied by `setrlimit` (both the soft and the hard
+/// limit, see `cpu_seconds` for the exception) and `setpriority` in the child
+/// process right before `exec` (unix only, ignored on other platforms). The command
+/// fails to spawn if a limit can't be set, e.g. raising the hard limit without the
+/// privilege.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandSpec, ResourceLimits};
+///
+/// let limits = ResourceLimits::new()
+///     .cpu_seconds(60)
+///     .address_space(2 * 1024 * 1024 * 1024)
+///     .open_files(256)
+///     .core_dump_size(0)
+///     .nice(10);
+///
+/// let output = CommandSpec::new("sh")
+///     .args(&["-c", "ulimit -n"])
+///     .resource_limits(limits)
+///     .execute()
+///     .check()
+///     .unwrap();
+/// assert_eq!(output.stdout_lossy(), "256\n");
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceLimits {
+    cpu_seconds: Option<u64>,
+    address_space: Option<u64>,
+    open_files: Option<u64>,
+    core_dump_size: Option<u64>,
+    nice: Option<i32>,
+}
+
+impl ResourceLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// `RLIMIT_CPU`, the process gets `SIGXCPU` when it uses up the CPU time and
+    /// `SIGKILL` one second later
+    ///
+    pub fn cpu_seconds(mut self, seconds: u64) -> Self {
+        self.cpu_seconds = Some(seconds);
+        self
+    }
+
+    ///
+    /// `RLIMIT_AS` in bytes, allocations beyond it fail
+    ///
+    pub fn address_space(mut self, bytes: u64) -> Self {
+        self.address_space = Some(bytes);
+        self
+    }
+
+    ///
+    /// `RLIMIT_NOFILE`
+    ///
+    pub fn open_files(mut self, count: u64) -> Self {
+        self.open_files = Some(count);
+        self
+    }
+
+    ///
+    /// `RLIMIT_CORE` in bytes, 0 disables core dumps
+    ///
+    pub fn core_dump_size(mut self, bytes: u64) -> Self {
+        self.core_dump_size = Some(bytes);
+        self
+    }
+
+    ///
+    /// The niceness from -20 (highest priority) to 19 (lowest priority), lowering it
+    /// needs the privilege
+    ///
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    //
+    // Called in the child process between `fork` and `exec`, so only async-signal-safe
+    // functions here
+    //
+    #[cfg(unix)]
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        macro_rules! set_limit {
+            ($resource:expr, $value:expr) => {
+                set_limit!($resource, $value, 0)
+            };
+            ($resource:expr, $value:expr, $hard_extra:expr) => {
+                if let Some(value) = $value {
+                    let limit = libc::rlimit {
+                        rlim_cur: value as libc::rlim_t,
+                        rlim_max: value.saturating_add($hard_extra) as libc::rlim_t,
+                    };
+                    if unsafe { libc::setrlimit($resource, &limit) } == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            };
+        }
+
+        //
+        // One more second for the hard limit, so the process gets `SIGXCPU` before
+        // `SIGKILL`
+        //
+        set_limit!(libc::RLIMIT_CPU, self.cpu_seconds, 1);
+        set_limit!(libc::RLIMIT_AS, self.address_space);
+        set_limit!(libc::RLIMIT_NOFILE, self.open_files);
+        set_limit!(libc::RLIMIT_CORE, self.core_dump_size);
+
+        if let Some(nice) = self.nice
+            && unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+///
+/// The resource usage of the finished child process (and its waited-for children),
+/// reported by `wait4` (unix only, not available for `execute_async`)
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+
+    ///
+    /// The maximum resident set size in bytes
+    ///
+    pub max_rss: u64,
+    pub voluntary_context_switches: u64,
+    pub involuntary_context_switches: u64,
+}
+
+impl ResourceUsage {
+    ///
+    /// User time plus system time
+    ///
+    pub fn cpu_time(&self) -> Duration {
+        self.user_time + self.system_time
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn from_rusage(usage: &libc::rusage) -> Self {
+        let to_duration = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+
+        //
+        // `ru_maxrss` is in bytes on macOS but in kilobytes everywhere else
+        //
+        #[cfg(target_os = "macos")]
+        let max_rss = usage.ru_maxrss as u64;
+        #[cfg(not(target_os = "macos"))]
+        let max_rss = usage.ru_maxrss as u64 * 1024;
+
+        Self {
+            user_time: to_duration(usage.ru_utime),
+            system_time: to_duration(usage.ru_stime),
+            max_rss,
+            voluntary_context_switches: usage.ru_nvcsw as u64,
+            involuntary_context_switches: usage.ru_nivcsw as u64,
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::{CommandSpec, ExecuteCommandResult};
+
+    fn output_of(script: &str, limits: ResourceLimits) -> String {
+        CommandSpec::new("sh")
+            .args(&["-c", script])
+            .resource_limits(limits)
+            .execute()
+            .check()
+            .unwrap()
+            .stdout_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn limits_should_be_applied() {
+        let limits = ResourceLimits::new()
+            .address_space(512 * 1024 * 1024)
+            .open_files(64)
+            .core_dump_size(0)
+            .nice(5);
+        assert_eq!(
+            output_of("ulimit -v; ulimit -n; ulimit -c; nice", limits),
+            "524288\n64\n0\n5\n"
+        );
+    }
+
+    #[test]
+    fn cpu_limit_should_stop_runaway_process() {
+        let result = CommandSpec::new("sh")
+            .args(&["-c", "while :; do :; done"])
+            .resource_limits(ResourceLimits::new().cpu_seconds(1))
+            .timeout(Duration::from_secs(10))
+            .execute();
+        let ExecuteCommandResult::Success(output) = result else {
+            panic!("{result:?}");
+        };
+        assert_eq!(output.signal, Some(libc::SIGXCPU));
+        let usage = output.resource_usage.unwrap();
+        assert!(usage.cpu_time() >= Duration::from_millis(900), "{usage:?}");
+    }
+
+    #[test]
+    fn invalid_limits_should_fail_to_spawn() {
+        //
+        // Beyond the system-wide maximum, even root can't set it
+        //
+        let result = CommandSpec::new("true")
+            .resource_limits(ResourceLimits::new().open_files(u64::MAX - 1))
+            .execute();
+        assert!(
+            matches!(result, ExecuteCommandResult::Fail(_)),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn usage_should_be_reported() {
+        //
+        // Hold 32MB in a shell variable
+        //
+        let output = CommandSpec::new("sh")
+            .args(&[
+                "-c",
+                "x=$(head -c 33554432 /dev/zero | tr '\\0' a); echo ${#x}",
+            ])
+            .execute()
+            .check()
+            .unwrap();
+        assert_eq!(output.stdout_lossy(), "33554432\n");
+        let usage = output.resource_usage.unwrap();
+        assert!(usage.max_rss >= 32 * 1024 * 1024, "{usage:?}");
+    }
+}
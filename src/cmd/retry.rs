@@ -0,0 +1,410 @@
+//!
+//! Run a command again when it fails in a way that's likely temporary, e.g. a local
+//! service which is still starting up.
+//!
+use super::{CommandError, CommandOutput, CommandSpec, ExecuteCommandResult};
+use regex::Regex;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//
+// How often the cancellation token is checked while waiting for the next attempt
+//
+const BACKOFF_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+///
+/// How long to wait before the next attempt
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+
+    ///
+    /// `initial` before the 2nd attempt, then doubled every time up to `max`
+    ///
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    ///
+    /// The delay before the given retry, `retry` starts from 1
+    ///
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        match *self {
+            Self::Fixed(delay) => delay,
+            Self::Exponential { initial, max } => initial
+                .checked_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+                .unwrap_or(max)
+                .min(max),
+        }
+    }
+}
+
+type RetryPredicate = Arc<dyn Fn(&ExecuteCommandResult) -> bool + Send + Sync>;
+
+///
+/// When and how often to retry.
+///
+/// Without any `retry_on_*`/`retry_if` condition, every failure except cancellation is
+/// retried. With conditions, only the failures matching any of them are retried.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{Backoff, CommandSpec, RetryPolicy};
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::new(5)
+///     .backoff(Backoff::Exponential {
+///         initial: Duration::from_millis(10),
+///         max: Duration::from_millis(100),
+///     })
+///     .jitter(0.2)
+///     .retry_on_exit_codes(&[7])
+///     .retry_on_stderr("(?i)connection refused")
+///     .unwrap()
+///     .retry_on_timeout(true);
+///
+/// let result = CommandSpec::new("sh")
+///     .args(&["-c", "echo 'Connection refused' >&2; exit 1"])
+///     .execute_with_retry(&policy);
+///
+/// assert_eq!(result.attempts.len(), 5);
+/// assert!(result.check().is_err());
+/// ```
+///
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Backoff,
+    jitter: f64,
+    exit_codes: Vec<i32>,
+    stderr_pattern: Option<Regex>,
+    on_timeout: bool,
+    predicate: Option<RetryPredicate>,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("jitter", &self.jitter)
+            .field("exit_codes", &self.exit_codes)
+            .field("stderr_pattern", &self.stderr_pattern)
+            .field("on_timeout", &self.on_timeout)
+            .field("predicate", &self.predicate.as_ref().map(|_| "Fn"))
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    ///
+    /// Run at most `max_attempts` times (at least 1), with a fixed 1s backoff
+    ///
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff: Backoff::Fixed(Duration::from_secs(1)),
+            jitter: 0.0,
+            exit_codes: Vec::new(),
+            stderr_pattern: None,
+            on_timeout: false,
+            predicate: None,
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    ///
+    /// Randomly shorten every delay by up to this fraction (0.0 - 1.0), so many
+    /// clients don't retry at the same moment
+    ///
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    ///
+    /// Retry when the command exits with one of these exit codes
+    ///
+    pub fn retry_on_exit_codes(mut self, exit_codes: &[i32]) -> Self {
+        self.exit_codes.extend_from_slice(exit_codes);
+        self
+    }
+
+    ///
+    /// Retry when the command fails and its stderr matches the regex
+    ///
+    pub fn retry_on_stderr(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.stderr_pattern = Some(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    ///
+    /// Retry when the command times out (`CommandSpec::timeout`)
+    ///
+    pub fn retry_on_timeout(mut self, enable: bool) -> Self {
+        self.on_timeout = enable;
+        self
+    }
+
+    ///
+    /// Retry when the predicate returns `true` for the failed attempt
+    ///
+    pub fn retry_if(
+        mut self,
+        predicate: impl Fn(&ExecuteCommandResult) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    fn has_conditions(&self) -> bool {
+        !self.exit_codes.is_empty()
+            || self.stderr_pattern.is_some()
+            || self.on_timeout
+            || self.predicate.is_some()
+    }
+
+    ///
+    /// Whether the failed attempt should be retried
+    ///
+    pub fn should_retry(&self, result: &ExecuteCommandResult) -> bool {
+        if result.is_success() || matches!(result, ExecuteCommandResult::Cancelled(_)) {
+            return false;
+        }
+        if !self.has_conditions() {
+            return true;
+        }
+
+        let exit_code_matches = matches!(
+            result,
+            ExecuteCommandResult::Success(CommandOutput { exit_code: Some(code), .. })
+                if self.exit_codes.contains(code)
+        );
+        let stderr_matches = match (result, &self.stderr_pattern) {
+            (ExecuteCommandResult::Success(output), Some(pattern)) => {
+                pattern.is_match(&output.stderr_lossy())
+            }
+            _ => false,
+        };
+        let timed_out = self.on_timeout && matches!(result, ExecuteCommandResult::TimedOut(_));
+        let predicate_matches = self
+            .predicate
+            .as_ref()
+            .is_some_and(|predicate| predicate(result));
+
+        exit_code_matches || stderr_matches || timed_out || predicate_matches
+    }
+
+    //
+    // The delay before the given retry with the jitter applied
+    //
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.backoff.delay(retry);
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+//
+// A random number in [0, 1) for the jitter, it doesn't need to be a good one
+//
+fn random_fraction() -> f64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u64);
+
+    // splitmix64
+    let mut x = nanos
+        ^ COUNTER
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+///
+/// One run of the command
+///
+#[derive(Debug)]
+pub struct RetryAttempt {
+    ///
+    /// How long it waited before this attempt, zero for the first one
+    ///
+    pub delay: Duration,
+    pub duration: Duration,
+    pub result: ExecuteCommandResult,
+}
+
+///
+/// All attempts in order, the last one is the final result
+///
+#[derive(Debug)]
+pub struct RetryResult {
+    pub attempts: Vec<RetryAttempt>,
+}
+
+impl RetryResult {
+    pub fn last(&self) -> &ExecuteCommandResult {
+        // There is always at least 1 attempt
+        &self.attempts[self.attempts.len() - 1].result
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.last().is_success()
+    }
+
+    ///
+    /// The final result, see `ExecuteCommandResult::check`
+    ///
+    pub fn check(self) -> Result<CommandOutput, CommandError> {
+        self.into_last().check()
+    }
+
+    pub fn into_last(mut self) -> ExecuteCommandResult {
+        // There is always at least 1 attempt
+        self.attempts.pop().unwrap().result
+    }
+}
+
+pub(crate) fn execute_with_retry(spec: &CommandSpec, policy: &RetryPolicy) -> RetryResult {
+    let mut attempts = Vec::new();
+    let mut delay = Duration::ZERO;
+
+    loop {
+        let start = Instant::now();
+        let result = spec.execute();
+        let retry = attempts.len() as u32 + 1;
+        let should_retry = retry < policy.max_attempts && policy.should_retry(&result);
+        attempts.push(RetryAttempt {
+            delay,
+            duration: start.elapsed(),
+            result,
+        });
+        if !should_retry {
+            break;
+        }
+
+        //
+        // Sleep until the next attempt unless cancelled
+        //
+        delay = policy.delay(retry);
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if spec.cancel_token.as_ref().is_some_and(|t| t.is_cancelled()) {
+                return RetryResult { attempts };
+            }
+            std::thread::sleep(BACKOFF_POLL_INTERVAL.min(deadline - Instant::now()));
+        }
+    }
+
+    RetryResult { attempts }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_should_work() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let delays = (1..=6)
+            .map(|retry| backoff.delay(retry))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+
+        let policy = RetryPolicy::new(3)
+            .backoff(Backoff::Fixed(Duration::from_millis(100)))
+            .jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn retry_should_stop_after_success() {
+        //
+        // Fail twice then succeed, the counter lives in a file
+        //
+        let counter = std::env::temp_dir().join(format!("rust_utils_retry_{}", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let script = format!(
+            "echo x >> {0}; [ $(wc -l < {0}) -ge 3 ] || {{ echo 'not ready' >&2; exit 75; }}",
+            counter.display()
+        );
+
+        let policy = RetryPolicy::new(5)
+            .backoff(Backoff::Fixed(Duration::from_millis(50)))
+            .retry_on_exit_codes(&[75]);
+        let result = CommandSpec::new("sh")
+            .args(&["-c", &script])
+            .execute_with_retry(&policy);
+
+        assert!(result.is_success());
+        assert_eq!(result.attempts.len(), 3);
+        assert_eq!(result.attempts[0].delay, Duration::ZERO);
+        assert_eq!(result.attempts[1].delay, Duration::from_millis(50));
+        assert!(matches!(
+            &result.attempts[0].result,
+            ExecuteCommandResult::Success(output) if output.stderr_lossy() == "not ready\n"
+        ));
+        let _ = std::fs::remove_file(&counter);
+    }
+
+    #[test]
+    fn predicate_should_decide() {
+        let no_delay = |policy: RetryPolicy| policy.backoff(Backoff::Fixed(Duration::ZERO));
+        let fail = CommandSpec::new("sh").args(&["-c", "echo 'disk full' >&2; exit 1"]);
+
+        // Not matching any condition
+        let policy = no_delay(RetryPolicy::new(3).retry_on_exit_codes(&[75]));
+        assert_eq!(fail.execute_with_retry(&policy).attempts.len(), 1);
+
+        let policy = no_delay(RetryPolicy::new(3).retry_on_stderr("disk").unwrap());
+        assert_eq!(fail.execute_with_retry(&policy).attempts.len(), 3);
+
+        let policy = no_delay(RetryPolicy::new(3));
+        assert_eq!(fail.execute_with_retry(&policy).attempts.len(), 3);
+
+        let policy = no_delay(RetryPolicy::new(3).retry_if(|result| {
+            matches!(
+                result,
+                ExecuteCommandResult::Fail(CommandError::NotFound { .. })
+            )
+        }));
+        let missing = CommandSpec::new("no-such-program-xyz");
+        assert_eq!(missing.execute_with_retry(&policy).attempts.len(), 3);
+        assert_eq!(fail.execute_with_retry(&policy).attempts.len(), 1);
+
+        let policy = no_delay(RetryPolicy::new(2).retry_on_timeout(true));
+        let slow = CommandSpec::new("sleep")
+            .arg("5")
+            .timeout(Duration::from_millis(50));
+        let result = slow.execute_with_retry(&policy);
+        assert_eq!(result.attempts.len(), 2);
+        assert!(matches!(result.check(), Err(CommandError::TimedOut { .. })));
+    }
+}
@@ -0,0 +1,414 @@
+//!
+//! Run many independent commands concurrently with a bounded number of worker threads.
+//!
+use super::{CancellationToken, CommandOutput, CommandSpec, ExecuteCommandResult};
+use crate::logger::{LogLevel, log};
+use crate::{info_log, warn_log};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const LOG_MODULE_NAME: &str = "BatchRunner";
+
+///
+/// What to do when a command fails (anything other than running and exiting with 0)
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureMode {
+    ///
+    /// Run all commands no matter how many of them fail, it's the default
+    ///
+    CollectAll,
+
+    ///
+    /// Don't start any new command and cancel the running ones after the first failure.
+    /// The commands which never started get `ExecuteCommandResult::Cancelled` with an
+    /// empty output.
+    ///
+    FailFast,
+}
+
+///
+/// Reported every time a command finishes
+///
+#[derive(Debug)]
+pub struct BatchProgress<'a> {
+    ///
+    /// The index of the finished command in the submitted list
+    ///
+    pub index: usize,
+    pub completed: usize,
+    pub failed: usize,
+    pub total: usize,
+    pub elapsed: Duration,
+    pub result: &'a ExecuteCommandResult,
+}
+
+///
+/// Run a list of commands concurrently, the results are always in submission order.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{BatchRunner, CommandSpec, FailureMode};
+///
+/// let specs = (1..=5)
+///     .map(|i| CommandSpec::new("echo").arg(&format!("file_{i}.txt")))
+///     .collect::<Vec<_>>();
+///
+/// let results = BatchRunner::new()
+///     .concurrency(2)
+///     .failure_mode(FailureMode::FailFast)
+///     .run_with_progress(&specs, |progress| {
+///         println!("[{}/{}] done", progress.completed, progress.total);
+///     });
+///
+/// assert_eq!(results.len(), 5);
+/// assert!(results.iter().all(|result| result.is_success()));
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchRunner {
+    concurrency: usize,
+    failure_mode: FailureMode,
+    log_progress: bool,
+    validate_first: bool,
+}
+
+impl Default for BatchRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatchRunner {
+    ///
+    /// The default concurrency is the number of available CPUs
+    ///
+    pub fn new() -> Self {
+        Self {
+            concurrency: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            failure_mode: FailureMode::CollectAll,
+            log_progress: false,
+            validate_first: false,
+        }
+    }
+
+    ///
+    /// How many commands run at the same time, at least 1
+    ///
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn failure_mode(mut self, failure_mode: FailureMode) -> Self {
+        self.failure_mode = failure_mode;
+        self
+    }
+
+    ///
+    /// Log every finished command: `info_log!` if it succeeded, otherwise `warn_log!`
+    ///
+    pub fn log_progress(mut self, enable: bool) -> Self {
+        self.log_progress = enable;
+        self
+    }
+
+    ///
+    /// Validate all commands (`CommandSpec::validate`) before running any of them. If
+    /// any is invalid, e.g. a required tool is missing, nothing runs: the invalid ones
+    /// get `ExecuteCommandResult::Fail` and the others `ExecuteCommandResult::Cancelled`
+    /// with an empty output, and the progress isn't reported.
+    ///
+    pub fn validate_first(mut self, enable: bool) -> Self {
+        self.validate_first = enable;
+        self
+    }
+
+    pub fn run(&self, specs: &[CommandSpec]) -> Vec<ExecuteCommandResult> {
+        self.run_with_progress(specs, |_| {})
+    }
+
+    ///
+    /// Same as `run`, `on_progress` is called on the current thread every time a
+    /// command finishes.
+    ///
+    pub fn run_with_progress(
+        &self,
+        specs: &[CommandSpec],
+        mut on_progress: impl FnMut(&BatchProgress),
+    ) -> Vec<ExecuteCommandResult> {
+        if self.validate_first
+            && let Some(results) = self.validate(specs)
+        {
+            return results;
+        }
+
+        let start = Instant::now();
+        let total = specs.len();
+
+        //
+        // Every command gets its own token, so fail-fast can cancel them without
+        // cancelling the caller's tokens
+        //
+        let tokens = specs
+            .iter()
+            .map(|spec| {
+                spec.cancel_token
+                    .as_ref()
+                    .map_or_else(CancellationToken::new, |token| token.child_token())
+            })
+            .collect::<Vec<_>>();
+        let next_index = AtomicUsize::new(0);
+        let stopped = AtomicBool::new(false);
+        let mut results: Vec<Option<ExecuteCommandResult>> =
+            std::iter::repeat_with(|| None).take(total).collect();
+
+        let fail_fast = self.failure_mode == FailureMode::FailFast;
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            for _ in 0..self.concurrency.min(total) {
+                let sender = sender.clone();
+                let (next_index, stopped, tokens) = (&next_index, &stopped, &tokens);
+                scope.spawn(move || {
+                    while !stopped.load(Ordering::SeqCst) {
+                        let index = next_index.fetch_add(1, Ordering::SeqCst);
+                        let Some(spec) = specs.get(index) else {
+                            break;
+                        };
+
+                        //
+                        // Another worker might have failed while this one claimed the
+                        // index, the command is reported as not started then
+                        //
+                        if stopped.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        let result = spec.clone().cancel_token(&tokens[index]).execute();
+
+                        //
+                        // Stop right here, before this worker picks up the next command
+                        //
+                        if fail_fast && !result.is_success() {
+                            stopped.store(true, Ordering::SeqCst);
+                            tokens.iter().for_each(|token| token.cancel());
+                        }
+                        if sender.send((index, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            let (mut completed, mut failed) = (0, 0);
+            for (index, result) in receiver {
+                completed += 1;
+                if !result.is_success() {
+                    failed += 1;
+                }
+
+                let progress = BatchProgress {
+                    index,
+                    completed,
+                    failed,
+                    total,
+                    elapsed: start.elapsed(),
+                    result: &result,
+                };
+                if self.log_progress {
+                    log_progress(&progress);
+                }
+                on_progress(&progress);
+                results[index] = Some(result);
+            }
+        });
+
+        results
+            .into_iter()
+            .zip(specs)
+            .map(|(result, spec)| result.unwrap_or_else(|| not_started(spec)))
+            .collect()
+    }
+
+    //
+    // The results without running anything if any command is invalid
+    //
+    fn validate(&self, specs: &[CommandSpec]) -> Option<Vec<ExecuteCommandResult>> {
+        let errors = specs
+            .iter()
+            .map(|spec| spec.validate().err())
+            .collect::<Vec<_>>();
+        if errors.iter().all(Option::is_none) {
+            return None;
+        }
+
+        let results = errors
+            .into_iter()
+            .zip(specs)
+            .map(|(error, spec)| match error {
+                Some(error) => {
+                    if self.log_progress {
+                        warn_log!(LOG_MODULE_NAME, "run", &format!("Invalid: {error}"));
+                    }
+                    ExecuteCommandResult::Fail(error)
+                }
+                None => not_started(spec),
+            })
+            .collect();
+        Some(results)
+    }
+}
+
+fn not_started(spec: &CommandSpec) -> ExecuteCommandResult {
+    ExecuteCommandResult::Cancelled(CommandOutput {
+        cmd_desc: spec.cmd_desc(),
+        ..Default::default()
+    })
+}
+
+fn log_progress(progress: &BatchProgress) {
+    let prefix = format!("[{}/{}]", progress.completed, progress.total);
+    match progress.result {
+        ExecuteCommandResult::Success(output) if output.exit_code == Some(0) => info_log!(
+            LOG_MODULE_NAME,
+            "run",
+            &format!("{prefix} Succeeded: {}", output.cmd_desc)
+        ),
+        ExecuteCommandResult::Success(output)
+        | ExecuteCommandResult::TimedOut(output)
+        | ExecuteCommandResult::Cancelled(output) => warn_log!(
+            LOG_MODULE_NAME,
+            "run",
+            &format!(
+                "{prefix} Failed ({}): {}",
+                describe_failure(progress.result),
+                output.cmd_desc
+            )
+        ),
+        ExecuteCommandResult::Fail(error) => {
+            warn_log!(LOG_MODULE_NAME, "run", &format!("{prefix} Failed: {error}"))
+        }
+    }
+}
+
+pub(crate) fn describe_failure(result: &ExecuteCommandResult) -> String {
+    match result {
+        ExecuteCommandResult::Success(output) => match (output.exit_code, output.signal) {
+            (Some(exit_code), _) => format!("exit code {exit_code}"),
+            (None, Some(signal)) => format!("signal {signal}"),
+            (None, None) => String::from("terminated"),
+        },
+        ExecuteCommandResult::TimedOut(_) => String::from("timed out"),
+        ExecuteCommandResult::Cancelled(_) => String::from("cancelled"),
+        ExecuteCommandResult::Fail(_) => String::from("failed"),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::CommandError;
+
+    fn sleep_and_exit(seconds: &str, exit_code: i32) -> CommandSpec {
+        CommandSpec::new("sh").args(&["-c", &format!("sleep {seconds}; exit {exit_code}")])
+    }
+
+    #[test]
+    fn run_should_keep_submission_order() {
+        //
+        // The later commands finish first
+        //
+        let specs = (0..6)
+            .map(|i| CommandSpec::new("sh").args(&["-c", &format!("sleep 0.{}; echo {i}", 6 - i)]))
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let mut finished = Vec::new();
+        let results = BatchRunner::new()
+            .concurrency(6)
+            .log_progress(true)
+            .run_with_progress(&specs, |progress| finished.push(progress.index));
+
+        let outputs = results
+            .into_iter()
+            .map(|result| result.check().unwrap().stdout_lossy().trim().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, vec!["0", "1", "2", "3", "4", "5"]);
+        finished.sort_unstable();
+        assert_eq!(finished, vec![0, 1, 2, 3, 4, 5]);
+        assert!(start.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn concurrency_should_be_bounded() {
+        let specs = (0..4).map(|_| sleep_and_exit("0.2", 0)).collect::<Vec<_>>();
+        let start = Instant::now();
+        let results = BatchRunner::new().concurrency(2).run(&specs);
+        assert!(results.iter().all(|result| result.is_success()));
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn collect_all_should_run_everything() {
+        let specs = vec![
+            sleep_and_exit("0", 1),
+            sleep_and_exit("0", 0),
+            CommandSpec::new("no-such-program-xyz"),
+        ];
+        let mut last_failed = 0;
+        let results = BatchRunner::new()
+            .concurrency(1)
+            .run_with_progress(&specs, |progress| last_failed = progress.failed);
+
+        assert!(!results[0].is_success());
+        assert!(results[1].is_success());
+        assert!(matches!(results[2], ExecuteCommandResult::Fail(_)));
+        assert_eq!(last_failed, 2);
+    }
+
+    #[test]
+    fn fail_fast_should_cancel_the_rest() {
+        let specs = vec![
+            sleep_and_exit("0.1", 1),
+            sleep_and_exit("10", 0),
+            sleep_and_exit("0", 0),
+            sleep_and_exit("0", 0),
+        ];
+        let start = Instant::now();
+        let results = BatchRunner::new()
+            .concurrency(2)
+            .failure_mode(FailureMode::FailFast)
+            .run(&specs);
+
+        assert!(
+            matches!(&results[0], ExecuteCommandResult::Success(output) if output.exit_code == Some(1))
+        );
+        assert!(matches!(results[1], ExecuteCommandResult::Cancelled(_)));
+        assert!(matches!(results[2], ExecuteCommandResult::Cancelled(_)));
+        assert!(
+            matches!(&results[3], ExecuteCommandResult::Cancelled(output) if output.cmd_desc == "sh -c 'sleep 0; exit 0'")
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn validate_first_should_not_run_anything() {
+        let marker = std::env::temp_dir().join(format!(
+            "rust_utils_validate_first_test_{}",
+            std::process::id()
+        ));
+        let specs = vec![
+            CommandSpec::new("touch").arg(marker.to_str().unwrap()),
+            CommandSpec::new("rust_utils_no_such_program"),
+        ];
+        let results = BatchRunner::new().validate_first(true).run(&specs);
+
+        assert!(matches!(results[0], ExecuteCommandResult::Cancelled(_)));
+        assert!(
+            matches!(&results[1], ExecuteCommandResult::Fail(CommandError::NotFound { searched, .. }) if !searched.is_empty())
+        );
+        assert!(!marker.exists());
+    }
+}
@@ -0,0 +1,288 @@
+//!
+//! Split a command line string into argv by the POSIX shell quoting rules, and quote
+//! argv back into a command line that can be pasted into a shell.
+//!
+//! Only the quoting is supported: `'single'`, `"double"`, backslash escapes and the
+//! optional `$VAR`/`${VAR}` expansion. Unquoted shell operators (`|`, `>`, `;`, `&`
+//! ...) are rejected instead of being passed as plain arguments, use `Pipeline` and
+//! `Redirect` for them.
+//!
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+///
+/// Split the command line into argv, `$` is a plain character.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::shell;
+///
+/// assert_eq!(
+///     shell::split(r#"grep -e 'a b' "say \"hi\"" c\ d"#).unwrap(),
+///     vec!["grep", "-e", "a b", "say \"hi\"", "c d"]
+/// );
+/// assert!(shell::split("echo 'unterminated").is_err());
+/// ```
+///
+pub fn split(line: &str) -> Result<Vec<String>, String> {
+    Splitter::new(line, None).split()
+}
+
+///
+/// Split the command line into argv and expand `$VAR` and `${VAR}` (unquoted or in
+/// double quotes) from `vars`, an unknown var expands to an empty string. Unlike a
+/// real shell, the expanded value is never split into more arguments.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::shell;
+/// use std::collections::HashMap;
+///
+/// let vars = HashMap::from([(String::from("DIR"), String::from("my files"))]);
+/// assert_eq!(
+///     shell::split_with_vars("ls $DIR \"${DIR}/a\" '$DIR' $EMPTY", &vars).unwrap(),
+///     vec!["ls", "my files", "my files/a", "$DIR"]
+/// );
+/// ```
+///
+pub fn split_with_vars(line: &str, vars: &HashMap<String, String>) -> Result<Vec<String>, String> {
+    Splitter::new(line, Some(vars)).split()
+}
+
+//
+// The characters which never need quoting
+//
+fn is_safe_char(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || matches!(c, '_' | '-' | '.' | '/' | ',' | ':' | '=' | '+' | '@' | '%')
+}
+
+///
+/// Quote the argument for a POSIX shell if needed, e.g. `a b` -> `'a b'`,
+/// `it's` -> `'it'\''s'`, empty string -> `''`
+///
+pub fn quote(arg: &str) -> Cow<'_, str> {
+    if !arg.is_empty() && arg.chars().all(is_safe_char) {
+        return Cow::Borrowed(arg);
+    }
+    Cow::Owned(format!("'{}'", arg.replace('\'', r"'\''")))
+}
+
+///
+/// Quote every argument and join them with spaces
+///
+pub fn join<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter()
+        .map(|arg| quote(arg.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+struct Splitter<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    vars: Option<&'a HashMap<String, String>>,
+    args: Vec<String>,
+    word: String,
+
+    //
+    // An empty quoted string (`''`) is still an argument, but an unquoted var which
+    // expands to nothing is not
+    //
+    in_word: bool,
+}
+
+impl<'a> Splitter<'a> {
+    fn new(line: &'a str, vars: Option<&'a HashMap<String, String>>) -> Self {
+        Self {
+            chars: line.chars().peekable(),
+            vars,
+            args: Vec::new(),
+            word: String::new(),
+            in_word: false,
+        }
+    }
+
+    fn end_word(&mut self) {
+        if self.in_word || !self.word.is_empty() {
+            self.args.push(std::mem::take(&mut self.word));
+        }
+        self.in_word = false;
+    }
+
+    fn split(mut self) -> Result<Vec<String>, String> {
+        while let Some(c) = self.chars.next() {
+            match c {
+                ' ' | '\t' | '\n' => self.end_word(),
+                '#' if !self.in_word && self.word.is_empty() => {
+                    // Comment until the end of the line
+                    for c in self.chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                '\\' => match self.chars.next() {
+                    // Line continuation
+                    Some('\n') => {}
+                    Some(c) => {
+                        self.word.push(c);
+                        self.in_word = true;
+                    }
+                    None => return Err(String::from("trailing backslash")),
+                },
+                '\'' => {
+                    self.in_word = true;
+                    loop {
+                        match self.chars.next() {
+                            Some('\'') => break,
+                            Some(c) => self.word.push(c),
+                            None => return Err(String::from("unterminated single quote")),
+                        }
+                    }
+                }
+                '"' => {
+                    self.in_word = true;
+                    self.double_quoted()?;
+                }
+                '$' if self.vars.is_some() => self.expand_var()?,
+                '|' | '&' | ';' | '<' | '>' | '(' | ')' | '`' => {
+                    return Err(format!("unsupported unquoted shell operator '{c}'"));
+                }
+                c => {
+                    self.word.push(c);
+                    self.in_word = true;
+                }
+            }
+        }
+        self.end_word();
+        Ok(self.args)
+    }
+
+    fn double_quoted(&mut self) -> Result<(), String> {
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(()),
+                Some('\\') => match self.chars.next() {
+                    Some('\n') => {}
+                    Some(c @ ('$' | '`' | '"' | '\\')) => self.word.push(c),
+                    Some(c) => {
+                        self.word.push('\\');
+                        self.word.push(c);
+                    }
+                    None => return Err(String::from("unterminated double quote")),
+                },
+                Some('$') if self.vars.is_some() => self.expand_var()?,
+                Some('`') if self.vars.is_some() => {
+                    return Err(String::from("command substitution is not supported"));
+                }
+                Some(c) => self.word.push(c),
+                None => return Err(String::from("unterminated double quote")),
+            }
+        }
+    }
+
+    //
+    // Expand the var after `$`, a `$` which isn't followed by a name is kept as it is
+    //
+    fn expand_var(&mut self) -> Result<(), String> {
+        let name = match self.chars.peek() {
+            Some('{') => {
+                self.chars.next();
+                let mut name = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+                        Some(c) => {
+                            return Err(format!("unsupported character '{c}' in '${{...}}'"));
+                        }
+                        None => return Err(String::from("unterminated '${'")),
+                    }
+                }
+                if name.is_empty() {
+                    return Err(String::from("empty var name in '${}'"));
+                }
+                name
+            }
+            Some('(') => return Err(String::from("command substitution is not supported")),
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                let mut name = String::new();
+                while let Some(c) = self.chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || *c == '_') {
+                        break;
+                    }
+                    name.push(*c);
+                    self.chars.next();
+                }
+                name
+            }
+            _ => {
+                self.word.push('$');
+                self.in_word = true;
+                return Ok(());
+            }
+        };
+
+        if let Some(value) = self.vars.and_then(|vars| vars.get(&name)) {
+            self.word.push_str(value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_should_work() {
+        assert_eq!(split("").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            split("  a\tb \\\n c  # comment 'x").unwrap(),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(split("a#b '' \"\"").unwrap(), vec!["a#b", "", ""]);
+        assert_eq!(
+            split(r#"'a\b' "a\b\$\\" a"b"'c'"#).unwrap(),
+            vec![r"a\b", r"a\b$\", "abc"]
+        );
+        assert_eq!(split("echo $HOME").unwrap(), vec!["echo", "$HOME"]);
+
+        assert!(split("echo \"a").is_err());
+        assert!(split("echo a\\").is_err());
+        assert!(split("ls | wc").is_err());
+        assert!(split("ls > out").is_err());
+        assert_eq!(split("echo '|' \\>").unwrap(), vec!["echo", "|", ">"]);
+    }
+
+    #[test]
+    fn split_with_vars_should_work() {
+        let vars = HashMap::from([
+            (String::from("A"), String::from("x y")),
+            (String::from("B_1"), String::from("'z'")),
+        ]);
+        assert_eq!(
+            split_with_vars("$A ${B_1}.txt \"$A-$\" \\$A $NONE \"$NONE\" 5$", &vars).unwrap(),
+            vec!["x y", "'z'.txt", "x y-$", "$A", "", "5$"]
+        );
+        assert!(split_with_vars("echo $(ls)", &vars).is_err());
+        assert!(split_with_vars("echo ${A", &vars).is_err());
+        assert!(split_with_vars("echo ${A:-b}", &vars).is_err());
+    }
+
+    #[test]
+    fn quote_should_round_trip() {
+        assert_eq!(quote("ls"), "ls");
+        assert_eq!(quote("./a-b_c/d.txt"), "./a-b_c/d.txt");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+
+        let args = [
+            "echo", "", "a b", "it's", "$HOME", "\"q\"", "x\\y", "a\nb", "*", "|",
+        ];
+        assert_eq!(split(&join(&args)).unwrap(), args);
+    }
+}
@@ -0,0 +1,906 @@
+use super::retry::{self, RetryPolicy, RetryResult};
+use super::stream::LineSplitter;
+use super::{
+    CancellationToken, CaptureLimit, CommandError, ExecuteCommandResult, OutputStream,
+    ResourceLimits, exec, shell, which,
+};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+const DEFAULT_KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+const DEFAULT_SEARCH_PATH: &str = "/bin:/usr/bin";
+
+///
+/// Where the child process reads its stdin from
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum StdinSource {
+    ///
+    /// Reading from stdin gets EOF immediately, it's the default
+    ///
+    Null,
+    Bytes(Vec<u8>),
+    File(PathBuf),
+}
+
+///
+/// Where the child process writes its stdout or stderr to, instead of collecting it
+/// into the result
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Redirect {
+    ///
+    /// Discard the output, same as `> /dev/null`
+    ///
+    Null,
+
+    ///
+    /// Create or truncate the file, same as `> file`
+    ///
+    File(PathBuf),
+
+    ///
+    /// Create the file or append to it, same as `>> file`
+    ///
+    Append(PathBuf),
+}
+
+///
+/// Command builder, it describes how to run a command and can be executed many times.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandSpec, ExecuteCommandResult};
+///
+/// let result = CommandSpec::new("sh")
+///     .arg("-c")
+///     .arg("cat; echo \" from $GREETING in $(pwd)\"")
+///     .env_clear()
+///     .env("PATH", "/usr/bin:/bin")
+///     .env("GREETING", "rust")
+///     .cwd("/tmp")
+///     .stdin_bytes(b"hello".to_vec())
+///     .execute();
+///
+/// if let ExecuteCommandResult::Success(output) = result {
+///     assert_eq!(output.stdout_lossy(), "hello from rust in /tmp\n");
+/// }
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSpec {
+    pub(crate) program: String,
+    pub(crate) args: Vec<String>,
+
+    //
+    // `None` value means removing the env var
+    //
+    pub(crate) envs: Vec<(String, Option<String>)>,
+    pub(crate) env_clear: bool,
+    pub(crate) cwd: Option<PathBuf>,
+    pub(crate) stdin: StdinSource,
+    pub(crate) stdout_redirect: Option<Redirect>,
+    pub(crate) stderr_redirect: Option<Redirect>,
+    pub(crate) combined_output: bool,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) kill_grace_period: Duration,
+    pub(crate) kill_process_group: bool,
+    pub(crate) cancel_token: Option<CancellationToken>,
+    pub(crate) log_output: bool,
+    pub(crate) resource_limits: Option<ResourceLimits>,
+    pub(crate) stdout_limit: Option<CaptureLimit>,
+    pub(crate) stderr_limit: Option<CaptureLimit>,
+}
+
+impl CommandSpec {
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            env_clear: false,
+            cwd: None,
+            stdin: StdinSource::Null,
+            stdout_redirect: None,
+            stderr_redirect: None,
+            combined_output: false,
+            timeout: None,
+            kill_grace_period: DEFAULT_KILL_GRACE_PERIOD,
+            kill_process_group: false,
+            cancel_token: None,
+            log_output: false,
+            resource_limits: None,
+            stdout_limit: None,
+            stderr_limit: None,
+        }
+    }
+
+    ///
+    /// Build from a command line string by the POSIX shell quoting rules, see
+    /// `shell::split`. Return error if the line is invalid or empty.
+    ///
+    pub fn parse(line: &str) -> Result<Self, String> {
+        Self::from_args(shell::split(line)?)
+    }
+
+    ///
+    /// Same as `parse` but also expand `$VAR` and `${VAR}` from `vars`, see
+    /// `shell::split_with_vars`
+    ///
+    pub fn parse_with_vars(line: &str, vars: &HashMap<String, String>) -> Result<Self, String> {
+        Self::from_args(shell::split_with_vars(line, vars)?)
+    }
+
+    fn from_args(args: Vec<String>) -> Result<Self, String> {
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| String::from("empty command line"))?;
+        Ok(Self::new(program).args(args))
+    }
+
+    ///
+    /// Build from a `cmd_list` like `execute_command` accepts, the first element is
+    /// the program. Return `None` if the `cmd_list` is empty.
+    ///
+    pub fn from_list(cmd_list: &[&str]) -> Option<Self> {
+        let (program, args) = cmd_list.split_first()?;
+        Some(Self::new(program).args(args))
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub fn args<S: AsRef<str>>(mut self, args: &[S]) -> Self {
+        self.args
+            .extend(args.iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    ///
+    /// Set an env var for the child process
+    ///
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.envs.push((key.to_string(), Some(value.to_string())));
+        self
+    }
+
+    ///
+    /// Remove an env var (inherited from the current process) for the child process
+    ///
+    pub fn env_remove(mut self, key: &str) -> Self {
+        self.envs.push((key.to_string(), None));
+        self
+    }
+
+    ///
+    /// Don't inherit any env var from the current process, only the ones set by
+    /// `env()` after or before this call are passed to the child process.
+    ///
+    pub fn env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self.envs.retain(|(_, value)| value.is_some());
+        self
+    }
+
+    ///
+    /// The working directory of the child process
+    ///
+    pub fn cwd<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.cwd = Some(dir.into());
+        self
+    }
+
+    ///
+    /// Feed the given bytes into the child process stdin, then close it
+    ///
+    pub fn stdin_bytes(mut self, bytes: Vec<u8>) -> Self {
+        self.stdin = StdinSource::Bytes(bytes);
+        self
+    }
+
+    ///
+    /// Feed the given file content into the child process stdin
+    ///
+    pub fn stdin_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.stdin = StdinSource::File(path.into());
+        self
+    }
+
+    ///
+    /// Redirect stdout to a file or `/dev/null`, the result's `stdout` is empty then
+    ///
+    pub fn stdout_to(mut self, redirect: Redirect) -> Self {
+        self.stdout_redirect = Some(redirect);
+        self
+    }
+
+    ///
+    /// Redirect stderr to a file or `/dev/null`, the result's `stderr` is empty then
+    ///
+    pub fn stderr_to(mut self, redirect: Redirect) -> Self {
+        self.stderr_redirect = Some(redirect);
+        self
+    }
+
+    ///
+    /// Also capture stdout and stderr interleaved in the order they were read,
+    /// see `CommandOutput::combined`
+    ///
+    pub fn combined_output(mut self, enable: bool) -> Self {
+        self.combined_output = enable;
+        self
+    }
+
+    ///
+    /// Terminate the child process if it's still running after the given duration,
+    /// the result is `ExecuteCommandResult::TimedOut` with the output collected so far.
+    ///
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    ///
+    /// When terminating the child process (timeout or cancellation), send `SIGTERM`
+    /// first and `SIGKILL` if it's still running after the grace period. Zero means
+    /// sending `SIGKILL` directly. Default is 2s.
+    ///
+    pub fn kill_grace_period(mut self, grace_period: Duration) -> Self {
+        self.kill_grace_period = grace_period;
+        self
+    }
+
+    ///
+    /// Run the child process in its own process group, so terminating it also
+    /// terminates all processes it started (unix only). The child process doesn't
+    /// receive the terminal signals (e.g. `Ctrl-C`) sent to the current process
+    /// group anymore.
+    ///
+    pub fn kill_process_group(mut self, enable: bool) -> Self {
+        self.kill_process_group = enable;
+        self
+    }
+
+    ///
+    /// Terminate the child process when the token is cancelled, the result is
+    /// `ExecuteCommandResult::Cancelled` with the output collected so far.
+    ///
+    pub fn cancel_token(mut self, token: &CancellationToken) -> Self {
+        self.cancel_token = Some(token.clone());
+        self
+    }
+
+    ///
+    /// Forward every output line to the logger while the command runs: `info_log!`
+    /// for stdout and `warn_log!` for stderr, the command name is the module name.
+    ///
+    pub fn log_output(mut self, enable: bool) -> Self {
+        self.log_output = enable;
+        self
+    }
+
+    ///
+    /// Resource limits and niceness of the child process (unix only), see
+    /// `ResourceLimits`
+    ///
+    pub fn resource_limits(mut self, limits: ResourceLimits) -> Self {
+        self.resource_limits = Some(limits);
+        self
+    }
+
+    ///
+    /// Bound how much stdout is kept in the result, it's unbounded by default. The
+    /// callbacks of `execute_with_chunks` and `execute_with_lines` still get all of it.
+    ///
+    pub fn stdout_limit(mut self, limit: CaptureLimit) -> Self {
+        self.stdout_limit = Some(limit);
+        self
+    }
+
+    ///
+    /// Bound how much stderr is kept in the result, see `stdout_limit`
+    ///
+    pub fn stderr_limit(mut self, limit: CaptureLimit) -> Self {
+        self.stderr_limit = Some(limit);
+        self
+    }
+
+    pub fn get_program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn get_args(&self) -> &[String] {
+        &self.args
+    }
+
+    ///
+    /// Get back a cmd string which can be pasted into a shell, e.g. `ls -lht ./` or
+    /// `grep -e 'a b' file.txt > /tmp/out.txt`
+    ///
+    pub fn cmd_desc(&self) -> String {
+        let mut cmd_desc = shell::quote(&self.program).into_owned();
+        for arg in &self.args {
+            cmd_desc.push(' ');
+            cmd_desc.push_str(&shell::quote(arg));
+        }
+
+        let quote_path = |path: &PathBuf| shell::quote(&path.to_string_lossy()).into_owned();
+        if let StdinSource::File(path) = &self.stdin {
+            cmd_desc.push_str(&format!(" < {}", quote_path(path)));
+        }
+        for (fd, redirect) in [("", &self.stdout_redirect), ("2", &self.stderr_redirect)] {
+            match redirect {
+                Some(Redirect::Null) => cmd_desc.push_str(&format!(" {fd}> /dev/null")),
+                Some(Redirect::File(path)) => {
+                    cmd_desc.push_str(&format!(" {fd}> {}", quote_path(path)))
+                }
+                Some(Redirect::Append(path)) => {
+                    cmd_desc.push_str(&format!(" {fd}>> {}", quote_path(path)))
+                }
+                None => {}
+            }
+        }
+        cmd_desc
+    }
+
+    //
+    // The file name of the program, e.g. `/usr/bin/make` -> `make`
+    //
+    pub(crate) fn program_name(&self) -> &str {
+        std::path::Path::new(&self.program)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.program)
+    }
+
+    ///
+    /// Build the `std::process::Command` with everything except stdio
+    ///
+    pub(crate) fn to_command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+
+        if self.env_clear {
+            cmd.env_clear();
+        }
+        for (key, value) in &self.envs {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+
+        #[cfg(unix)]
+        if let Some(limits) = self.resource_limits {
+            use std::os::unix::process::CommandExt;
+            unsafe {
+                cmd.pre_exec(move || limits.apply());
+            }
+        }
+        cmd
+    }
+
+    ///
+    /// Find the executable the way spawning the command would: in the `PATH` set by
+    /// `env()` if any, otherwise in the current process's `PATH`. A program with a
+    /// path separator is relative to `cwd`. See `cmd::which`.
+    ///
+    pub fn resolve_program(&self) -> Result<PathBuf, CommandError> {
+        let search_path = match self.envs.iter().rev().find(|(key, _)| key == "PATH") {
+            Some((_, Some(path))) => Some(OsString::from(path)),
+            //
+            // `execvp` falls back to its default when the child has no `PATH`
+            //
+            Some((_, None)) => Some(OsString::from(DEFAULT_SEARCH_PATH)),
+            None if self.env_clear => Some(OsString::from(DEFAULT_SEARCH_PATH)),
+            None => std::env::var_os("PATH"),
+        };
+        which::resolve(&self.program, search_path.as_deref(), self.cwd.as_deref())
+    }
+
+    ///
+    /// Check without running anything that the program can be found and executed and
+    /// the working directory exists
+    ///
+    pub fn validate(&self) -> Result<(), CommandError> {
+        if let Some(cwd) = &self.cwd
+            && !cwd.is_dir()
+        {
+            return Err(CommandError::Spawn {
+                program: self.program.clone(),
+                source: io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("working directory '{}' doesn't exist", cwd.display()),
+                ),
+            });
+        }
+        self.resolve_program().map(|_| ())
+    }
+
+    ///
+    /// Execute the command as a child process, wait for it to finish and collect all
+    /// of its output.
+    ///
+    pub fn execute(&self) -> ExecuteCommandResult {
+        exec::execute(self)
+    }
+
+    ///
+    /// Spawn the command on the tokio runtime (`tokio` feature), must be called within
+    /// a tokio runtime. See `AsyncCommand` for streaming the output lines.
+    ///
+    #[cfg(feature = "tokio")]
+    pub fn spawn_async(&self) -> Result<super::AsyncCommand, super::CommandError> {
+        super::AsyncCommand::spawn(self)
+    }
+
+    ///
+    /// Async version of `execute` on the tokio runtime (`tokio` feature)
+    ///
+    #[cfg(feature = "tokio")]
+    pub async fn execute_async(&self) -> ExecuteCommandResult {
+        let (started_at, start) = (std::time::SystemTime::now(), std::time::Instant::now());
+        let dry_run = super::audit::is_dry_run();
+        let result = if dry_run {
+            super::audit::dry_run(self)
+        } else {
+            match self.spawn_async() {
+                Ok(command) => command.wait().await,
+                Err(error) => ExecuteCommandResult::Fail(error),
+            }
+        };
+        super::audit::record_global(self, &result, started_at, start.elapsed(), dry_run);
+        result
+    }
+
+    ///
+    /// Execute the command on a pseudo-terminal with the given window size (unix
+    /// only), for the programs which only show colors, progress bars or prompts on a
+    /// terminal. The child process gets `TERM=xterm-256color` unless `TERM` is set
+    /// explicitly or inherited.
+    ///
+    /// `stdout` of the result is the whole terminal transcript (stdout and stderr
+    /// interleaved, `\n` becomes `\r\n`) and `stderr` is always empty. stdin is typed
+    /// into the terminal without EOF, and the stdout/stderr redirects are ignored.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use rust_utils::cmd::{CommandSpec, PtySize};
+    ///
+    /// let output = CommandSpec::new("sh")
+    ///     .args(&["-c", "test -t 1 && stty size"])
+    ///     .execute_pty(PtySize::new(40, 120))
+    ///     .check()
+    ///     .unwrap();
+    /// assert_eq!(output.stdout_lossy(), "40 120\r\n");
+    /// ```
+    ///
+    #[cfg(unix)]
+    pub fn execute_pty(&self, size: super::PtySize) -> ExecuteCommandResult {
+        super::pty::execute_pty(self, size, &mut |_, _| {})
+    }
+
+    ///
+    /// Spawn the command for scripted interaction with piped stdio, see
+    /// `ExpectSession`
+    ///
+    pub fn spawn_session(&self) -> Result<super::ExpectSession, super::CommandError> {
+        super::ExpectSession::spawn(self)
+    }
+
+    ///
+    /// Same as `spawn_session` but on a pseudo-terminal (unix only), see `execute_pty`
+    ///
+    #[cfg(unix)]
+    pub fn spawn_session_pty(
+        &self,
+        size: super::PtySize,
+    ) -> Result<super::ExpectSession, super::CommandError> {
+        super::ExpectSession::spawn_pty(self, size)
+    }
+
+    ///
+    /// Execute the command and run it again by the policy while it fails, every
+    /// attempt is recorded in the result.
+    ///
+    pub fn execute_with_retry(&self, policy: &RetryPolicy) -> RetryResult {
+        retry::execute_with_retry(self, policy)
+    }
+
+    ///
+    /// Execute the command and call `on_chunk` with every chunk of stdout and stderr
+    /// as soon as it's read. The result still has the full output.
+    ///
+    pub fn execute_with_chunks(
+        &self,
+        mut on_chunk: impl FnMut(OutputStream, &[u8]),
+    ) -> ExecuteCommandResult {
+        exec::execute_with(self, &mut on_chunk)
+    }
+
+    ///
+    /// Execute the command and call `on_line` with every line of stdout and stderr
+    /// (without the trailing newline) as soon as it's complete. The result still has
+    /// the full output.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use rust_utils::cmd::{CommandSpec, OutputStream};
+    ///
+    /// let mut stderr_lines = Vec::new();
+    /// let result = CommandSpec::new("sh")
+    ///     .args(&["-c", "echo building; echo 'warning: unused' >&2"])
+    ///     .execute_with_lines(|stream, line| {
+    ///         if stream == OutputStream::Stderr {
+    ///             stderr_lines.push(line.to_string());
+    ///         }
+    ///     });
+    /// assert_eq!(stderr_lines, vec!["warning: unused"]);
+    /// ```
+    ///
+    pub fn execute_with_lines(
+        &self,
+        mut on_line: impl FnMut(OutputStream, &str),
+    ) -> ExecuteCommandResult {
+        let mut splitter = LineSplitter::default();
+        let result = exec::execute_with(self, &mut |stream, data| {
+            splitter.push(stream, data, &mut on_line)
+        });
+        splitter.finish(&mut on_line);
+        result
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::{CommandError, CommandOutput};
+
+    fn output_of(spec: &CommandSpec) -> String {
+        match spec.execute() {
+            ExecuteCommandResult::Success(output) => output.stdout_lossy().to_string(),
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[test]
+    fn env_and_cwd_should_work() {
+        let spec = CommandSpec::new("sh")
+            .args(&["-c", "echo \"$A|$B|$HOME|$(pwd)\""])
+            .env("A", "1")
+            .env("B", "2")
+            .env_remove("B")
+            .cwd("/");
+        assert_eq!(
+            output_of(&spec),
+            format!("1||{}|/\n", std::env::var("HOME").unwrap_or_default())
+        );
+
+        let cleared = CommandSpec::new("/usr/bin/env")
+            .env_clear()
+            .env("ONLY", "me");
+        assert_eq!(output_of(&cleared), "ONLY=me\n");
+    }
+
+    #[test]
+    fn stdin_should_work() {
+        let bytes = CommandSpec::new("cat").stdin_bytes(b"from bytes".to_vec());
+        assert_eq!(output_of(&bytes), "from bytes");
+
+        let stdin_file =
+            std::env::temp_dir().join(format!("rust_utils_stdin_{}", std::process::id()));
+        std::fs::write(&stdin_file, "from file").unwrap();
+        let file = CommandSpec::new("cat").stdin_file(&stdin_file);
+        assert_eq!(output_of(&file), "from file");
+        let _ = std::fs::remove_file(&stdin_file);
+
+        let missing = CommandSpec::new("cat").stdin_file("/no/such/file");
+        assert!(matches!(
+            missing.execute(),
+            ExecuteCommandResult::Fail(CommandError::Io { .. })
+        ));
+
+        // Without stdin, reading from it gets EOF immediately
+        assert_eq!(output_of(&CommandSpec::new("cat")), "");
+    }
+
+    #[test]
+    fn from_list_should_work() {
+        let spec = CommandSpec::from_list(&["ls", "-lht", "./"]).unwrap();
+        assert_eq!(spec.get_program(), "ls");
+        assert_eq!(spec.get_args(), ["-lht", "./"]);
+        assert_eq!(spec.cmd_desc(), "ls -lht ./");
+        assert_eq!(CommandSpec::from_list(&[]), None);
+    }
+
+    #[test]
+    fn parse_should_work() {
+        let line = r#"grep -e "it's here" 'a b.txt' \$HOME"#;
+        let spec = CommandSpec::parse(line).unwrap();
+        assert_eq!(spec.get_args(), ["-e", "it's here", "a b.txt", "$HOME"]);
+        assert_eq!(spec.cmd_desc(), r"grep -e 'it'\''s here' 'a b.txt' '$HOME'");
+        assert_eq!(CommandSpec::parse(&spec.cmd_desc()).unwrap(), spec);
+
+        let vars = HashMap::from([(String::from("NAME"), String::from("a b"))]);
+        let spec = CommandSpec::parse_with_vars("touch /tmp/$NAME", &vars).unwrap();
+        assert_eq!(spec.get_args(), ["/tmp/a b"]);
+
+        assert!(CommandSpec::parse("  ").is_err());
+        assert!(CommandSpec::parse("ls 'a").is_err());
+
+        let spec = CommandSpec::new("sort")
+            .stdin_file("my input")
+            .stdout_to(Redirect::Append(PathBuf::from("/tmp/out.txt")));
+        assert_eq!(spec.cmd_desc(), "sort < 'my input' >> /tmp/out.txt");
+    }
+
+    #[test]
+    fn raw_output_should_work() {
+        //
+        // Sleep between the writes, the interleaving of the two pipes is only
+        // deterministic when the reader threads get each chunk before the next one
+        //
+        let spec = CommandSpec::new("sh")
+            .args(&[
+                "-c",
+                "printf 'out\\377'; sleep 0.1; printf 'err' >&2; sleep 0.1; printf 'end'",
+            ])
+            .combined_output(true);
+        let ExecuteCommandResult::Success(output) = spec.execute() else {
+            panic!("should succeed");
+        };
+
+        assert_eq!(output.stdout, b"out\xffend");
+        assert!(output.stdout_str().is_err());
+        assert_eq!(output.stdout_lossy(), "out\u{FFFD}end");
+        assert_eq!(output.stderr_str(), Ok("err"));
+        assert_eq!(output.combined_lossy().unwrap(), "out\u{FFFD}errend");
+
+        let no_combined = CommandSpec::new("echo").execute();
+        assert!(matches!(
+            no_combined,
+            ExecuteCommandResult::Success(CommandOutput { combined: None, .. })
+        ));
+    }
+
+    #[test]
+    fn timeout_should_return_partial_output() {
+        let start = std::time::Instant::now();
+        let result = CommandSpec::new("sh")
+            .args(&["-c", "echo partial; sleep 10"])
+            .timeout(Duration::from_millis(300))
+            .execute();
+
+        let ExecuteCommandResult::TimedOut(output) = result else {
+            panic!("should time out: {result:?}");
+        };
+        assert_eq!(output.stdout_lossy(), "partial\n");
+        assert_eq!(output.exit_code, None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn timeout_should_escalate_to_sigkill() {
+        //
+        // The shell ignores `SIGTERM`, and its `sleep` child keeps the stdout pipe open
+        // after the shell is killed.
+        //
+        let start = std::time::Instant::now();
+        let result = CommandSpec::new("sh")
+            .args(&["-c", "trap '' TERM; sleep 10; echo never"])
+            .timeout(Duration::from_millis(100))
+            .kill_grace_period(Duration::from_millis(200))
+            .execute();
+
+        assert!(matches!(result, ExecuteCommandResult::TimedOut(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn kill_process_group_should_kill_grandchildren() {
+        let result = CommandSpec::new("sh")
+            .args(&["-c", "sleep 30 & echo $!; wait"])
+            .timeout(Duration::from_millis(300))
+            .kill_process_group(true)
+            .execute();
+
+        let ExecuteCommandResult::TimedOut(output) = result else {
+            panic!("should time out: {result:?}");
+        };
+        let grandchild_pid = output.stdout_lossy().trim().to_string();
+        std::thread::sleep(Duration::from_millis(100));
+
+        //
+        // Either reaped already or a zombie waiting to be reaped
+        //
+        let stat = std::fs::read_to_string(format!("/proc/{grandchild_pid}/stat"));
+        if let Ok(stat) = stat {
+            assert!(stat.contains(") Z "), "grandchild still running: {stat}");
+        }
+    }
+
+    #[test]
+    fn cancel_token_should_work() {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+
+        let start = std::time::Instant::now();
+        let result = CommandSpec::new("sh")
+            .args(&["-c", "echo started; sleep 10"])
+            .cancel_token(&token)
+            .execute();
+
+        let ExecuteCommandResult::Cancelled(output) = result else {
+            panic!("should be cancelled: {result:?}");
+        };
+        assert_eq!(output.stdout_lossy(), "started\n");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn timeout_should_stop_chatty_child() {
+        let start = std::time::Instant::now();
+        let result = CommandSpec::new("yes")
+            .stdout_limit(CaptureLimit::tail(1024))
+            .timeout(Duration::from_millis(300))
+            .execute();
+
+        let ExecuteCommandResult::TimedOut(output) = result else {
+            panic!("should time out: {result:?}");
+        };
+        assert!(output.stdout_lossy().ends_with("y\n"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+        let result = CommandSpec::new("yes")
+            .stdout_limit(CaptureLimit::tail(1024))
+            .cancel_token(&token)
+            .execute();
+        assert!(matches!(result, ExecuteCommandResult::Cancelled(_)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn timeout_should_apply_while_grandchild_keeps_pipe_open() {
+        for kill_process_group in [false, true] {
+            let start = std::time::Instant::now();
+            let result = CommandSpec::new("sh")
+                .args(&["-c", "sleep 3 & echo hi"])
+                .timeout(Duration::from_millis(300))
+                .kill_process_group(kill_process_group)
+                .execute();
+
+            let ExecuteCommandResult::TimedOut(output) = result else {
+                panic!("should time out: {result:?}");
+            };
+            assert_eq!(output.stdout_lossy(), "hi\n");
+            assert_eq!(output.exit_code, Some(0));
+            assert!(
+                start.elapsed() < Duration::from_secs(2),
+                "{:?}",
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn execute_with_lines_should_stream() {
+        let start = std::time::Instant::now();
+        let mut lines = Vec::new();
+        let result = CommandSpec::new("sh")
+            .args(&["-c", "echo first; echo oops >&2; sleep 0.5; printf last"])
+            .log_output(true)
+            .execute_with_lines(|stream, line| {
+                lines.push((stream, line.to_string(), start.elapsed()));
+            });
+
+        let ExecuteCommandResult::Success(output) = result else {
+            panic!("{result:?}");
+        };
+        assert_eq!(output.stdout_lossy(), "first\nlast");
+        assert_eq!(output.stderr_lossy(), "oops\n");
+
+        let received = lines
+            .iter()
+            .map(|(stream, line, _)| (*stream, line.as_str()))
+            .collect::<Vec<_>>();
+        assert!(received.contains(&(OutputStream::Stdout, "first")));
+        assert!(received.contains(&(OutputStream::Stderr, "oops")));
+        assert_eq!(received.last(), Some(&(OutputStream::Stdout, "last")));
+
+        //
+        // The first line arrives before the command exits
+        //
+        assert!(lines[0].2 < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn check_should_map_errors() {
+        let error = CommandSpec::new("sh")
+            .args(&["-c", "echo out; echo bad >&2; exit 3"])
+            .execute()
+            .check()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            CommandError::NonZeroExit { exit_code: 3, .. }
+        ));
+        assert_eq!(error.stderr(), Some("bad"));
+        assert_eq!(
+            error.to_string(),
+            "`sh -c 'echo out; echo bad >&2; exit 3'` exited with code 3, stderr:\nbad"
+        );
+
+        let error = CommandSpec::new("sh")
+            .args(&["-c", "kill -9 $$"])
+            .execute()
+            .check()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            CommandError::Signaled {
+                signal: 9,
+                core_dumped: false,
+                ..
+            }
+        ));
+
+        let error = CommandSpec::new("no-such-program-xyz")
+            .env("PATH", "/usr/bin:/bin")
+            .execute()
+            .check()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Command not found: no-such-program-xyz (searched /usr/bin/no-such-program-xyz, /bin/no-such-program-xyz)"
+        );
+
+        let not_executable =
+            std::env::temp_dir().join(format!("rust_utils_not_exec_{}", std::process::id()));
+        std::fs::write(&not_executable, "#!/bin/sh\n").unwrap();
+        let error = CommandSpec::new(&not_executable.to_string_lossy())
+            .execute()
+            .check()
+            .unwrap_err();
+        assert!(matches!(error, CommandError::PermissionDenied { .. }));
+        let _ = std::fs::remove_file(&not_executable);
+
+        let error = CommandSpec::new("ls")
+            .cwd("/no/such/dir")
+            .execute()
+            .check()
+            .unwrap_err();
+        assert!(matches!(error, CommandError::Spawn { .. }));
+        assert!(std::error::Error::source(&error).is_some());
+
+        let error = CommandSpec::new("sleep")
+            .arg("5")
+            .timeout(Duration::from_millis(50))
+            .execute()
+            .check()
+            .unwrap_err();
+        assert!(matches!(error, CommandError::TimedOut { .. }));
+    }
+}
@@ -0,0 +1,104 @@
+//!
+//! Split the output chunks into lines for the streaming callbacks and the logger.
+//!
+use super::OutputStream;
+use crate::logger::{LogLevel, log};
+use crate::{info_log, warn_log};
+
+///
+/// Buffer the incomplete last line of each stream until its newline arrives
+///
+#[derive(Default)]
+pub(crate) struct LineSplitter {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl LineSplitter {
+    fn buffer_of(&mut self, stream: OutputStream) -> &mut Vec<u8> {
+        match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        }
+    }
+
+    ///
+    /// Hand every complete line in `data` to `on_line`, without the trailing `\n`
+    /// (or `\r\n`)
+    ///
+    pub(crate) fn push(
+        &mut self,
+        stream: OutputStream,
+        data: &[u8],
+        on_line: &mut dyn FnMut(OutputStream, &str),
+    ) {
+        let buffer = self.buffer_of(stream);
+        buffer.extend_from_slice(data);
+
+        let Some(last_newline) = buffer.iter().rposition(|b| *b == b'\n') else {
+            return;
+        };
+        let rest = buffer.split_off(last_newline + 1);
+        let complete = std::mem::replace(buffer, rest);
+
+        for line in complete[..last_newline].split(|b| *b == b'\n') {
+            on_line(stream, &line_to_str(line));
+        }
+    }
+
+    ///
+    /// Hand the last lines without trailing newline to `on_line`
+    ///
+    pub(crate) fn finish(&mut self, on_line: &mut dyn FnMut(OutputStream, &str)) {
+        for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+            let buffer = std::mem::take(self.buffer_of(stream));
+            if !buffer.is_empty() {
+                on_line(stream, &line_to_str(&buffer));
+            }
+        }
+    }
+}
+
+fn line_to_str(line: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+///
+/// Forward the output line to the logger: `INFO` for stdout and `WARN` for stderr,
+/// the command name is the module name.
+///
+pub(crate) fn log_output_line(program: &str, stream: OutputStream, line: &str) {
+    match stream {
+        OutputStream::Stdout => info_log!(program, "stdout", line),
+        OutputStream::Stderr => warn_log!(program, "stderr", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_splitter_should_work() {
+        let mut lines = Vec::new();
+        let mut on_line = |stream: OutputStream, line: &str| lines.push((stream, line.to_string()));
+        let mut splitter = LineSplitter::default();
+
+        splitter.push(OutputStream::Stdout, b"a\r\nb", &mut on_line);
+        splitter.push(OutputStream::Stderr, b"x", &mut on_line);
+        splitter.push(OutputStream::Stdout, b"c\n\nd", &mut on_line);
+        splitter.push(OutputStream::Stderr, b"y\n", &mut on_line);
+        splitter.finish(&mut on_line);
+
+        assert_eq!(
+            lines,
+            vec![
+                (OutputStream::Stdout, String::from("a")),
+                (OutputStream::Stdout, String::from("bc")),
+                (OutputStream::Stdout, String::from("")),
+                (OutputStream::Stderr, String::from("xy")),
+                (OutputStream::Stdout, String::from("d")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,518 @@
+//!
+//! Keep a long-running command (e.g. a helper daemon) alive in the background:
+//! restart it by the policy when it exits and forward its output to the logger.
+//!
+use super::exec::{RunningCommand, Termination};
+use super::stream::{LineSplitter, log_output_line};
+use super::{Backoff, CancellationToken, CommandSpec};
+use crate::logger::{LogLevel, log};
+use crate::{error_log, info_log, warn_log};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const LOG_MODULE_NAME: &str = "Supervisor";
+
+//
+// How often the stop request is checked during the backoff
+//
+const BACKOFF_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+//
+// The backoff starts over once the process has been running for this long
+//
+const HEALTHY_UPTIME: Duration = Duration::from_secs(10);
+
+///
+/// When to restart the process after it exits
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    Never,
+
+    ///
+    /// Restart unless it exited with 0, it's the default
+    ///
+    OnFailure,
+    Always,
+}
+
+///
+/// How the process exited the last time
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExitReason {
+    Code(i32),
+
+    ///
+    /// Terminated by the signal (unix only)
+    ///
+    Signal(i32),
+
+    ///
+    /// Failed to spawn or to wait for the process
+    ///
+    Error(String),
+}
+
+///
+/// What the supervisor is doing right now
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessState {
+    Starting,
+    Running {
+        pid: u32,
+    },
+
+    ///
+    /// Waiting for the backoff before the next restart
+    ///
+    BackingOff {
+        delay: Duration,
+    },
+
+    ///
+    /// Stopped by `SupervisedProcess::stop` or the spec's cancellation token
+    ///
+    Stopped,
+
+    ///
+    /// Exited and not restarted by the policy
+    ///
+    Exited,
+
+    ///
+    /// Restarted too many times within the window
+    ///
+    GaveUp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupervisorStatus {
+    pub state: ProcessState,
+    pub restarts: usize,
+    pub last_exit: Option<ExitReason>,
+}
+
+//
+// A thread panicking while it holds the status mustn't make every later `status()`
+// call panic too
+//
+fn lock_status(status: &Mutex<SupervisorStatus>) -> MutexGuard<'_, SupervisorStatus> {
+    status.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+///
+/// Supervisor builder.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{Backoff, CommandSpec, ProcessState, RestartPolicy, Supervisor};
+/// use std::time::Duration;
+///
+/// let spec = CommandSpec::new("sleep")
+///     .arg("30")
+///     .kill_grace_period(Duration::from_secs(1));
+///
+/// let daemon = Supervisor::new(spec)
+///     .restart_policy(RestartPolicy::Always)
+///     .max_restarts(5, Duration::from_secs(60))
+///     .backoff(Backoff::Exponential {
+///         initial: Duration::from_millis(100),
+///         max: Duration::from_secs(10),
+///     })
+///     .start();
+///
+/// // ... the main work
+///
+/// let status = daemon.stop();
+/// assert_eq!(status.state, ProcessState::Stopped);
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Supervisor {
+    spec: CommandSpec,
+    restart_policy: RestartPolicy,
+    max_restarts: Option<(usize, Duration)>,
+    backoff: Backoff,
+    log_output: bool,
+}
+
+impl Supervisor {
+    ///
+    /// The spec's `kill_grace_period` is how long `stop` waits after `SIGTERM` before
+    /// sending `SIGKILL`
+    ///
+    pub fn new(spec: CommandSpec) -> Self {
+        Self {
+            spec,
+            restart_policy: RestartPolicy::OnFailure,
+            max_restarts: None,
+            backoff: Backoff::Fixed(Duration::from_secs(1)),
+            log_output: true,
+        }
+    }
+
+    pub fn restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.restart_policy = policy;
+        self
+    }
+
+    ///
+    /// Give up after `count` restarts within `window`, it restarts forever by default
+    ///
+    pub fn max_restarts(mut self, count: usize, window: Duration) -> Self {
+        self.max_restarts = Some((count, window));
+        self
+    }
+
+    ///
+    /// The delay before every restart, it starts over once the process has been
+    /// running for 10 seconds. The default is a fixed 1s.
+    ///
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    ///
+    /// Forward every stdout line to `info_log!` and every stderr line to `warn_log!`,
+    /// it's enabled by default. The output isn't kept in memory either way.
+    ///
+    pub fn log_output(mut self, enable: bool) -> Self {
+        self.log_output = enable;
+        self
+    }
+
+    ///
+    /// Start the process in the background
+    ///
+    pub fn start(self) -> SupervisedProcess {
+        let token = self
+            .spec
+            .cancel_token
+            .as_ref()
+            .map_or_else(CancellationToken::new, |token| token.child_token());
+        let status = Arc::new(Mutex::new(SupervisorStatus {
+            state: ProcessState::Starting,
+            restarts: 0,
+            last_exit: None,
+        }));
+
+        let thread = {
+            let (token, status) = (token.clone(), status.clone());
+            let spec = self.spec.clone().cancel_token(&token);
+            std::thread::spawn(move || self.supervise(&spec, &token, &status))
+        };
+
+        SupervisedProcess {
+            status,
+            token,
+            thread: Some(thread),
+        }
+    }
+
+    fn supervise(
+        &self,
+        spec: &CommandSpec,
+        token: &CancellationToken,
+        status: &Mutex<SupervisorStatus>,
+    ) {
+        let set_state = |state| lock_status(status).state = state;
+        let cmd_desc = spec.cmd_desc();
+        let mut restart_times: VecDeque<Instant> = VecDeque::new();
+        let mut backoff_retry = 0;
+
+        loop {
+            let started_at = Instant::now();
+            let exit = self.run_once(spec, status);
+            if token.is_cancelled() {
+                info_log!(
+                    LOG_MODULE_NAME,
+                    "supervise",
+                    &format!("Stopped `{cmd_desc}`")
+                );
+                set_state(ProcessState::Stopped);
+                return;
+            }
+
+            let failed = exit != ExitReason::Code(0);
+            let message = format!("`{cmd_desc}` exited: {exit:?}");
+            if failed {
+                warn_log!(LOG_MODULE_NAME, "supervise", &message);
+            } else {
+                info_log!(LOG_MODULE_NAME, "supervise", &message);
+            }
+            lock_status(status).last_exit = Some(exit);
+
+            let restart = match self.restart_policy {
+                RestartPolicy::Never => false,
+                RestartPolicy::OnFailure => failed,
+                RestartPolicy::Always => true,
+            };
+            if !restart {
+                set_state(ProcessState::Exited);
+                return;
+            }
+
+            if let Some((max_restarts, window)) = self.max_restarts {
+                while restart_times.front().is_some_and(|t| t.elapsed() > window) {
+                    restart_times.pop_front();
+                }
+                if restart_times.len() >= max_restarts {
+                    error_log!(
+                        LOG_MODULE_NAME,
+                        "supervise",
+                        &format!(
+                            "Gave up `{cmd_desc}` after {max_restarts} restarts within {window:?}"
+                        )
+                    );
+                    set_state(ProcessState::GaveUp);
+                    return;
+                }
+            }
+
+            backoff_retry = if started_at.elapsed() >= HEALTHY_UPTIME {
+                1
+            } else {
+                backoff_retry + 1
+            };
+            let delay = self.backoff.delay(backoff_retry);
+            info_log!(
+                LOG_MODULE_NAME,
+                "supervise",
+                &format!("Restarting `{cmd_desc}` in {delay:?}")
+            );
+            set_state(ProcessState::BackingOff { delay });
+
+            let deadline = Instant::now() + delay;
+            while Instant::now() < deadline {
+                if token.is_cancelled() {
+                    set_state(ProcessState::Stopped);
+                    return;
+                }
+                std::thread::sleep(BACKOFF_POLL_INTERVAL.min(deadline - Instant::now()));
+            }
+
+            restart_times.push_back(Instant::now());
+            lock_status(status).restarts += 1;
+        }
+    }
+
+    //
+    // Run the process once until it exits or it's stopped, the output is only logged
+    //
+    fn run_once(&self, spec: &CommandSpec, status: &Mutex<SupervisorStatus>) -> ExitReason {
+        let mut running = match RunningCommand::spawn(spec) {
+            Ok(running) => running,
+            Err(error) => return ExitReason::Error(error.to_string()),
+        };
+        let pid = running.child.id();
+        info_log!(
+            LOG_MODULE_NAME,
+            "supervise",
+            &format!("Started `{}` (pid {pid})", spec.cmd_desc())
+        );
+        lock_status(status).state = ProcessState::Running { pid };
+
+        let mut splitter = self.log_output.then(LineSplitter::default);
+        let module_name = spec.program_name();
+        let mut log_line = |stream, line: &str| log_output_line(module_name, stream, line);
+        let result = running.wait(spec, &mut |stream, data| {
+            if let Some(splitter) = splitter.as_mut() {
+                splitter.push(stream, data, &mut log_line);
+            }
+        });
+        if let Some(splitter) = splitter.as_mut() {
+            splitter.finish(&mut log_line);
+        }
+
+        match result {
+            Ok((status, termination)) => {
+                #[cfg(unix)]
+                let signal = {
+                    use std::os::unix::process::ExitStatusExt;
+                    status.signal()
+                };
+                #[cfg(not(unix))]
+                let signal = None;
+
+                match (status.code(), signal, termination) {
+                    (Some(code), _, _) => ExitReason::Code(code),
+                    (None, Some(signal), _) => ExitReason::Signal(signal),
+                    (None, None, Some(Termination::TimedOut)) => {
+                        ExitReason::Error(String::from("timed out"))
+                    }
+                    (None, None, _) => ExitReason::Error(String::from("terminated")),
+                }
+            }
+            Err(error) => ExitReason::Error(error.to_string()),
+        }
+    }
+}
+
+///
+/// The handle of a supervised process, dropping it stops the process
+///
+#[derive(Debug)]
+pub struct SupervisedProcess {
+    status: Arc<Mutex<SupervisorStatus>>,
+    token: CancellationToken,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SupervisedProcess {
+    pub fn status(&self) -> SupervisorStatus {
+        lock_status(&self.status).clone()
+    }
+
+    ///
+    /// The pid of the running process
+    ///
+    pub fn pid(&self) -> Option<u32> {
+        match lock_status(&self.status).state {
+            ProcessState::Running { pid } => Some(pid),
+            _ => None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.pid().is_some()
+    }
+
+    ///
+    /// Stop the process gracefully: `SIGTERM` first, then `SIGKILL` after the spec's
+    /// `kill_grace_period`, and return the final status
+    ///
+    pub fn stop(mut self) -> SupervisorStatus {
+        self.token.cancel();
+        self.join();
+        self.status()
+    }
+
+    ///
+    /// Wait until the supervisor is done, i.e. the process exits and the policy says
+    /// no restart, or it gives up
+    ///
+    pub fn wait(mut self) -> SupervisorStatus {
+        self.join();
+        self.status()
+    }
+
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SupervisedProcess {
+    fn drop(&mut self) {
+        self.token.cancel();
+        self.join();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn wait_for_running(process: &SupervisedProcess) -> u32 {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if let Some(pid) = process.pid() {
+                return pid;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{:?}", process.status());
+    }
+
+    #[test]
+    fn on_failure_should_give_up_after_max_restarts() {
+        let status = Supervisor::new(CommandSpec::new("sh").args(&["-c", "echo oops >&2; exit 3"]))
+            .max_restarts(2, Duration::from_secs(60))
+            .backoff(Backoff::Fixed(Duration::from_millis(10)))
+            .start()
+            .wait();
+        assert_eq!(status.state, ProcessState::GaveUp);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_exit, Some(ExitReason::Code(3)));
+
+        let status = Supervisor::new(CommandSpec::new("true")).start().wait();
+        assert_eq!(status.state, ProcessState::Exited);
+        assert_eq!(status.restarts, 0);
+
+        let status = Supervisor::new(CommandSpec::new("no-such-program-xyz"))
+            .restart_policy(RestartPolicy::Never)
+            .start()
+            .wait();
+        assert!(matches!(status.last_exit, Some(ExitReason::Error(_))));
+    }
+
+    #[test]
+    fn always_should_restart_after_success() {
+        let process = Supervisor::new(CommandSpec::new("sh").args(&["-c", "sleep 0.05"]))
+            .restart_policy(RestartPolicy::Always)
+            .backoff(Backoff::Fixed(Duration::from_millis(10)))
+            .log_output(false)
+            .start();
+
+        let start = Instant::now();
+        while process.status().restarts < 3 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let status = process.stop();
+        assert_eq!(status.state, ProcessState::Stopped);
+        assert_eq!(status.last_exit, Some(ExitReason::Code(0)));
+    }
+
+    #[test]
+    fn stop_should_escalate_to_sigkill() {
+        let spec = CommandSpec::new("sh")
+            .args(&[
+                "-c",
+                "trap '' TERM; echo ready; while :; do sleep 0.1; done",
+            ])
+            .kill_grace_period(Duration::from_millis(200))
+            .kill_process_group(true);
+        let process = Supervisor::new(spec).start();
+        let pid = wait_for_running(&process);
+
+        let start = Instant::now();
+        let status = process.stop();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(status.state, ProcessState::Stopped);
+        assert_eq!(unsafe { libc::kill(pid as libc::pid_t, 0) }, -1);
+    }
+
+    #[test]
+    fn stop_should_work_with_chatty_child() {
+        let process = Supervisor::new(CommandSpec::new("yes"))
+            .log_output(false)
+            .start();
+        wait_for_running(&process);
+        std::thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        let status = process.stop();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(status.state, ProcessState::Stopped);
+
+        //
+        // Dropping without `stop` must not hang either
+        //
+        let process = Supervisor::new(CommandSpec::new("yes"))
+            .log_output(false)
+            .start();
+        wait_for_running(&process);
+        let start = Instant::now();
+        drop(process);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
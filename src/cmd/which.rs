@@ -0,0 +1,196 @@
+//!
+//! Find the executable the way the OS would when spawning it, and report every
+//! location tried when it can't be found.
+//!
+use super::CommandError;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+///
+/// Find the program in `PATH`, same as the `which` command. A program with a path
+/// separator (e.g. `./build.sh`) isn't searched but only checked.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::cmd::{CommandError, which};
+///
+/// assert!(which("sh").unwrap().is_absolute());
+///
+/// let error = which("rust_utils_no_such_program").unwrap_err();
+/// assert!(matches!(error, CommandError::NotFound { ref searched, .. } if !searched.is_empty()));
+/// ```
+///
+pub fn which(program: &str) -> Result<PathBuf, CommandError> {
+    resolve(program, std::env::var_os("PATH").as_deref(), None)
+}
+
+///
+/// Same as `which` but search the given path list instead of `PATH`, e.g.
+/// `/usr/local/bin:/usr/bin`
+///
+pub fn which_in<S: AsRef<OsStr>>(program: &str, search_path: S) -> Result<PathBuf, CommandError> {
+    resolve(program, Some(search_path.as_ref()), None)
+}
+
+//
+// A program with a path separator is relative to `cwd` (the child's working
+// directory), otherwise it's searched in `search_path`. Found but non-executable
+// files are skipped, they're only reported if nothing executable is found.
+//
+pub(crate) fn resolve(
+    program: &str,
+    search_path: Option<&OsStr>,
+    cwd: Option<&Path>,
+) -> Result<PathBuf, CommandError> {
+    if program.is_empty() {
+        return Err(CommandError::InvalidCommand {
+            reason: String::from("the program is empty"),
+        });
+    }
+
+    let directories = if has_separator(program) {
+        vec![cwd.map_or_else(PathBuf::new, Path::to_path_buf)]
+    } else {
+        search_path
+            .map(|paths| {
+                std::env::split_paths(paths)
+                    //
+                    // An empty entry means the current directory
+                    //
+                    .map(|dir| match dir.as_os_str().is_empty() {
+                        true => PathBuf::from("."),
+                        false => dir,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let mut searched = Vec::new();
+    let mut not_executable = None;
+    for candidate in directories
+        .iter()
+        .flat_map(|dir| candidates(&dir.join(program)))
+    {
+        if candidate.is_file() {
+            if is_executable(&candidate) {
+                return Ok(candidate);
+            }
+            not_executable.get_or_insert_with(|| candidate.clone());
+        }
+        searched.push(candidate);
+    }
+
+    match not_executable {
+        Some(path) => Err(CommandError::PermissionDenied {
+            program: path.display().to_string(),
+        }),
+        None => Err(CommandError::NotFound {
+            program: program.to_string(),
+            searched,
+        }),
+    }
+}
+
+fn has_separator(program: &str) -> bool {
+    program.contains('/') || (cfg!(windows) && program.contains('\\'))
+}
+
+#[cfg(unix)]
+fn candidates(path: &Path) -> Vec<PathBuf> {
+    vec![path.to_path_buf()]
+}
+
+//
+// Without an extension, try the ones in `PATHEXT` as `cmd.exe` does
+//
+#[cfg(not(unix))]
+fn candidates(path: &Path) -> Vec<PathBuf> {
+    if path.extension().is_some() {
+        return vec![path.to_path_buf()];
+    }
+
+    let extensions =
+        std::env::var("PATHEXT").unwrap_or_else(|_| String::from(".COM;.EXE;.BAT;.CMD"));
+    std::iter::once(path.to_path_buf())
+        .chain(
+            extensions
+                .split(';')
+                .filter(|ext| !ext.is_empty())
+                .map(|ext| {
+                    let mut candidate = path.as_os_str().to_os_string();
+                    candidate.push(ext);
+                    PathBuf::from(candidate)
+                }),
+        )
+        .collect()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    //
+    // `access` also takes the current user, ACLs and read-only mounts into account,
+    // the mode bits alone don't
+    //
+    let mut bytes = path.as_os_str().as_bytes().to_vec();
+    bytes.push(0);
+    unsafe { libc::access(bytes.as_ptr() as *const libc::c_char, libc::X_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn which_should_search_in_order() {
+        let dir =
+            std::env::temp_dir().join(format!("rust_utils_which_test_{}", std::process::id()));
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+
+        let write_script = |path: &Path, mode: u32| {
+            std::fs::write(path, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+        };
+        write_script(&first.join("tool"), 0o644);
+        write_script(&second.join("tool"), 0o755);
+        write_script(&first.join("data"), 0o644);
+
+        let search_path = std::env::join_paths([&first, &second]).unwrap();
+        assert_eq!(which_in("tool", &search_path).unwrap(), second.join("tool"));
+
+        let error = which_in("data", &search_path).unwrap_err();
+        assert!(
+            matches!(error, CommandError::PermissionDenied { ref program } if *program == first.join("data").display().to_string()),
+            "{error:?}"
+        );
+
+        let error = which_in("missing", &search_path).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Command not found: missing (searched {}, {})",
+                first.join("missing").display(),
+                second.join("missing").display()
+            )
+        );
+
+        assert_eq!(
+            resolve("./second/tool", None, Some(&dir)).unwrap(),
+            dir.join("./second/tool")
+        );
+        assert!(resolve("./tool", None, Some(&dir)).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+//!
+//! Minimal JSON helpers used by the structured log formats.
+//!
+use std::fmt::Write;
+
+///
+/// Quote and escape the given string as a JSON string literal
+///
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+///
+/// A parsed JSON value, object keys keep their original order
+///
+#[derive(Debug, PartialEq, Clone)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The plain text of a scalar value, e.g. `"abc"` -> `abc`, `1.5` -> `1.5`
+    ///
+    pub fn to_plain_string(&self) -> String {
+        match self {
+            Self::Null => String::from("null"),
+            Self::Bool(b) => b.to_string(),
+            Self::Number(n) => n.to_string(),
+            Self::String(s) => s.clone(),
+            _ => self.to_json(),
+        }
+    }
+
+    ///
+    /// Serialize back into a compact JSON string
+    ///
+    pub fn to_json(&self) -> String {
+        match self {
+            Self::Null => String::from("null"),
+            Self::Bool(b) => b.to_string(),
+            Self::Number(n) => n.to_string(),
+            Self::String(s) => quote(s),
+            Self::Array(values) => format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|v| v.to_json())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Self::Object(entries) => format!(
+                "{{{}}}",
+                entries
+                    .iter()
+                    .map(|(k, v)| format!("{}:{}", quote(k), v.to_json()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+///
+/// Parse the given JSON text
+///
+pub fn parse(text: &str) -> Result<JsonValue, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+    };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.chars.len() {
+        return Err(format!("unexpected trailing characters at {}", parser.pos));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{c}' at {}", self.pos))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str, value: JsonValue) -> Result<JsonValue, String> {
+        for c in keyword.chars() {
+            self.expect(c)?;
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => Ok(JsonValue::String(self.parse_string()?)),
+            Some('t') => self.expect_keyword("true", JsonValue::Bool(true)),
+            Some('f') => self.expect_keyword("false", JsonValue::Bool(false)),
+            Some('n') => self.expect_keyword("null", JsonValue::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            _ => Err(format!("unexpected character at {}", self.pos)),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue, String> {
+        self.expect('{')?;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(JsonValue::Object(entries));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            entries.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(entries));
+                }
+                _ => return Err(format!("expected ',' or '}}' at {}", self.pos)),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(JsonValue::Array(values));
+        }
+
+        loop {
+            values.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(values));
+                }
+                _ => return Err(format!("expected ',' or ']' at {}", self.pos)),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let hex = self
+            .chars
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| format!("invalid unicode escape at {}", self.pos))?
+            .iter()
+            .collect::<String>();
+        self.pos += 4;
+        u32::from_str_radix(&hex, 16).map_err(|_| format!("invalid unicode escape: {hex}"))
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            let c = self.peek().ok_or("unterminated string")?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = self.peek().ok_or("unterminated string")?;
+                    self.pos += 1;
+                    match escaped {
+                        '"' => s.push('"'),
+                        '\\' => s.push('\\'),
+                        '/' => s.push('/'),
+                        'b' => s.push('\u{08}'),
+                        'f' => s.push('\u{0c}'),
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'u' => {
+                            let mut code = self.parse_hex4()?;
+                            //
+                            // Surrogate pair
+                            //
+                            if (0xD800..0xDC00).contains(&code)
+                                && self.chars.get(self.pos..self.pos + 2) == Some(&['\\', 'u'])
+                            {
+                                self.pos += 2;
+                                let low = self.parse_hex4()?;
+                                code = 0x10000
+                                    + ((code - 0xD800) << 10)
+                                    + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            s.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        _ => return Err(format!("invalid escape at {}", self.pos)),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, String> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E') {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text = self.chars[start..self.pos].iter().collect::<String>();
+        text.parse::<f64>()
+            .map(JsonValue::Number)
+            .map_err(|_| format!("invalid number: {text}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_should_work() {
+        assert_eq!(quote("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+
+    #[test]
+    fn parse_should_work() {
+        let value = parse(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "x\"\né😀"}} "#).unwrap();
+        assert_eq!(
+            value,
+            JsonValue::Object(vec![
+                (
+                    String::from("a"),
+                    JsonValue::Array(vec![
+                        JsonValue::Number(1.0),
+                        JsonValue::Number(-25.0),
+                        JsonValue::Bool(true),
+                        JsonValue::Null,
+                    ])
+                ),
+                (
+                    String::from("b"),
+                    JsonValue::Object(vec![(
+                        String::from("c"),
+                        JsonValue::String(String::from("x\"\né😀"))
+                    )])
+                ),
+            ])
+        );
+        assert_eq!(parse(&value.to_json()).unwrap(), value);
+
+        assert!(parse(r#"{"a": 1"#).is_err());
+        assert!(parse(r#"{"a": 1} x"#).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 pub mod bits;
 pub mod cmd;
 pub mod hex;
+mod json;
 pub mod logger;
 pub mod memory;
 
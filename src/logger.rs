@@ -85,8 +85,96 @@
 //!
 //! After re-compiling, all `debug_log!` macros expand to an empty block `{}`.
 //!
+//!
+//! ## 3. Log sinks
+//!
+//! Besides the console output, every printed log record is handed to all sinks added by
+//! `add_sink`. A sink decides where the record goes, e.g. `FileSink` appends it to a log
+//! file in one of the `LogFormat` formats:
+//!
+//! ```rust
+//! use rust_utils::logger::{self, FileSink, LogFormat};
+//!
+//! let log_file = std::env::temp_dir().join("rust_utils_doc_example.log");
+//! logger::add_sink(FileSink::new(&log_file, LogFormat::Text).unwrap());
+//! ```
+//!
+//! Buffered sinks only hit the disk when they're full or when `logger::flush()` is called.
+//!
+//! `TcpSink` and `UdpSink` ship newline-delimited records to a remote collector, they
+//! reconnect automatically and keep records in a bounded buffer while disconnected.
+//! `GelfSink` (Graylog GELF over UDP/TCP) and `OtlpSink` (OpenTelemetry OTLP/HTTP JSON)
+//! export records to the observability backends.
+//!
+//!
+//! ## 4. Panic hook
+//!
+//! Call `logger::install_panic_hook()` at the beginning of `main` to route all panics
+//! (including the ones in background threads) through the logger. The panic message,
+//! location, thread name and backtrace are printed as an `ERROR` log and written to all
+//! sinks, then all sinks are flushed before the process unwinds or aborts:
+//!
+//! ```bash
+//! (E) [ Panic - worker-1 ] thread 'worker-1' panicked at src/main.rs:12:9:
+//! something went wrong
+//! stack backtrace:
+//!    0: ...
+//! ```
+//!
+//!
+//! ## 5. Time log
+//!
+//! `time_log!` measures how long a block (or the rest of the current scope) takes and
+//! logs it at the given level on exit:
+//!
+//! ```rust
+//! use rust_utils::time_log;
+//! use std::time::Duration;
+//!
+//! const LOGGER_PREFIX: &'static str = "TempMain";
+//!
+//! // Time a block, the macro returns the block value
+//! let sum = time_log!(DEBUG, LOGGER_PREFIX, "main", "sum" => {
+//!     (1..=100).sum::<u32>()
+//! });
+//!
+//! // Time the rest of the scope: only log when it's slower than 5ms,
+//! // and log it as `WARN` when it's slower than 100ms
+//! let _guard = time_log!(
+//!     INFO, LOGGER_PREFIX, "main", "load config",
+//!     min = Duration::from_millis(5),
+//!     warn = Duration::from_millis(100)
+//! );
+//! ```
+//!
+//! Example output:
+//!
+//! ```bash
+//! (D) [ TempMain - main ] sum took 1.2µs
+//! (W) [ TempMain - main ] load config took 150.3ms (slower than 100ms)
+//! ```
+//!
+//! Just like `debug_log!`, `time_log!(DEBUG, ...)` expands to nothing (the block is still
+//! executed) when the `DISABLE_DEBUG_LOG` feature is enabled.
+//!
+mod gelf;
+mod network;
+mod otlp;
+pub mod reader;
+
+pub use gelf::{GelfCompression, GelfSink, to_gelf_payload};
+pub use network::{TcpSink, UdpSink};
+pub use otlp::{OtlpSink, to_otlp_payload};
+
+use crate::json;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
 use std::env;
-use std::sync::OnceLock;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 //
 // ANSI color escape contants
@@ -101,6 +189,24 @@ const LOG_COLOR_RESET: &'static str = "\x1b[0m";
 //
 static ENV_LOG_LEVEL: OnceLock<LogLevel> = OnceLock::new();
 
+//
+// All sinks added by `add_sink`
+//
+static LOG_SINKS: Mutex<Vec<Box<dyn LogSink>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    //
+    // Set while this thread holds `LOG_SINKS`, so the panic hook can tell a sink
+    // panicking on this thread from another thread logging at the same time
+    //
+    static IN_SINKS: Cell<bool> = const { Cell::new(false) };
+}
+
+//
+// The module name used by the panic hook
+//
+const PANIC_LOGGER_NAME: &str = "Panic";
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum LogLevel {
     DEBUG = 1,
@@ -146,18 +252,480 @@ impl LogLevel {
             Self::ERROR => "(E)",
         }
     }
+
+    ///
+    /// The level name used by the structured log formats, e.g. `"DEBUG"`
+    ///
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::DEBUG => "DEBUG",
+            Self::INFO => "INFO",
+            Self::WARN => "WARN",
+            Self::ERROR => "ERROR",
+        }
+    }
+
+    ///
+    /// Get back the level from the name (case insensitive) or the prefix, e.g.
+    /// `"DEBUG"`, `"debug"` and `"(D)"`
+    ///
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_uppercase().as_str() {
+            "DEBUG" | "(D)" => Some(Self::DEBUG),
+            "INFO" | "(I)" => Some(Self::INFO),
+            "WARN" | "(W)" => Some(Self::WARN),
+            "ERROR" | "(E)" => Some(Self::ERROR),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Log record that hands to all sinks
+///
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub timestamp: SystemTime,
+    pub level: LogLevel,
+    pub module_name: String,
+    pub function_name: String,
+    pub message: String,
+
+    ///
+    /// Structured key/value pairs, see `log_with_fields`
+    ///
+    pub fields: Vec<(String, String)>,
+
+    ///
+    /// The span the record was logged in, see `SpanContext::enter`
+    ///
+    pub span: Option<SpanContext>,
+}
+
+///
+/// W3C trace context of the current span: a 32 hex digits trace id and a 16 hex
+/// digits span id (lowercase). Sinks that understand tracing (GELF, OTLP) attach it
+/// to every record logged while it's entered.
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::logger::SpanContext;
+///
+/// let span = SpanContext::new("4bf92f3577b34da6a3ce929d0e0e4736", "00f067aa0ba902b7").unwrap();
+/// let _guard = span.enter();
+/// // records logged on this thread carry the span until `_guard` is dropped
+///
+/// assert!(SpanContext::new("not-hex", "00f067aa0ba902b7").is_none());
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+thread_local! {
+    static CURRENT_SPAN: RefCell<Option<SpanContext>> = const { RefCell::new(None) };
+}
+
+impl SpanContext {
+    ///
+    /// `None` if the ids aren't hex of the right length or are all zeros (invalid
+    /// in W3C trace context)
+    ///
+    pub fn new(trace_id: &str, span_id: &str) -> Option<Self> {
+        let is_valid = |id: &str, len: usize| {
+            id.len() == len
+                && id.bytes().all(|b| b.is_ascii_hexdigit())
+                && id.bytes().any(|b| b != b'0')
+        };
+        if !is_valid(trace_id, 32) || !is_valid(span_id, 16) {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_ascii_lowercase(),
+            span_id: span_id.to_ascii_lowercase(),
+        })
+    }
+
+    ///
+    /// Make it the current span of this thread until the guard is dropped, the
+    /// previous one is restored then
+    ///
+    pub fn enter(self) -> SpanGuard {
+        let previous = CURRENT_SPAN.with(|span| span.replace(Some(self)));
+        SpanGuard { previous }
+    }
+
+    ///
+    /// The span entered on this thread, if any
+    ///
+    pub fn current() -> Option<Self> {
+        CURRENT_SPAN.with(|span| span.borrow().clone())
+    }
+}
+
+///
+/// Returned by `SpanContext::enter`
+///
+pub struct SpanGuard {
+    previous: Option<SpanContext>,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_SPAN.with(|span| *span.borrow_mut() = previous);
+    }
+}
+
+///
+/// The format a sink uses to write a `LogRecord`
+///
+/// - `Text`: `2026-10-19T08:30:00.123Z (I) [ module - function ] message`
+/// - `Json`: `{"timestamp":"2026-10-19T08:30:00.123Z","level":"INFO","module":"module","function":"function","message":"message"}`
+///
+/// Multi-line messages keep their newlines in `Text` format, and they're escaped in
+/// `Json` format (one record per line). Only the `Json` format writes the record's
+/// fields (a field named like a built-in key becomes `field_<key>`) and its span as
+/// `trace_id` and `span_id`.
+///
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogRecord {
+    ///
+    /// Format the record into a single string without the trailing newline
+    ///
+    pub fn format(&self, format: LogFormat) -> String {
+        let timestamp = format_timestamp(self.timestamp);
+        match format {
+            LogFormat::Text => format!(
+                "{timestamp} {} [ {} - {} ] {}",
+                self.level.get_logger_prefix(),
+                self.module_name,
+                self.function_name,
+                self.message
+            ),
+            LogFormat::Json => {
+                let mut line = format!(
+                    "{{\"timestamp\":{},\"level\":{},\"module\":{},\"function\":{},\"message\":{}",
+                    json::quote(&timestamp),
+                    json::quote(self.level.get_name()),
+                    json::quote(&self.module_name),
+                    json::quote(&self.function_name),
+                    json::quote(&self.message)
+                );
+                for (key, value) in &self.fields {
+                    line.push_str(&format!(
+                        ",{}:{}",
+                        json::quote(&json_field_name(key)),
+                        json::quote(value)
+                    ));
+                }
+                if let Some(span) = &self.span {
+                    line.push_str(&format!(
+                        ",\"trace_id\":{},\"span_id\":{}",
+                        json::quote(&span.trace_id),
+                        json::quote(&span.span_id)
+                    ));
+                }
+                line.push('}');
+                line
+            }
+        }
+    }
+}
+
+//
+// A field named like a built-in key is written as `field_<key>`, so it can't
+// overwrite it
+//
+fn json_field_name(key: &str) -> String {
+    match key {
+        "timestamp" | "level" | "module" | "function" | "message" | "trace_id" | "span_id" => {
+            format!("field_{key}")
+        }
+        _ => key.to_string(),
+    }
+}
+
+///
+/// Format the given time as an RFC 3339 UTC timestamp with milliseconds,
+/// e.g. `2026-10-19T08:30:00.123Z`
+///
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    //
+    // Convert days since epoch into the civil date (Howard Hinnant's algorithm)
+    //
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+///
+/// Parse an RFC 3339 UTC timestamp that `format_timestamp` produces. The time part and
+/// the milliseconds are optional, e.g. `2026-10-19`, `2026-10-19T08:30` and
+/// `2026-10-19T08:30:00.123Z` are all valid.
+///
+pub fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let timestamp = timestamp.trim().trim_end_matches('Z');
+    let (date, time) = match timestamp.split_once(['T', ' ']) {
+        Some((date, time)) => (date, time),
+        None => (timestamp, ""),
+    };
+
+    let mut date_parts = date.splitn(3, '-').map(|v| v.parse::<i64>().ok());
+    let year = date_parts.next()??;
+    let month = date_parts.next()??;
+    let day = date_parts.next()??;
+    let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if is_leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    //
+    // Only the first 3 digits of the fraction count, e.g. `.5` is 500ms and
+    // `.123456` is 123ms
+    //
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) => {
+            if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let digits = fraction.get(..3).unwrap_or(fraction);
+            (time, format!("{digits:0<3}").parse::<u64>().ok()?)
+        }
+        None => (time, 0),
+    };
+    let mut time_parts = time.split(':').filter(|v| !v.is_empty());
+    let hour = time_parts
+        .next()
+        .map_or(Some(0), |v| v.parse::<u64>().ok())?;
+    let minute = time_parts
+        .next()
+        .map_or(Some(0), |v| v.parse::<u64>().ok())?;
+    let second = time_parts
+        .next()
+        .map_or(Some(0), |v| v.parse::<u64>().ok())?;
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    //
+    // Convert the civil date into days since epoch (Howard Hinnant's algorithm)
+    //
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = u64::try_from(era * 146097 + doe - 719468).ok()?;
+
+    Some(
+        UNIX_EPOCH
+            + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second)
+            + Duration::from_millis(millis),
+    )
+}
+
+///
+/// A log sink receives every printed log record.
+///
+pub trait LogSink: Send {
+    fn write(&mut self, record: &LogRecord);
+
+    ///
+    /// Buffered sinks should write all buffered records out.
+    ///
+    fn flush(&mut self) {}
+}
+
+///
+/// Append log records to a file, it's buffered until `logger::flush()` is called.
+///
+pub struct FileSink {
+    writer: BufWriter<File>,
+    format: LogFormat,
+}
+
+impl FileSink {
+    pub fn new<P: AsRef<Path>>(path: P, format: LogFormat) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            format,
+        })
+    }
+}
+
+impl LogSink for FileSink {
+    fn write(&mut self, record: &LogRecord) {
+        let _ = writeln!(self.writer, "{}", record.format(self.format));
+    }
+
+    fn flush(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+///
+/// Add a sink, all log records printed after that will be written to it as well.
+///
+pub fn add_sink<S: LogSink + 'static>(sink: S) {
+    LOG_SINKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Box::new(sink));
+}
+
+///
+/// Flush all sinks and the console output.
+///
+pub fn flush() {
+    with_sinks(|sinks| {
+        for sink in sinks.iter_mut() {
+            sink.flush();
+        }
+    });
+
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+}
+
+//
+// Run `f` with the locked sinks, `IN_SINKS` is set meanwhile (and reset on unwinding)
+//
+fn with_sinks(f: impl FnOnce(&mut Vec<Box<dyn LogSink>>)) {
+    struct InSinksGuard;
+
+    impl Drop for InSinksGuard {
+        fn drop(&mut self) {
+            IN_SINKS.with(|in_sinks| in_sinks.set(false));
+        }
+    }
+
+    let mut sinks = LOG_SINKS.lock().unwrap_or_else(PoisonError::into_inner);
+    IN_SINKS.with(|in_sinks| in_sinks.set(true));
+    let _guard = InSinksGuard;
+    f(&mut sinks);
+}
+
+//
+// Write the record to all sinks. When it's called by the panic hook on a thread
+// which holds the sinks already (a sink panicked), skip them instead of
+// deadlocking. Another thread holding them is waited for.
+//
+fn write_to_sinks(record: &LogRecord, from_panic_hook: bool) {
+    if from_panic_hook && IN_SINKS.with(Cell::get) {
+        return;
+    }
+
+    with_sinks(|sinks| {
+        for sink in sinks.iter_mut() {
+            sink.write(record);
+            if from_panic_hook {
+                sink.flush();
+            }
+        }
+    });
 }
 
 ///
 ///
 ///
 pub fn log(log_level_to_check: LogLevel, module_name: &str, function_name: &str, message: &str) {
+    log_with_fields(log_level_to_check, module_name, function_name, message, &[]);
+}
+
+///
+/// Same as `log` with structured key/value pairs, the console only shows the message
+/// but sinks get the fields too (e.g. `Json` keys, GELF `_key` additional fields and
+/// OTLP attributes).
+///
+/// Example:
+///
+/// ```rust
+/// use rust_utils::logger::{LogLevel, log_with_fields};
+///
+/// log_with_fields(
+///     LogLevel::INFO,
+///     "Upload",
+///     "main",
+///     "file uploaded",
+///     &[("bucket", "images"), ("size", &1024.to_string())],
+/// );
+/// ```
+///
+pub fn log_with_fields(
+    log_level_to_check: LogLevel,
+    module_name: &str,
+    function_name: &str,
+    message: &str,
+    fields: &[(&str, &str)],
+) {
     let enable_log = *LogLevel::get_config_from_env() as u8 <= log_level_to_check as u8;
 
     if !enable_log {
         return;
     }
 
+    print_to_console(log_level_to_check, module_name, function_name, message);
+
+    write_to_sinks(
+        &LogRecord {
+            timestamp: SystemTime::now(),
+            level: log_level_to_check,
+            module_name: module_name.to_string(),
+            function_name: function_name.to_string(),
+            message: message.to_string(),
+            fields: fields
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            span: SpanContext::current(),
+        },
+        false,
+    );
+}
+
+fn print_to_console(
+    log_level_to_check: LogLevel,
+    module_name: &str,
+    function_name: &str,
+    message: &str,
+) {
     if LogLevel::is_logger_disable_color() {
         eprintln!(
             "{} [ {module_name} - {function_name} ] {message}",
@@ -178,6 +746,137 @@ pub fn log(log_level_to_check: LogLevel, module_name: &str, function_name: &str,
     }
 }
 
+///
+/// Replace the default panic hook, so that all panics are printed as an `ERROR` log
+/// (with the thread name, location and backtrace) and written to all sinks. All sinks
+/// are flushed before the process unwinds or aborts. A panic inside a sink is only
+/// printed, the sinks are skipped then.
+///
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|panic_info| {
+        let thread = std::thread::current();
+        let thread_name = thread.name().unwrap_or("<unnamed>");
+
+        let payload = panic_info.payload();
+        let panic_message = match payload.downcast_ref::<&str>() {
+            Some(s) => *s,
+            None => match payload.downcast_ref::<String>() {
+                Some(s) => s.as_str(),
+                None => "Box<dyn Any>",
+            },
+        };
+
+        let location = match panic_info.location() {
+            Some(l) => format!("{}:{}:{}", l.file(), l.line(), l.column()),
+            None => String::from("<unknown>"),
+        };
+
+        let message = format!(
+            "thread '{thread_name}' panicked at {location}:\n{panic_message}\nstack backtrace:\n{}",
+            Backtrace::force_capture()
+        );
+
+        print_to_console(LogLevel::ERROR, PANIC_LOGGER_NAME, thread_name, &message);
+        write_to_sinks(
+            &LogRecord {
+                timestamp: SystemTime::now(),
+                level: LogLevel::ERROR,
+                module_name: PANIC_LOGGER_NAME.to_string(),
+                function_name: thread_name.to_string(),
+                message,
+                fields: Vec::new(),
+                span: SpanContext::current(),
+            },
+            true,
+        );
+
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+    }));
+}
+
+///
+/// Log how long it takes since the guard was created when it's dropped, it's what
+/// `time_log!` expands to.
+///
+pub struct TimeLogGuard {
+    level: LogLevel,
+    module_name: String,
+    function_name: String,
+    label: String,
+    start: Instant,
+    min_duration: Option<Duration>,
+    warn_above: Option<Duration>,
+}
+
+impl TimeLogGuard {
+    pub fn new(level: LogLevel, module_name: &str, function_name: &str, label: &str) -> Self {
+        Self {
+            level,
+            module_name: module_name.to_string(),
+            function_name: function_name.to_string(),
+            label: label.to_string(),
+            start: Instant::now(),
+            min_duration: None,
+            warn_above: None,
+        }
+    }
+
+    ///
+    /// Only log when the elapsed duration is at least `min_duration`
+    ///
+    pub fn min_duration(mut self, min_duration: Duration) -> Self {
+        self.min_duration = Some(min_duration);
+        self
+    }
+
+    ///
+    /// Log as `WARN` (unless the level is already higher) when the elapsed
+    /// duration is longer than `warn_above`
+    ///
+    pub fn warn_above(mut self, warn_above: Duration) -> Self {
+        self.warn_above = Some(warn_above);
+        self
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    //
+    // Get back the log level and message for the given elapsed duration,
+    // `None` means it's faster than `min_duration`.
+    //
+    fn log_for(&self, elapsed: Duration) -> Option<(LogLevel, String)> {
+        if self.min_duration.is_some_and(|min| elapsed < min) {
+            return None;
+        }
+
+        match self.warn_above {
+            Some(warn_above) if elapsed > warn_above => Some((
+                if (self.level as u8) < (LogLevel::WARN as u8) {
+                    LogLevel::WARN
+                } else {
+                    self.level
+                },
+                format!(
+                    "{} took {elapsed:?} (slower than {warn_above:?})",
+                    self.label
+                ),
+            )),
+            _ => Some((self.level, format!("{} took {elapsed:?}", self.label))),
+        }
+    }
+}
+
+impl Drop for TimeLogGuard {
+    fn drop(&mut self) {
+        if let Some((level, message)) = self.log_for(self.elapsed()) {
+            log(level, &self.module_name, &self.function_name, &message);
+        }
+    }
+}
+
 //
 // Debug log
 //
@@ -221,3 +920,410 @@ macro_rules! error_log {
         log(LogLevel::ERROR, $module_name, $function_name, $message)
     };
 }
+
+//
+// Time log
+//
+#[macro_export]
+#[cfg(not(feature = "DISABLE_DEBUG_LOG"))]
+macro_rules! time_log {
+    ($level:ident, $module_name:expr, $function_name:expr, $label:expr
+        $(, min = $min:expr)? $(, warn = $warn:expr)? => $body:block) => {{
+        let _time_log_guard = $crate::time_log!(
+            $level, $module_name, $function_name, $label $(, min = $min)? $(, warn = $warn)?
+        );
+        $body
+    }};
+    ($level:ident, $module_name:expr, $function_name:expr, $label:expr
+        $(, min = $min:expr)? $(, warn = $warn:expr)?) => {
+        $crate::logger::TimeLogGuard::new(
+            $crate::logger::LogLevel::$level,
+            $module_name,
+            $function_name,
+            $label,
+        )
+        $(.min_duration($min))?
+        $(.warn_above($warn))?
+    };
+}
+
+//
+// Time log: `DEBUG` level expands to nothing (the block is still executed) if
+// `DISABLE_DEBUG_LOG` feature is disabled
+//
+#[macro_export]
+#[cfg(feature = "DISABLE_DEBUG_LOG")]
+macro_rules! time_log {
+    (DEBUG, $module_name:expr, $function_name:expr, $label:expr
+        $(, min = $min:expr)? $(, warn = $warn:expr)? => $body:block) => {
+        $body
+    };
+    (DEBUG, $module_name:expr, $function_name:expr, $label:expr
+        $(, min = $min:expr)? $(, warn = $warn:expr)?) => {
+        ()
+    };
+    ($level:ident, $module_name:expr, $function_name:expr, $label:expr
+        $(, min = $min:expr)? $(, warn = $warn:expr)? => $body:block) => {{
+        let _time_log_guard = $crate::time_log!(
+            $level, $module_name, $function_name, $label $(, min = $min)? $(, warn = $warn)?
+        );
+        $body
+    }};
+    ($level:ident, $module_name:expr, $function_name:expr, $label:expr
+        $(, min = $min:expr)? $(, warn = $warn:expr)?) => {
+        $crate::logger::TimeLogGuard::new(
+            $crate::logger::LogLevel::$level,
+            $module_name,
+            $function_name,
+            $label,
+        )
+        $(.min_duration($min))?
+        $(.warn_above($warn))?
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    //
+    // Keep all records in memory, so the test can check them
+    //
+    struct MemorySink {
+        records: Arc<Mutex<Vec<LogRecord>>>,
+    }
+
+    impl LogSink for MemorySink {
+        fn write(&mut self, record: &LogRecord) {
+            self.records.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[test]
+    fn format_timestamp_should_work() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + std::time::Duration::from_millis(1_792_368_000_123)),
+            "2026-10-19T00:00:00.123Z"
+        );
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + std::time::Duration::from_secs(951_782_400 + 3661)),
+            "2000-02-29T01:01:01.000Z"
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_should_work() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(1_792_368_000_123);
+        assert_eq!(parse_timestamp(&format_timestamp(time)), Some(time));
+        assert_eq!(
+            parse_timestamp("2026-10-19"),
+            Some(UNIX_EPOCH + std::time::Duration::from_secs(1_792_368_000))
+        );
+        assert_eq!(
+            parse_timestamp("2000-02-29T01:01"),
+            Some(UNIX_EPOCH + std::time::Duration::from_secs(951_782_400 + 3660))
+        );
+        assert_eq!(
+            parse_timestamp("2026-10-19T00:00:00.5"),
+            Some(UNIX_EPOCH + std::time::Duration::from_millis(1_792_368_000_500))
+        );
+        assert_eq!(parse_timestamp("2026-13-01"), None);
+        assert_eq!(parse_timestamp("2026-02-31"), None);
+        assert_eq!(parse_timestamp("2026-02-29"), None);
+        assert_eq!(parse_timestamp("2026-04-31"), None);
+        assert_eq!(parse_timestamp("2026-10-19T00:00:00.x€"), None);
+        assert_eq!(parse_timestamp("2026-10-19T00:00:00.€"), None);
+        assert_eq!(parse_timestamp("2026-10-19T00:00:00."), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn log_record_format_should_work() {
+        let record = LogRecord {
+            timestamp: UNIX_EPOCH,
+            level: LogLevel::WARN,
+            module_name: String::from("LoggerTests"),
+            function_name: String::from("format"),
+            message: String::from("line 1\n\"line 2\""),
+            fields: Vec::new(),
+            span: None,
+        };
+
+        assert_eq!(
+            record.format(LogFormat::Text),
+            "1970-01-01T00:00:00.000Z (W) [ LoggerTests - format ] line 1\n\"line 2\""
+        );
+        assert_eq!(
+            record.format(LogFormat::Json),
+            concat!(
+                r#"{"timestamp":"1970-01-01T00:00:00.000Z","level":"WARN","#,
+                r#""module":"LoggerTests","function":"format","message":"line 1\n\"line 2\""}"#
+            )
+        );
+
+        let record = LogRecord {
+            message: String::from("hello"),
+            fields: vec![
+                (String::from("peer"), String::from("10.0.0.1")),
+                (String::from("level"), String::from("custom")),
+            ],
+            span: SpanContext::new("4bf92f3577b34da6a3ce929d0e0e4736", "00f067aa0ba902b7"),
+            ..record
+        };
+        assert_eq!(
+            record.format(LogFormat::Text),
+            "1970-01-01T00:00:00.000Z (W) [ LoggerTests - format ] hello"
+        );
+        assert_eq!(
+            record.format(LogFormat::Json),
+            concat!(
+                r#"{"timestamp":"1970-01-01T00:00:00.000Z","level":"WARN","#,
+                r#""module":"LoggerTests","function":"format","message":"hello","#,
+                r#""peer":"10.0.0.1","field_level":"custom","#,
+                r#""trace_id":"4bf92f3577b34da6a3ce929d0e0e4736","span_id":"00f067aa0ba902b7"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn file_sink_should_work() {
+        let log_file =
+            env::temp_dir().join(format!("rust_utils_file_sink_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&log_file);
+
+        add_sink(FileSink::new(&log_file, LogFormat::Json).unwrap());
+        log(
+            LogLevel::ERROR,
+            "FileSinkTests",
+            "file_sink_should_work",
+            "hello",
+        );
+        flush();
+
+        let content = std::fs::read_to_string(&log_file).unwrap();
+        assert!(content.lines().any(|line| line.contains(
+            r#""level":"ERROR","module":"FileSinkTests","function":"file_sink_should_work","message":"hello"}"#
+        )));
+        let _ = std::fs::remove_file(&log_file);
+    }
+
+    #[test]
+    fn panic_hook_should_log_to_sinks() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        add_sink(MemorySink {
+            records: Arc::clone(&records),
+        });
+        install_panic_hook();
+
+        let result = std::thread::Builder::new()
+            .name(String::from("panic-hook-test"))
+            .spawn(|| panic!("boom from the background thread"))
+            .unwrap()
+            .join();
+        assert!(result.is_err());
+
+        let records = records.lock().unwrap();
+        let record = records
+            .iter()
+            .find(|r| r.module_name == PANIC_LOGGER_NAME && r.function_name == "panic-hook-test")
+            .expect("the panic should be logged");
+        assert_eq!(record.level, LogLevel::ERROR);
+        assert!(
+            record
+                .message
+                .starts_with("thread 'panic-hook-test' panicked at src/logger.rs:")
+        );
+        assert!(record.message.contains("boom from the background thread"));
+        assert!(record.message.contains("stack backtrace:"));
+    }
+
+    #[test]
+    fn panic_hook_should_wait_for_other_threads_logging() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        add_sink(MemorySink {
+            records: Arc::clone(&records),
+        });
+        install_panic_hook();
+
+        //
+        // Another thread holds the sinks while the panic happens
+        //
+        let (locked_sender, locked_receiver) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn(move || {
+            let _sinks = LOG_SINKS.lock().unwrap_or_else(PoisonError::into_inner);
+            locked_sender.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+        });
+        locked_receiver.recv().unwrap();
+
+        let result = std::thread::Builder::new()
+            .name(String::from("panic-hook-wait-test"))
+            .spawn(|| panic!("boom while another thread logs"))
+            .unwrap()
+            .join();
+        assert!(result.is_err());
+        holder.join().unwrap();
+
+        assert!(records.lock().unwrap().iter().any(|r| {
+            r.function_name == "panic-hook-wait-test"
+                && r.message.contains("boom while another thread logs")
+        }));
+    }
+
+    #[test]
+    fn panic_hook_should_skip_sinks_on_reentry() {
+        struct PanickingSink;
+
+        impl LogSink for PanickingSink {
+            fn write(&mut self, record: &LogRecord) {
+                if record.message == "panic in the sink" {
+                    panic!("the sink failed");
+                }
+            }
+        }
+
+        add_sink(PanickingSink);
+        install_panic_hook();
+
+        //
+        // The panic hook runs while this thread holds the sinks, it must not deadlock
+        //
+        let in_sinks = std::thread::spawn(|| {
+            let result = std::panic::catch_unwind(|| {
+                log(
+                    LogLevel::ERROR,
+                    "LoggerTests",
+                    "reentry",
+                    "panic in the sink",
+                );
+            });
+            assert!(result.is_err());
+            IN_SINKS.with(Cell::get)
+        })
+        .join()
+        .unwrap();
+        assert!(!in_sinks);
+    }
+
+    #[test]
+    fn time_log_guard_should_work() {
+        let guard = TimeLogGuard::new(LogLevel::DEBUG, "TimeLogTests", "guard", "step")
+            .min_duration(Duration::from_millis(10))
+            .warn_above(Duration::from_millis(100));
+
+        assert_eq!(guard.log_for(Duration::from_millis(5)), None);
+        assert_eq!(
+            guard.log_for(Duration::from_millis(50)),
+            Some((LogLevel::DEBUG, String::from("step took 50ms")))
+        );
+        assert_eq!(
+            guard.log_for(Duration::from_millis(150)),
+            Some((
+                LogLevel::WARN,
+                String::from("step took 150ms (slower than 100ms)")
+            ))
+        );
+
+        let error_guard = TimeLogGuard::new(LogLevel::ERROR, "TimeLogTests", "guard", "step")
+            .warn_above(Duration::from_millis(100));
+        assert_eq!(
+            error_guard.log_for(Duration::from_millis(150)).unwrap().0,
+            LogLevel::ERROR
+        );
+    }
+
+    #[test]
+    fn time_log_macro_should_work() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        add_sink(MemorySink {
+            records: Arc::clone(&records),
+        });
+
+        let sum = crate::time_log!(ERROR, "TimeLogTests", "time_log_macro", "sum" => {
+            (1..=100).sum::<u32>()
+        });
+        assert_eq!(sum, 5050);
+
+        {
+            let _guard = crate::time_log!(
+                ERROR,
+                "TimeLogTests",
+                "time_log_macro",
+                "scope",
+                min = Duration::ZERO,
+                warn = Duration::from_secs(60)
+            );
+        }
+
+        let skipped = crate::time_log!(
+            ERROR, "TimeLogTests", "time_log_macro", "skipped", min = Duration::from_secs(60) => { 1 }
+        );
+        assert_eq!(skipped, 1);
+
+        let labels = records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.module_name == "TimeLogTests")
+            .map(|r| r.message.split(" took ").next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["sum", "scope"]);
+    }
+
+    #[test]
+    fn fields_and_span_should_reach_sinks() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        add_sink(MemorySink {
+            records: Arc::clone(&records),
+        });
+
+        let outer = SpanContext::new("4bf92f3577b34da6a3ce929d0e0e4736", "00f067aa0ba902b7");
+        let inner = SpanContext::new("4bf92f3577b34da6a3ce929d0e0e4736", "b7ad6b7169203331");
+        {
+            let _outer = outer.clone().unwrap().enter();
+            log_with_fields(
+                LogLevel::ERROR,
+                "SpanTests",
+                "fields",
+                "outer",
+                &[("user", "42"), ("region", "eu")],
+            );
+            {
+                let _inner = inner.clone().unwrap().enter();
+                log(LogLevel::ERROR, "SpanTests", "fields", "inner");
+            }
+            log(LogLevel::ERROR, "SpanTests", "fields", "outer again");
+        }
+        log(LogLevel::ERROR, "SpanTests", "fields", "no span");
+
+        let records = records.lock().unwrap();
+        let records = records
+            .iter()
+            .filter(|r| r.module_name == "SpanTests")
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            records[0].fields,
+            vec![
+                (String::from("user"), String::from("42")),
+                (String::from("region"), String::from("eu"))
+            ]
+        );
+        assert_eq!(records[0].span, outer);
+        assert!(records[1].fields.is_empty());
+        assert_eq!(records[1].span, inner);
+        assert_eq!(records[2].span, outer);
+        assert_eq!(records[3].span, None);
+
+        assert_eq!(
+            SpanContext::new("0".repeat(32).as_str(), "00f067aa0ba902b7"),
+            None
+        );
+        assert_eq!(
+            SpanContext::new("4bf92f3577b34da6", "00f067aa0ba902b7"),
+            None
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//!
+//! Export log records to Graylog in GELF 1.1 format over UDP or TCP.
+//!
+//! - UDP: a payload bigger than the chunk size is split into GELF chunks (at most 128),
+//!   it can be compressed with the `gelf-compression` feature.
+//! - TCP: every payload is terminated by a null byte, GELF doesn't support compression
+//!   over TCP.
+//!
+//! Every record is mapped like this:
+//!
+//! | `LogRecord`     | GELF                                                     |
+//! |-----------------|----------------------------------------------------------|
+//! | `message`       | `short_message` (first line) and `full_message`          |
+//! | `timestamp`     | `timestamp` (seconds with milliseconds)                  |
+//! | `level`         | `level` (syslog severity: `7`, `6`, `4`, `3`)            |
+//! | `module_name`   | `_module`                                                |
+//! | `function_name` | `_function`                                              |
+//! | `fields`        | `_<key>`, invalid characters in the key become `_`       |
+//! | `span`          | `_trace_id` and `_span_id`                               |
+//!
+//! A field named like a reserved or a built-in field (`id`, `module`, `function`,
+//! `trace_id` or `span_id`) is sent as `_field_<key>` so it can't overwrite it.
+//!
+//! Example:
+//!
+//! ```rust
+//! use rust_utils::logger::{self, GelfSink};
+//!
+//! logger::add_sink(GelfSink::udp("127.0.0.1:12201").host("edge-box-01"));
+//! ```
+//!
+use super::network::{BackgroundWriter, TcpTransport, UdpTransport};
+use super::{LogLevel, LogRecord, LogSink};
+use crate::json;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "gelf-compression")]
+use std::io::Write;
+
+const DEFAULT_CHUNK_SIZE: usize = 1420;
+const MAX_CHUNK_COUNT: usize = 128;
+const CHUNK_MAGIC_BYTES: [u8; 2] = [0x1e, 0x0f];
+const CHUNK_HEADER_SIZE: usize = 12;
+
+///
+/// GELF UDP payload compression
+///
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum GelfCompression {
+    None,
+    #[cfg(feature = "gelf-compression")]
+    Gzip,
+    #[cfg(feature = "gelf-compression")]
+    Zlib,
+}
+
+enum Transport {
+    Udp(BackgroundWriter<UdpTransport>),
+    Tcp(BackgroundWriter<TcpTransport>),
+}
+
+///
+/// Send log records to a Graylog GELF input
+///
+pub struct GelfSink {
+    transport: Transport,
+    host: String,
+    chunk_size: usize,
+    compression: GelfCompression,
+    message_counter: u64,
+}
+
+impl GelfSink {
+    ///
+    /// Send to a GELF UDP input, e.g. `"graylog.local:12201"`
+    ///
+    pub fn udp(address: &str) -> Self {
+        Self::new(Transport::Udp(BackgroundWriter::new(UdpTransport::new(
+            address,
+        ))))
+    }
+
+    ///
+    /// Send to a GELF TCP input, e.g. `"graylog.local:12201"`
+    ///
+    pub fn tcp(address: &str) -> Self {
+        Self::new(Transport::Tcp(BackgroundWriter::new(TcpTransport::new(
+            address,
+        ))))
+    }
+
+    fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            host: default_host_name(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            compression: GelfCompression::None,
+            message_counter: 0,
+        }
+    }
+
+    ///
+    /// The `host` field, default is the `HOSTNAME` env var or `/etc/hostname`.
+    ///
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    ///
+    /// The max UDP datagram size (including the 12 bytes chunk header), default is 1420.
+    ///
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(CHUNK_HEADER_SIZE + 1);
+        self
+    }
+
+    ///
+    /// Compress the UDP payload, it's ignored by TCP.
+    ///
+    pub fn compression(mut self, compression: GelfCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    ///
+    /// How many records can be kept while the endpoint is unreachable, default is 1024.
+    ///
+    pub fn buffer_capacity(self, capacity: usize) -> Self {
+        match &self.transport {
+            Transport::Udp(t) => t.state().delivery.capacity = capacity,
+            Transport::Tcp(t) => t.state().delivery.capacity = capacity,
+        }
+        self
+    }
+
+    ///
+    /// The reconnect delay starts from `initial` and doubles after every failure
+    /// until `max`, default is 500ms to 30s.
+    ///
+    pub fn backoff(self, initial: Duration, max: Duration) -> Self {
+        match &self.transport {
+            Transport::Udp(t) => t.state().delivery.set_backoff(initial, max),
+            Transport::Tcp(t) => t.state().delivery.set_backoff(initial, max),
+        }
+        self
+    }
+
+    ///
+    /// How many records have been dropped, because the spill buffer was full or
+    /// the payload needs more than 128 chunks.
+    ///
+    pub fn dropped_records(&self) -> u64 {
+        match &self.transport {
+            Transport::Udp(t) => t.state().delivery.dropped_records,
+            Transport::Tcp(t) => t.state().delivery.dropped_records,
+        }
+    }
+
+    //
+    // Split the payload into GELF chunks when it doesn't fit into one datagram,
+    // `None` means it needs more than 128 chunks.
+    //
+    fn split_into_datagrams(&mut self, payload: Vec<u8>) -> Option<Vec<Vec<u8>>> {
+        if payload.len() <= self.chunk_size {
+            return Some(vec![payload]);
+        }
+
+        let data_size = self.chunk_size - CHUNK_HEADER_SIZE;
+        let chunk_count = payload.len().div_ceil(data_size);
+        if chunk_count > MAX_CHUNK_COUNT {
+            return None;
+        }
+
+        self.message_counter = self.message_counter.wrapping_add(1);
+        let message_id = message_id(self.message_counter);
+
+        Some(
+            payload
+                .chunks(data_size)
+                .enumerate()
+                .map(|(index, data)| {
+                    let mut chunk = Vec::with_capacity(CHUNK_HEADER_SIZE + data.len());
+                    chunk.extend_from_slice(&CHUNK_MAGIC_BYTES);
+                    chunk.extend_from_slice(&message_id);
+                    chunk.push(index as u8);
+                    chunk.push(chunk_count as u8);
+                    chunk.extend_from_slice(data);
+                    chunk
+                })
+                .collect(),
+        )
+    }
+}
+
+impl LogSink for GelfSink {
+    fn write(&mut self, record: &LogRecord) {
+        let payload = to_gelf_payload(record, &self.host);
+
+        if let Transport::Tcp(t) = &mut self.transport {
+            let mut frame = payload.into_bytes();
+            frame.push(0);
+            t.push(frame);
+            return;
+        }
+
+        let compressed = compress(payload.into_bytes(), self.compression);
+        let datagrams = self.split_into_datagrams(compressed);
+        if let Transport::Udp(t) = &mut self.transport {
+            match datagrams {
+                Some(datagrams) => t.push(datagrams),
+                None => t.state().delivery.dropped_records += 1,
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        match &mut self.transport {
+            Transport::Udp(t) => t.flush(),
+            Transport::Tcp(t) => t.flush(),
+        }
+    }
+}
+
+//
+// Syslog severity
+//
+fn syslog_level(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::DEBUG => 7,
+        LogLevel::INFO => 6,
+        LogLevel::WARN => 4,
+        LogLevel::ERROR => 3,
+    }
+}
+
+fn default_host_name() -> String {
+    if let Ok(host) = std::env::var("HOSTNAME")
+        && !host.trim().is_empty()
+    {
+        return host.trim().to_string();
+    }
+
+    match std::fs::read_to_string("/etc/hostname") {
+        Ok(host) if !host.trim().is_empty() => host.trim().to_string(),
+        _ => String::from("localhost"),
+    }
+}
+
+//
+// An unique id for all chunks of the same message
+//
+fn message_id(counter: u64) -> [u8; 8] {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    (nanos ^ (counter << 40) ^ std::process::id() as u64).to_be_bytes()
+}
+
+///
+/// Convert the record into a GELF 1.1 JSON payload
+///
+pub fn to_gelf_payload(record: &LogRecord, host: &str) -> String {
+    let since_epoch = record
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let short_message = match record.message.lines().next() {
+        Some(line) if !line.trim().is_empty() => line,
+        _ => "-",
+    };
+
+    let mut payload = format!(
+        "{{\"version\":\"1.1\",\"host\":{},\"short_message\":{},",
+        json::quote(host),
+        json::quote(short_message),
+    );
+    if record.message.trim_end() != short_message {
+        payload.push_str(&format!(
+            "\"full_message\":{},",
+            json::quote(&record.message)
+        ));
+    }
+    payload.push_str(&format!(
+        "\"timestamp\":{}.{:03},\"level\":{},\"_module\":{},\"_function\":{}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_millis(),
+        syslog_level(record.level),
+        json::quote(&record.module_name),
+        json::quote(&record.function_name),
+    ));
+    for (key, value) in &record.fields {
+        payload.push_str(&format!(
+            ",{}:{}",
+            json::quote(&additional_field_name(key)),
+            json::quote(value)
+        ));
+    }
+    if let Some(span) = &record.span {
+        payload.push_str(&format!(
+            ",\"_trace_id\":{},\"_span_id\":{}",
+            json::quote(&span.trace_id),
+            json::quote(&span.span_id)
+        ));
+    }
+    payload.push('}');
+    payload
+}
+
+//
+// GELF only accepts `^[\w\.\-]*$` as additional field names, and `_id` is reserved
+//
+fn additional_field_name(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    match key.as_str() {
+        "id" | "module" | "function" | "trace_id" | "span_id" => format!("_field_{key}"),
+        _ => format!("_{key}"),
+    }
+}
+
+fn compress(payload: Vec<u8>, compression: GelfCompression) -> Vec<u8> {
+    match compression {
+        GelfCompression::None => payload,
+        #[cfg(feature = "gelf-compression")]
+        GelfCompression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            let _ = encoder.write_all(&payload);
+            encoder.finish().unwrap_or(payload)
+        }
+        #[cfg(feature = "gelf-compression")]
+        GelfCompression::Zlib => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            let _ = encoder.write_all(&payload);
+            encoder.finish().unwrap_or(payload)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::SpanContext;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, UdpSocket};
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_792_368_000_123),
+            level: LogLevel::WARN,
+            module_name: String::from("GelfTests"),
+            function_name: String::from("send"),
+            message: message.to_string(),
+            fields: Vec::new(),
+            span: None,
+        }
+    }
+
+    fn receiver() -> UdpSocket {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        receiver
+    }
+
+    #[test]
+    fn gelf_payload_should_work() {
+        assert_eq!(
+            to_gelf_payload(&record("hello"), "box-01"),
+            concat!(
+                r#"{"version":"1.1","host":"box-01","short_message":"hello","#,
+                r#""timestamp":1792368000.123,"level":4,"_module":"GelfTests","_function":"send"}"#
+            )
+        );
+        assert_eq!(
+            to_gelf_payload(&record("line 1\nline 2"), "box-01"),
+            concat!(
+                r#"{"version":"1.1","host":"box-01","short_message":"line 1","#,
+                r#""full_message":"line 1\nline 2","#,
+                r#""timestamp":1792368000.123,"level":4,"_module":"GelfTests","_function":"send"}"#
+            )
+        );
+
+        let mut with_fields = record("hello");
+        with_fields.fields = vec![
+            (String::from("user id"), String::from("42")),
+            (String::from("id"), String::from("7")),
+            (String::from("region.name"), String::from("eu-west")),
+        ];
+        with_fields.span = SpanContext::new("4bf92f3577b34da6a3ce929d0e0e4736", "00f067aa0ba902b7");
+        assert_eq!(
+            to_gelf_payload(&with_fields, "box-01"),
+            concat!(
+                r#"{"version":"1.1","host":"box-01","short_message":"hello","#,
+                r#""timestamp":1792368000.123,"level":4,"_module":"GelfTests","_function":"send","#,
+                r#""_user_id":"42","_field_id":"7","_region.name":"eu-west","#,
+                r#""_trace_id":"4bf92f3577b34da6a3ce929d0e0e4736","_span_id":"00f067aa0ba902b7"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn gelf_udp_should_chunk_big_payloads() {
+        let receiver = receiver();
+        let mut sink = GelfSink::udp(&receiver.local_addr().unwrap().to_string())
+            .host("box-01")
+            .chunk_size(64);
+
+        let message = "x".repeat(200);
+        sink.write(&record(&message));
+
+        let payload = to_gelf_payload(&record(&message), "box-01").into_bytes();
+        let chunk_count = payload.len().div_ceil(64 - CHUNK_HEADER_SIZE);
+
+        let mut chunks = Vec::new();
+        let mut datagram = [0u8; 64];
+        for _ in 0..chunk_count {
+            let size = receiver.recv(&mut datagram).unwrap();
+            chunks.push(datagram[..size].to_vec());
+        }
+
+        let mut reassembled = Vec::new();
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk[0..2], CHUNK_MAGIC_BYTES);
+            assert_eq!(chunk[2..10], chunks[0][2..10]);
+            assert_eq!(chunk[10] as usize, index);
+            assert_eq!(chunk[11] as usize, chunk_count);
+            reassembled.extend_from_slice(&chunk[CHUNK_HEADER_SIZE..]);
+        }
+        assert_eq!(reassembled, payload);
+    }
+
+    #[test]
+    fn gelf_tcp_should_work() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = GelfSink::tcp(&listener.local_addr().unwrap().to_string()).host("box-01");
+        sink.write(&record("hello"));
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut frame = Vec::new();
+        BufReader::new(stream).read_until(0, &mut frame).unwrap();
+        assert_eq!(frame.pop(), Some(0));
+        assert_eq!(
+            String::from_utf8(frame).unwrap(),
+            to_gelf_payload(&record("hello"), "box-01")
+        );
+    }
+
+    #[cfg(feature = "gelf-compression")]
+    #[test]
+    fn gelf_udp_compression_should_work() {
+        use std::io::Read;
+
+        let receiver = receiver();
+        let mut sink = GelfSink::udp(&receiver.local_addr().unwrap().to_string())
+            .host("box-01")
+            .compression(GelfCompression::Zlib);
+        sink.write(&record("hello"));
+
+        let mut datagram = [0u8; 1024];
+        let size = receiver.recv(&mut datagram).unwrap();
+        let mut payload = String::new();
+        flate2::read::ZlibDecoder::new(&datagram[..size])
+            .read_to_string(&mut payload)
+            .unwrap();
+        assert_eq!(payload, to_gelf_payload(&record("hello"), "box-01"));
+    }
+}
@@ -0,0 +1,579 @@
+//!
+//! Export log records to an OpenTelemetry collector with OTLP/HTTP JSON.
+//!
+//! Records are batched and posted to the collector's logs endpoint (e.g.
+//! `http://127.0.0.1:4318/v1/logs`) when the batch is full, when the oldest queued
+//! record has waited for `max_export_delay` or when `logger::flush()` is called. Posting happens on a background writer thread (see `TcpSink`), the
+//! logging thread only queues the record. Failed batches stay in the bounded buffer
+//! and are retried after a backoff delay. Only plain `http://` endpoints are
+//! supported, it's meant for a collector running next to the process.
+//!
+//! Every record is mapped like this:
+//!
+//! | `LogRecord`     | OTLP                                         |
+//! |-----------------|----------------------------------------------|
+//! | `message`       | `body.stringValue`                           |
+//! | `timestamp`     | `timeUnixNano`                               |
+//! | `level`         | `severityNumber` (5, 9, 13, 17) and `severityText` |
+//! | `module_name`   | `code.namespace` attribute                   |
+//! | `function_name` | `code.function` attribute                    |
+//! | `fields`        | one string attribute per key                 |
+//! | `span`          | `traceId` and `spanId` (hex)                 |
+//!
+//! Example:
+//!
+//! ```rust
+//! use rust_utils::logger::{self, OtlpSink};
+//! use std::time::Duration;
+//!
+//! logger::add_sink(
+//!     OtlpSink::new("http://127.0.0.1:4318/v1/logs")
+//!         .unwrap()
+//!         .service_name("edge-agent")
+//!         .batch_size(100)
+//!         .max_export_delay(Duration::from_secs(5)),
+//! );
+//! ```
+//!
+use super::network::{BackgroundWriter, Sender};
+use super::{LogLevel, LogRecord, LogSink};
+use crate::json;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_BATCH_SIZE: usize = 64;
+const DEFAULT_MAX_EXPORT_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+///
+/// Post log records to an OTLP/HTTP JSON logs endpoint
+///
+pub struct OtlpSink {
+    writer: BackgroundWriter<OtlpExporter>,
+}
+
+impl OtlpSink {
+    ///
+    /// Create the sink by the given endpoint, e.g. `"http://127.0.0.1:4318/v1/logs"` or
+    /// `"http://[::1]:4318/v1/logs"`.
+    ///
+    pub fn new(endpoint: &str) -> io::Result<Self> {
+        let (host, port, path) = parse_endpoint(endpoint)?;
+
+        Ok(Self {
+            writer: BackgroundWriter::new(OtlpExporter {
+                host,
+                port,
+                path,
+                service_name: default_service_name(),
+                batch_size: DEFAULT_BATCH_SIZE,
+                max_export_delay: DEFAULT_MAX_EXPORT_DELAY,
+                io_timeout: DEFAULT_IO_TIMEOUT,
+            }),
+        })
+    }
+
+    ///
+    /// The `service.name` resource attribute, default is the `OTEL_SERVICE_NAME` env
+    /// var or the executable name. It can't be changed after the first record.
+    ///
+    pub fn service_name(mut self, service_name: &str) -> Self {
+        if let Some(exporter) = self.writer.sender_mut() {
+            exporter.service_name = service_name.to_string();
+        }
+        self
+    }
+
+    ///
+    /// How many records are posted in one request, default is 64. It can't be changed
+    /// after the first record.
+    ///
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        if let Some(exporter) = self.writer.sender_mut() {
+            exporter.batch_size = batch_size.max(1);
+        }
+        self
+    }
+
+    ///
+    /// How long a record waits for its batch to fill up before it's posted anyway,
+    /// default is 1s. It can't be changed after the first record.
+    ///
+    pub fn max_export_delay(mut self, delay: Duration) -> Self {
+        if let Some(exporter) = self.writer.sender_mut() {
+            exporter.max_export_delay = delay;
+        }
+        self
+    }
+
+    ///
+    /// How many records can be kept while the collector is unreachable, default is 1024.
+    ///
+    pub fn buffer_capacity(self, capacity: usize) -> Self {
+        self.writer.state().delivery.capacity = capacity;
+        self
+    }
+
+    ///
+    /// The retry delay starts from `initial` and doubles after every failure
+    /// until `max`, default is 500ms to 30s.
+    ///
+    pub fn backoff(self, initial: Duration, max: Duration) -> Self {
+        self.writer.state().delivery.set_backoff(initial, max);
+        self
+    }
+
+    ///
+    /// The connect, read and write timeout, default is 2s. It can't be changed after
+    /// the first record.
+    ///
+    pub fn io_timeout(mut self, timeout: Duration) -> Self {
+        if let Some(exporter) = self.writer.sender_mut() {
+            exporter.io_timeout = timeout;
+        }
+        self
+    }
+
+    ///
+    /// How many records have been dropped because the buffer was full.
+    ///
+    pub fn dropped_records(&self) -> u64 {
+        self.writer.state().delivery.dropped_records
+    }
+}
+
+impl LogSink for OtlpSink {
+    fn write(&mut self, record: &LogRecord) {
+        self.writer.push(record.clone());
+    }
+
+    fn flush(&mut self) {
+        self.writer.flush();
+    }
+}
+
+//
+// Split `http://host:port/path` into its parts, an IPv6 host must be in brackets,
+// e.g. `http://[::1]:4318/v1/logs`
+//
+fn parse_endpoint(endpoint: &str) -> io::Result<(String, u16, String)> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{reason} in endpoint: {endpoint}"),
+        )
+    };
+
+    let Some(host_port_path) = endpoint.strip_prefix("http://") else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("only 'http://' endpoint is supported: {endpoint}"),
+        ));
+    };
+
+    let (host_port, path) = match host_port_path.find('/') {
+        Some(index) => host_port_path.split_at(index),
+        None => (host_port_path, "/v1/logs"),
+    };
+    let (host, port) = match host_port.strip_prefix('[') {
+        Some(bracketed) => {
+            let Some((host, rest)) = bracketed.split_once(']') else {
+                return Err(invalid("missing ']'"));
+            };
+            match rest {
+                "" => (host, None),
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(invalid("unexpected characters after ']'")),
+                },
+            }
+        }
+        None => match host_port.split_once(':') {
+            Some((_, port)) if port.contains(':') => {
+                return Err(invalid("IPv6 host must be in brackets"));
+            }
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| invalid("invalid port"))?,
+        None => 80,
+    };
+
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+//
+// Post the queued records batch by batch on the writer thread
+//
+struct OtlpExporter {
+    host: String,
+    port: u16,
+    path: String,
+    service_name: String,
+    batch_size: usize,
+    max_export_delay: Duration,
+    io_timeout: Duration,
+}
+
+impl OtlpExporter {
+    fn post(&self, body: &str) -> io::Result<()> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address resolved");
+        let mut stream = None;
+        for socket_address in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_address, self.io_timeout) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let Some(mut stream) = stream else {
+            return Err(last_error);
+        };
+        stream.set_read_timeout(Some(self.io_timeout))?;
+        stream.set_write_timeout(Some(self.io_timeout))?;
+
+        write!(
+            stream,
+            concat!(
+                "POST {} HTTP/1.1\r\n",
+                "Host: {}\r\n",
+                "Content-Type: application/json\r\n",
+                "Content-Length: {}\r\n",
+                "Connection: close\r\n",
+                "\r\n",
+                "{}"
+            ),
+            self.path,
+            self.host_header(),
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+
+        //
+        // Only the status line matters, e.g. `HTTP/1.1 200 OK`
+        //
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "OTLP collector responded: {}",
+                status_line.trim_end()
+            ))),
+        }
+    }
+
+    fn host_header(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Sender for OtlpExporter {
+    type Item = LogRecord;
+
+    //
+    // Wait for a full batch, the rest is posted after `max_export_delay` or by
+    // `logger::flush()`
+    //
+    fn is_ready(&self, queued: usize) -> bool {
+        queued >= self.batch_size
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        Some(self.max_export_delay)
+    }
+
+    fn send(&mut self, records: &mut VecDeque<LogRecord>) -> io::Result<()> {
+        while !records.is_empty() {
+            let batch_len = self.batch_size.min(records.len());
+            let payload = to_otlp_payload(records.range(..batch_len), &self.service_name);
+            self.post(&payload)?;
+            records.drain(..batch_len);
+        }
+        Ok(())
+    }
+}
+
+fn default_service_name() -> String {
+    if let Ok(name) = std::env::var("OTEL_SERVICE_NAME")
+        && !name.trim().is_empty()
+    {
+        return name.trim().to_string();
+    }
+
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| path.file_stem().map(|s| s.to_string_lossy().to_string()))
+        .unwrap_or_else(|| String::from("unknown_service"))
+}
+
+//
+// OTLP severity number
+//
+fn severity_number(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::DEBUG => 5,
+        LogLevel::INFO => 9,
+        LogLevel::WARN => 13,
+        LogLevel::ERROR => 17,
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> String {
+    format!(
+        "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+        json::quote(key),
+        json::quote(value)
+    )
+}
+
+///
+/// Convert the records into an OTLP `ExportLogsServiceRequest` JSON payload
+///
+pub fn to_otlp_payload<'a, I: Iterator<Item = &'a LogRecord>>(
+    records: I,
+    service_name: &str,
+) -> String {
+    let observed_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let log_records = records
+        .map(|record| {
+            let attributes = [
+                string_attribute("code.namespace", &record.module_name),
+                string_attribute("code.function", &record.function_name),
+            ]
+            .into_iter()
+            .chain(
+                record
+                    .fields
+                    .iter()
+                    .map(|(key, value)| string_attribute(key, value)),
+            )
+            .collect::<Vec<_>>()
+            .join(",");
+            let span = match &record.span {
+                Some(span) => format!(
+                    ",\"traceId\":{},\"spanId\":{}",
+                    json::quote(&span.trace_id),
+                    json::quote(&span.span_id)
+                ),
+                None => String::new(),
+            };
+
+            format!(
+                concat!(
+                    "{{\"timeUnixNano\":\"{}\",\"observedTimeUnixNano\":\"{}\",",
+                    "\"severityNumber\":{},\"severityText\":{},",
+                    "\"body\":{{\"stringValue\":{}}},\"attributes\":[{}]{}}}"
+                ),
+                record
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos(),
+                observed_time,
+                severity_number(record.level),
+                json::quote(record.level.get_name()),
+                json::quote(&record.message),
+                attributes,
+                span,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        concat!(
+            "{{\"resourceLogs\":[{{\"resource\":{{\"attributes\":[{}]}},",
+            "\"scopeLogs\":[{{\"scope\":{{\"name\":\"rust_utils\",\"version\":{}}},",
+            "\"logRecords\":[{}]}}]}}]}}"
+        ),
+        string_attribute("service.name", service_name),
+        json::quote(env!("CARGO_PKG_VERSION")),
+        log_records
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::SpanContext;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_792_368_000_123),
+            level: LogLevel::ERROR,
+            module_name: String::from("OtlpTests"),
+            function_name: String::from("export"),
+            message: message.to_string(),
+            fields: Vec::new(),
+            span: None,
+        }
+    }
+
+    //
+    // Accept one request and respond with the given status, return the request body
+    //
+    fn mock_collector(listener: &TcpListener, status: &str) -> (String, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                content_length = value.trim().parse::<usize>().unwrap();
+            }
+        }
+
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).unwrap();
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n"
+        )
+        .unwrap();
+
+        (request_line, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn otlp_endpoint_should_be_parsed() {
+        let expected =
+            |host: &str, port: u16, path: &str| (host.to_string(), port, path.to_string());
+        assert_eq!(
+            parse_endpoint("http://collector.local:4318/v1/logs").unwrap(),
+            expected("collector.local", 4318, "/v1/logs")
+        );
+        assert_eq!(
+            parse_endpoint("http://collector.local").unwrap(),
+            expected("collector.local", 80, "/v1/logs")
+        );
+        assert_eq!(
+            parse_endpoint("http://[::1]:4318/v1/logs").unwrap(),
+            expected("::1", 4318, "/v1/logs")
+        );
+        assert_eq!(
+            parse_endpoint("http://[fe80::1]/otlp/logs").unwrap(),
+            expected("fe80::1", 80, "/otlp/logs")
+        );
+
+        let mut sink = OtlpSink::new("http://[::1]:4318/v1/logs").unwrap();
+        let exporter = sink.writer.sender_mut().unwrap();
+        assert_eq!(exporter.host_header(), "[::1]:4318");
+
+        assert!(OtlpSink::new("https://collector.local:4318/v1/logs").is_err());
+        assert!(OtlpSink::new("http://collector.local:bad/v1/logs").is_err());
+        assert!(OtlpSink::new("http://::1:4318/v1/logs").is_err());
+        assert!(OtlpSink::new("http://[::1:4318/v1/logs").is_err());
+        assert!(OtlpSink::new("http://[::1]4318/v1/logs").is_err());
+        assert!(OtlpSink::new("http://:4318/v1/logs").is_err());
+    }
+
+    #[test]
+    fn otlp_payload_should_work() {
+        let payload = to_otlp_payload([record("hello")].iter(), "test-service");
+        assert!(payload.starts_with(concat!(
+            r#"{"resourceLogs":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"test-service"}}]},"#,
+            r#""scopeLogs":[{"scope":{"name":"rust_utils","#
+        )));
+        assert!(payload.contains(r#"{"timeUnixNano":"1792368000123000000","#));
+        assert!(payload.contains(concat!(
+            r#""severityNumber":17,"severityText":"ERROR","body":{"stringValue":"hello"},"#,
+            r#""attributes":[{"key":"code.namespace","value":{"stringValue":"OtlpTests"}},"#,
+            r#"{"key":"code.function","value":{"stringValue":"export"}}]}"#
+        )));
+
+        let mut with_fields = record("hello");
+        with_fields.fields = vec![(String::from("user.id"), String::from("42"))];
+        with_fields.span = SpanContext::new("4BF92F3577B34DA6A3CE929D0E0E4736", "00f067aa0ba902b7");
+        let payload = to_otlp_payload([with_fields].iter(), "test-service");
+        assert!(payload.contains(concat!(
+            r#""attributes":[{"key":"code.namespace","value":{"stringValue":"OtlpTests"}},"#,
+            r#"{"key":"code.function","value":{"stringValue":"export"}},"#,
+            r#"{"key":"user.id","value":{"stringValue":"42"}}],"#,
+            r#""traceId":"4bf92f3577b34da6a3ce929d0e0e4736","spanId":"00f067aa0ba902b7"}"#
+        )));
+    }
+
+    #[test]
+    fn otlp_sink_should_post_batches_and_retry() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/logs", listener.local_addr().unwrap());
+
+        let collector = std::thread::spawn(move || {
+            let rejected = mock_collector(&listener, "503 Service Unavailable");
+            let accepted = mock_collector(&listener, "200 OK");
+            (rejected, accepted)
+        });
+
+        let mut sink = OtlpSink::new(&endpoint)
+            .unwrap()
+            .service_name("test-service")
+            .batch_size(2)
+            .backoff(Duration::ZERO, Duration::ZERO);
+        sink.write(&record("first"));
+        sink.write(&record("second"));
+        sink.flush();
+
+        let ((request_line, rejected_body), (_, accepted_body)) = collector.join().unwrap();
+        assert_eq!(request_line, "POST /v1/logs HTTP/1.1\r\n");
+        assert!(rejected_body.contains(r#""body":{"stringValue":"first"}"#));
+        assert!(accepted_body.contains(r#""body":{"stringValue":"first"}"#));
+        assert!(accepted_body.contains(r#""body":{"stringValue":"second"}"#));
+
+        //
+        // The first flush returns after the rejected attempt, wait for the retry too
+        //
+        sink.flush();
+        assert_eq!(sink.writer.state().pending_records(), 0);
+    }
+
+    #[test]
+    fn otlp_sink_should_post_after_max_export_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/logs", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || mock_collector(&listener, "200 OK"));
+
+        //
+        // The batch never fills up and nothing flushes it
+        //
+        let mut sink = OtlpSink::new(&endpoint)
+            .unwrap()
+            .batch_size(64)
+            .max_export_delay(Duration::from_millis(100));
+        let start = Instant::now();
+        sink.write(&record("lonely"));
+
+        let (_, body) = collector.join().unwrap();
+        assert!(body.contains(r#""body":{"stringValue":"lonely"}"#));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,672 @@
+//!
+//! Read log records back from the files produced by this logger, it's what the
+//! `rulog` binary uses.
+//!
+//! The following line formats are supported, they can be mixed in the same file:
+//!
+//! - `LogFormat::Json`: one record per line.
+//! - `LogFormat::Text`: `2026-10-19T08:30:00.123Z (I) [ module - function ] message`,
+//!   every line that doesn't start with a timestamp belongs to the previous record,
+//!   so the multi-line messages (e.g. `memory::print_memory`) stay in one record.
+//! - The console output (with or without ANSI colors): `(I) [ module - function ] message`,
+//!   it doesn't have a timestamp.
+//!
+use super::{
+    LOG_COLOR_GREEN, LOG_COLOR_RED, LOG_COLOR_RESET, LOG_COLOR_YELLOW, LogLevel, format_timestamp,
+    parse_timestamp,
+};
+use crate::json::{self, JsonValue};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+//
+// How long to wait for the next line before the pending record is returned by
+// `LogFollower::poll`
+//
+const DEFAULT_PENDING_TIMEOUT: Duration = Duration::from_millis(300);
+
+///
+/// A log record read back from a log file
+///
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReadLogRecord {
+    pub timestamp: Option<SystemTime>,
+    pub level: LogLevel,
+    pub module_name: String,
+    pub function_name: String,
+    pub message: String,
+
+    ///
+    /// All other fields of a `Json` record
+    ///
+    pub fields: Vec<(String, String)>,
+}
+
+impl ReadLogRecord {
+    ///
+    /// Get back the field value by name, `timestamp`, `level`, `module`, `function`
+    /// and `message` are always available.
+    ///
+    pub fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "timestamp" => self.timestamp.map(format_timestamp),
+            "level" => Some(self.level.get_name().to_string()),
+            "module" => Some(self.module_name.clone()),
+            "function" => Some(self.function_name.clone()),
+            "message" => Some(self.message.clone()),
+            _ => self
+                .fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone()),
+        }
+    }
+
+    ///
+    /// Render the record like the console output, with the timestamp if it has one
+    ///
+    pub fn render(&self, color: bool) -> String {
+        let timestamp = match self.timestamp {
+            Some(t) => format!("{} ", format_timestamp(t)),
+            None => String::new(),
+        };
+
+        if !color {
+            return format!(
+                "{timestamp}{} [ {} - {} ] {}",
+                self.level.get_logger_prefix(),
+                self.module_name,
+                self.function_name,
+                self.message
+            );
+        }
+
+        format!(
+            "{timestamp}{}{} [ {} - {} ] {}{}",
+            match self.level {
+                LogLevel::DEBUG => "",
+                LogLevel::INFO => LOG_COLOR_GREEN,
+                LogLevel::WARN => LOG_COLOR_YELLOW,
+                LogLevel::ERROR => LOG_COLOR_RED,
+            },
+            self.level.get_logger_prefix(),
+            self.module_name,
+            self.function_name,
+            self.message,
+            LOG_COLOR_RESET
+        )
+    }
+}
+
+///
+/// Filter records by level, module, function, time range and field values.
+/// An empty filter matches everything.
+///
+#[derive(Debug, Default, Clone)]
+pub struct LogFilter {
+    ///
+    /// The minimal level
+    ///
+    pub level: Option<LogLevel>,
+    pub module_name: Option<String>,
+    pub function_name: Option<String>,
+
+    ///
+    /// Records without timestamp never match a time range
+    ///
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+
+    ///
+    /// Every `(name, value)` pair must be equal to the record field
+    ///
+    pub fields: Vec<(String, String)>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, record: &ReadLogRecord) -> bool {
+        if self
+            .level
+            .is_some_and(|level| (record.level as u8) < (level as u8))
+        {
+            return false;
+        }
+
+        if self
+            .module_name
+            .as_ref()
+            .is_some_and(|m| *m != record.module_name)
+            || self
+                .function_name
+                .as_ref()
+                .is_some_and(|f| *f != record.function_name)
+        {
+            return false;
+        }
+
+        if self.since.is_some() || self.until.is_some() {
+            let Some(timestamp) = record.timestamp else {
+                return false;
+            };
+            if self.since.is_some_and(|since| timestamp < since)
+                || self.until.is_some_and(|until| timestamp > until)
+            {
+                return false;
+            }
+        }
+
+        self.fields
+            .iter()
+            .all(|(name, value)| record.field(name).as_deref() == Some(value.as_str()))
+    }
+}
+
+///
+/// Turn lines into records. Feed it line by line with `push_line`, a record is only
+/// complete when the next record starts (or `finish` is called), because the lines
+/// after a `Text` record header belong to its message.
+///
+#[derive(Debug, Default)]
+pub struct LogRecordParser {
+    pending: Option<ReadLogRecord>,
+
+    //
+    // The pending record is a `Text` record (with timestamp), the lines without
+    // timestamp belong to its message.
+    //
+    pending_is_text: bool,
+}
+
+impl LogRecordParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Push a line (without the trailing newline), return the previous record if
+    /// this line starts a new one.
+    ///
+    pub fn push_line(&mut self, line: &str) -> Option<ReadLogRecord> {
+        let line = strip_ansi_escapes(line.trim_end_matches(['\r', '\n']));
+
+        let parsed = match parse_json_line(&line) {
+            Some(record) => Some((record, false)),
+            None => parse_text_line(&line, self.pending_is_text).map(|record| {
+                let is_text = record.timestamp.is_some();
+                (record, is_text)
+            }),
+        };
+
+        match parsed {
+            Some((record, is_text)) => {
+                self.pending_is_text = is_text;
+                self.pending.replace(record)
+            }
+            None => {
+                //
+                // Continuation line of a multi-line message, or a line that isn't
+                // a log record at all (dropped if there is no record yet)
+                //
+                if let Some(pending) = self.pending.as_mut() {
+                    pending.message.push('\n');
+                    pending.message.push_str(&line);
+                }
+                None
+            }
+        }
+    }
+
+    ///
+    /// Take back the last pending record
+    ///
+    pub fn finish(&mut self) -> Option<ReadLogRecord> {
+        self.pending_is_text = false;
+        self.pending.take()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+///
+/// Follow a log file like `tail -F`: every `poll` returns the records written since
+/// the last one. The file is reopened from the beginning when the path points to a
+/// new file (rotated) or the file is truncated, a missing file is waited for.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// use rust_utils::logger::reader::LogFollower;
+/// use std::time::Duration;
+///
+/// let mut follower = LogFollower::new("app.log");
+/// loop {
+///     for record in follower.poll().unwrap() {
+///         println!("{}", record.render(false));
+///     }
+///     std::thread::sleep(Duration::from_millis(200));
+/// }
+/// ```
+///
+pub struct LogFollower {
+    path: PathBuf,
+    reader: Option<BufReader<File>>,
+    identity: Option<(u64, u64)>,
+    position: u64,
+    partial: Vec<u8>,
+    parser: LogRecordParser,
+    last_data: Instant,
+    pending_timeout: Duration,
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+impl LogFollower {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            reader: None,
+            identity: None,
+            position: 0,
+            partial: Vec::new(),
+            parser: LogRecordParser::new(),
+            last_data: Instant::now(),
+            pending_timeout: DEFAULT_PENDING_TIMEOUT,
+        }
+    }
+
+    ///
+    /// A record is only complete when the next one starts, it's returned anyway once
+    /// no new line is written for this long, default is 300ms.
+    ///
+    pub fn pending_timeout(mut self, timeout: Duration) -> Self {
+        self.pending_timeout = timeout;
+        self
+    }
+
+    ///
+    /// Read what's new and return the complete records
+    ///
+    pub fn poll(&mut self) -> io::Result<Vec<ReadLogRecord>> {
+        let mut records = Vec::new();
+        self.poll_into(&mut records)?;
+
+        //
+        // Return the pending record when the writer is idle, the next line might never
+        // come to complete it
+        //
+        if self.partial.is_empty()
+            && self.last_data.elapsed() >= self.pending_timeout
+            && let Some(record) = self.parser.finish()
+        {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn poll_into(&mut self, records: &mut Vec<ReadLogRecord>) -> io::Result<()> {
+        if self.reader.is_none() {
+            self.open();
+        }
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(());
+        };
+
+        let mut push = |record| {
+            records.push(record);
+            Ok(())
+        };
+        if read_lines(reader, &mut self.partial, &mut self.parser, &mut push)? {
+            self.last_data = Instant::now();
+        }
+        self.position = reader.stream_position()?;
+
+        if self.is_rotated() {
+            //
+            // The old file is done, take its last record and start over
+            //
+            records.extend(self.parser.finish());
+            self.partial.clear();
+            self.reader = None;
+            self.open();
+            return self.poll_into(records);
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) {
+        if let Ok(file) = File::open(&self.path) {
+            self.identity = file.metadata().ok().as_ref().and_then(file_identity);
+            self.reader = Some(BufReader::new(file));
+            self.position = 0;
+        }
+    }
+
+    fn is_rotated(&self) -> bool {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) => {
+                let identity = file_identity(&metadata);
+                (identity.is_some() && identity != self.identity) || metadata.len() < self.position
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+///
+/// Read lines until EOF and hand every complete record to `on_record`. A line without
+/// the trailing newline is kept in `partial` until the rest of it is written, and the
+/// last record stays in `parser` until the next one starts (see
+/// `LogRecordParser::finish`). Return whether anything was read.
+///
+pub fn read_lines<R: BufRead>(
+    reader: &mut R,
+    partial: &mut Vec<u8>,
+    parser: &mut LogRecordParser,
+    on_record: &mut dyn FnMut(ReadLogRecord) -> io::Result<()>,
+) -> io::Result<bool> {
+    let mut has_data = false;
+    loop {
+        let size = reader.read_until(b'\n', partial)?;
+        if size == 0 {
+            return Ok(has_data);
+        }
+        has_data = true;
+
+        if partial.last() != Some(&b'\n') {
+            continue;
+        }
+
+        if let Some(record) = parser.push_line(&String::from_utf8_lossy(partial)) {
+            on_record(record)?;
+        }
+        partial.clear();
+    }
+}
+
+///
+/// Parse all records from the given text
+///
+pub fn parse_records(text: &str) -> Vec<ReadLogRecord> {
+    let mut parser = LogRecordParser::new();
+    let mut records = text
+        .lines()
+        .filter_map(|line| parser.push_line(line))
+        .collect::<Vec<_>>();
+    records.extend(parser.finish());
+    records
+}
+
+fn strip_ansi_escapes(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.peek() == Some(&'[') {
+            chars.next();
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn parse_json_line(line: &str) -> Option<ReadLogRecord> {
+    if !line.trim_start().starts_with('{') {
+        return None;
+    }
+
+    let JsonValue::Object(entries) = json::parse(line).ok()? else {
+        return None;
+    };
+
+    let mut record = ReadLogRecord {
+        timestamp: None,
+        level: LogLevel::ERROR,
+        module_name: String::new(),
+        function_name: String::new(),
+        message: String::new(),
+        fields: Vec::new(),
+    };
+    let mut has_level = false;
+
+    for (key, value) in entries {
+        match key.as_str() {
+            "timestamp" => record.timestamp = value.as_str().and_then(parse_timestamp),
+            "level" => {
+                record.level = LogLevel::from_name(value.as_str()?)?;
+                has_level = true;
+            }
+            "module" => record.module_name = value.to_plain_string(),
+            "function" => record.function_name = value.to_plain_string(),
+            "message" => record.message = value.to_plain_string(),
+            _ => record.fields.push((key, value.to_plain_string())),
+        }
+    }
+
+    has_level.then_some(record)
+}
+
+//
+// `[timestamp ](D) [ module - function ] message`, the timestamp is required
+// when `timestamp_required` is `true`.
+//
+fn parse_text_line(line: &str, timestamp_required: bool) -> Option<ReadLogRecord> {
+    let (timestamp, rest) = match line.split_once(' ') {
+        Some((first, rest)) if first.len() >= 10 && first.as_bytes()[0].is_ascii_digit() => {
+            (Some(parse_timestamp(first)?), rest)
+        }
+        _ => (None, line),
+    };
+
+    //
+    // In a `Text` log file, the console-like lines without timestamp belong to the
+    // previous record's message (e.g. `memory::print_memory` output).
+    //
+    if timestamp.is_none() && timestamp_required {
+        return None;
+    }
+
+    let level = LogLevel::from_name(rest.get(..3)?)?;
+    let header = rest[3..].strip_prefix(" [ ")?;
+    let (names, message) = header.split_once(" ]")?;
+    let (module_name, function_name) = names.split_once(" - ")?;
+    let message = message.strip_prefix(' ').unwrap_or(message);
+
+    Some(ReadLogRecord {
+        timestamp,
+        level,
+        module_name: module_name.to_string(),
+        function_name: function_name.to_string(),
+        //
+        // The console output with colors has a space before the color reset
+        //
+        message: message.trim_end().to_string(),
+        fields: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn parse_records_should_work() {
+        let text = concat!(
+            "2026-10-19T00:00:00.123Z (I) [ Main - main ] hello\n",
+            "{\"timestamp\":\"2026-10-19T00:00:01.000Z\",\"level\":\"WARN\",\"module\":\"Net\",",
+            "\"function\":\"send\",\"message\":\"line 1\\nline 2\",\"peer\":\"10.0.0.1\"}\n",
+            "\u{1b}[1;31m(E) [ Main - main ] failed \u{1b}[0m\n",
+            "2026-10-19T00:00:02.000Z (D) [ Memory - print_memory ] 'v', size: 1\n",
+            "(D) [ Memory - print_memory ] --\n",
+            "(D) [ Memory - print_memory ] 31\n",
+        );
+
+        let records = parse_records(text);
+        assert_eq!(records.len(), 4);
+
+        assert_eq!(
+            records[0].timestamp,
+            Some(UNIX_EPOCH + Duration::from_millis(1_792_368_000_123))
+        );
+        assert_eq!(records[0].level, LogLevel::INFO);
+        assert_eq!(records[0].message, "hello");
+
+        assert_eq!(records[1].level, LogLevel::WARN);
+        assert_eq!(records[1].module_name, "Net");
+        assert_eq!(records[1].message, "line 1\nline 2");
+        assert_eq!(records[1].field("peer").as_deref(), Some("10.0.0.1"));
+
+        assert_eq!(records[2].timestamp, None);
+        assert_eq!(records[2].level, LogLevel::ERROR);
+        assert_eq!(records[2].message, "failed");
+
+        assert_eq!(
+            records[3].message,
+            "'v', size: 1\n(D) [ Memory - print_memory ] --\n(D) [ Memory - print_memory ] 31"
+        );
+    }
+
+    #[test]
+    fn log_filter_should_work() {
+        let records = parse_records(concat!(
+            "2026-10-19T00:00:00.000Z (D) [ Main - main ] 1\n",
+            "2026-10-19T00:00:01.000Z (I) [ Main - init ] 2\n",
+            "2026-10-19T00:00:02.000Z (W) [ Net - send ] 3\n",
+            "{\"level\":\"ERROR\",\"module\":\"Net\",\"function\":\"send\",\"message\":\"4\"}\n",
+        ));
+        let messages = |filter: &LogFilter| {
+            records
+                .iter()
+                .filter(|r| filter.matches(r))
+                .map(|r| r.message.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(messages(&LogFilter::default()), vec!["1", "2", "3", "4"]);
+        assert_eq!(
+            messages(&LogFilter {
+                level: Some(LogLevel::INFO),
+                ..Default::default()
+            }),
+            vec!["2", "3", "4"]
+        );
+        assert_eq!(
+            messages(&LogFilter {
+                module_name: Some(String::from("Main")),
+                function_name: Some(String::from("init")),
+                ..Default::default()
+            }),
+            vec!["2"]
+        );
+        assert_eq!(
+            messages(&LogFilter {
+                since: parse_timestamp("2026-10-19T00:00:01"),
+                until: parse_timestamp("2026-10-19T00:00:02"),
+                ..Default::default()
+            }),
+            vec!["2", "3"]
+        );
+        assert_eq!(
+            messages(&LogFilter {
+                fields: vec![(String::from("level"), String::from("ERROR"))],
+                ..Default::default()
+            }),
+            vec!["4"]
+        );
+    }
+
+    #[test]
+    fn render_should_work() {
+        let record = &parse_records("2026-10-19T00:00:00.000Z (W) [ Net - send ] retry\n")[0];
+        assert_eq!(
+            record.render(false),
+            "2026-10-19T00:00:00.000Z (W) [ Net - send ] retry"
+        );
+        assert_eq!(
+            record.render(true),
+            "2026-10-19T00:00:00.000Z \x1b[1;33m(W) [ Net - send ] retry\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn log_follower_should_follow_rotation_and_truncation() {
+        use std::io::Write;
+
+        let dir =
+            std::env::temp_dir().join(format!("rust_utils_log_follower_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let line =
+            |message: &str| format!("2026-10-19T00:00:00.000Z (I) [ Main - main ] {message}\n");
+        let append = |text: &str| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(text.as_bytes())
+                .unwrap();
+        };
+        let messages = |records: Vec<ReadLogRecord>| {
+            records
+                .into_iter()
+                .map(|record| record.message)
+                .collect::<Vec<_>>()
+        };
+
+        //
+        // The last record stays pending until the next one starts
+        //
+        let mut follower = LogFollower::new(&path).pending_timeout(Duration::from_secs(60));
+        assert!(follower.poll().unwrap().is_empty());
+        append(&line("first"));
+        append("continued\n");
+        append(&line("second")[..20]);
+        assert!(messages(follower.poll().unwrap()).is_empty());
+        append(&line("second")[20..]);
+        assert_eq!(messages(follower.poll().unwrap()), vec!["first\ncontinued"]);
+
+        //
+        // Rotated: the old file's last record is returned, the new file is read from
+        // the beginning
+        //
+        std::fs::rename(&path, dir.join("app.log.1")).unwrap();
+        append(&line("third"));
+        append(&line("fourth"));
+        assert_eq!(messages(follower.poll().unwrap()), vec!["second", "third"]);
+
+        //
+        // Truncated: read from the beginning again
+        //
+        std::fs::File::create(&path).unwrap();
+        append(&line("fifth"));
+        assert_eq!(messages(follower.poll().unwrap()), vec!["fourth"]);
+
+        //
+        // The pending record is returned once the writer is idle
+        //
+        let mut follower = LogFollower::new(&path).pending_timeout(Duration::ZERO);
+        assert_eq!(messages(follower.poll().unwrap()), vec!["fifth"]);
+        assert!(follower.poll().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}